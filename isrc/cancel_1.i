@@ -0,0 +1,10 @@
+// Cancellation
+//   * 'Y' asks for the hidden dimension of 't' before 'X' is evaluated.
+Y + X
+where
+  dim t <- 0
+
+  X = 5
+
+  Y = #.t
+end
@@ -0,0 +1,10 @@
+// Contexts are first-class values
+//   * 'K' names a context which is then used to perturb 'X'.
+X @ K
+where
+  dim t <- 0
+
+  K = [t <- 5]
+
+  X = #.t
+end
@@ -0,0 +1,16 @@
+// Context primitives
+//   * '#' is the current context, '++' is context union, '<|' restricts a
+//     context to a domain and 'dom' extracts the domain of a context.
+(Y @ [t <- 7]) + (Z @ K)
+where
+  dim t <- 0
+  dim s <- 0
+
+  K = [t <- 1, s <- 2]
+
+  X = #.t + #.s
+
+  Y = X @ (# ++ [s <- 1])
+
+  Z = X @ (K <| dom![t <- 0]) @ [s <- 10]
+end
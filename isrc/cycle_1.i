@@ -0,0 +1,7 @@
+// Cyclic demand
+//   * 'X' depends on itself at the same context, evaluation stops with a
+//     cyclic demand error instead of overflowing the stack.
+X
+where
+  X = X + 1
+end
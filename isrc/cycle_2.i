@@ -0,0 +1,10 @@
+// Cyclic demand through another identifier
+//   * 'B' does not vary in 't' so that 'A' demands itself through 'B'.
+A @ [t <- 3]
+where
+  dim t <- 0
+
+  A = B + 1
+
+  B = A @ [t <- #.t]
+end
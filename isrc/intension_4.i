@@ -0,0 +1,11 @@
+(=> test) @ [t <- 3, s <- 3]
+where
+  dim t <- 0
+  dim s <- 0
+
+  test = intension @ [t <- 1, s <- 1]
+
+  intension = ↑X
+
+  X = #.t + #.s
+end
@@ -0,0 +1,10 @@
+(X @ (=> test)) @ [t <- 3]
+where
+  dim t <- 0
+
+  test = intension @ [t <- 0]
+
+  intension = ↑#
+
+  X = #.t
+end
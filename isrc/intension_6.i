@@ -0,0 +1,11 @@
+(=> test) @ [t <- 3, s <- 3]
+where
+  dim t <- 0
+  dim s <- 0
+
+  test = outer @ [t <- 1, s <- 1]
+
+  outer = ↑(=> inner)
+
+  inner = if #.t <= 0 then {t} #.t else {t} #.t + #.s
+end
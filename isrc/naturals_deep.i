@@ -0,0 +1,15 @@
+// Deep streams
+//   * Every natural demands the previous one, the demands are kept on the
+//     stack of the evaluator rather than the native stack.
+naturals @ [t <- 5000]
+where
+    dim t <- 0
+
+    naturals = fby.t 0 (naturals + 1)
+
+    fby.t X Y =
+        if #.t <= 0 then
+            X
+        else
+            Y @ [t <- #.t - 1]
+end
@@ -0,0 +1,11 @@
+// Partial evaluation
+//   * 'Y' varies in the hidden dimension of 't' which is not in the domain,
+//     the result is a residual in which 'X' has already been computed.
+X + Y
+where
+  dim t <- 0
+
+  X = 5
+
+  Y = #.t
+end
@@ -0,0 +1,15 @@
+// Deep residuals
+//   * Every intension applies the one before it and the innermost one is
+//     missing the hidden dimension of 's', the residual is rebuilt on the
+//     stack of the evaluator rather than the native stack.
+=> (nested @ [n <- 300])
+where
+  dim n <- 0
+  dim s <- 0
+
+  nested =
+    if #.n <= 0 then
+      {} #.s
+    else
+      {n} (=> nested @ [n <- #.n - 1]) + 1
+end
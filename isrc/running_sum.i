@@ -0,0 +1,15 @@
+// Running sum
+//   * Every point of 'sum' only looks back at the previous one, so the
+//     cache only needs to keep the last few points.
+sum @ [t <- 1000]
+where
+    dim t <- 0
+
+    sum = fby.t 0 (sum + #.t)
+
+    fby.t X Y =
+        if #.t <= 0 then
+            X
+        else
+            Y @ [t <- #.t - 1]
+end
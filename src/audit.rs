@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use backend::CacheBackend;
+use cache::{Cache, Key};
+use domain::Domain;
+use either::Either;
+use encoding::encode;
+use error::EvalError;
+use limits::{EvalStats, Meter};
+use rng::Rng;
+use runtime::Runtime;
+use value::Value;
+
+// The entries claimed by a peer, shared by the spot checks of an audit.
+#[derive(Clone, Debug)]
+pub struct Claims(Rc<HashMap<Key, Either<Value, Domain>>>);
+
+impl Claims {
+    pub fn new<I: Iterator<Item = (Key, Either<Value, Domain>)>>(entries: I) -> Claims {
+        Claims(Rc::new(entries.collect()))
+    }
+
+    pub fn of(c: &Cache) -> Claims {
+        Claims::new(c.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&Either<Value, Domain>> {
+        self.0.get(key)
+    }
+}
+
+// The backend of a spot check, which finds every claim but the one being
+// checked and keeps what the check computes apart.
+#[derive(Debug)]
+struct ClaimBackend {
+    claims: Claims,
+    checked: Key,
+    computed: HashMap<Key, Either<Value, Domain>>,
+    // The number of entries found, kept as they are added and removed.
+    len: usize,
+}
+
+impl ClaimBackend {
+    fn new(claims: Claims, checked: Key) -> ClaimBackend {
+        let len = claims.len() - claims.get(&checked).map_or(0, |_| 1);
+        ClaimBackend {
+            claims,
+            checked,
+            computed: HashMap::new(),
+            len,
+        }
+    }
+
+    // Whether a computed entry is found only because it was computed, rather
+    // than standing in for a claim.
+    fn unclaimed(&self, key: &Key) -> bool {
+        *key == self.checked || self.claims.get(key).is_none()
+    }
+}
+
+impl CacheBackend for ClaimBackend {
+    fn find(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        if let Some(v) = self.computed.get(key) {
+            return Some(v.clone());
+        }
+        if *key == self.checked {
+            return None;
+        }
+        self.claims.get(key).cloned()
+    }
+
+    fn add(&mut self, key: Key, v: Either<Value, Domain>) {
+        if self.unclaimed(&key) && !self.computed.contains_key(&key) {
+            self.len += 1;
+        }
+        self.computed.insert(key, v);
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        let v = self.computed.remove(key);
+        if v.is_some() && self.unclaimed(key) {
+            self.len -= 1;
+        }
+        v
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Key, Either<Value, Domain>)> + 'a> {
+        let claims = self.claims
+            .0
+            .iter()
+            .filter(move |&(key, _)| *key != self.checked && !self.computed.contains_key(key))
+            .map(|(key, v)| (key.clone(), v.clone()));
+        let computed = self.computed.iter().map(|(key, v)| (key.clone(), v.clone()));
+        Box::new(computed.chain(claims))
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Verdict {
+    // The equation gives the claimed value from the claimed values of its
+    // dependencies.
+    Consistent,
+    // It gives another value.
+    Inconsistent(Either<Value, Domain>),
+    // The program does not demand the claimed key at its context, the claim
+    // is not about this program.
+    NotDemanded,
+    // The equation cannot be evaluated.
+    Failed(EvalError),
+}
+
+// The outcome of checking one claimed entry.
+#[derive(Clone, Debug)]
+pub struct SpotCheck {
+    pub key: Key,
+    pub verdict: Verdict,
+    // The entries the check had to compute besides the claimed one, the
+    // dependencies which the peer did not claim.
+    pub recomputed: Vec<Key>,
+    pub stats: EvalStats,
+}
+
+impl SpotCheck {
+    pub fn is_consistent(&self) -> bool {
+        matches!(self.verdict, Verdict::Consistent)
+    }
+}
+
+// Check the claim for a key by evaluating the equation of its identifier at
+// its context once, taking the values of its dependencies from the claims.
+pub fn spot_check(runtime: &mut Runtime, claims: &Claims, key: &Key) -> SpotCheck {
+    let mut c = Cache::with_backend(ClaimBackend::new(claims.clone(), key.clone()));
+    let mut m = Meter::unlimited();
+    let result = runtime.demand_at(&key.x, key.k.clone(), &mut c, &mut m);
+    let recomputed = c.iter()
+        .filter(|(computed, _)| claims.get(computed).is_none())
+        .map(|(computed, _)| computed)
+        .collect();
+    let computed = c.iter().find(|(computed, _)| computed == key).map(|(_, v)| v);
+    let verdict = match (result, computed, claims.get(key)) {
+        (Err(err), _, _) => Verdict::Failed(err),
+        (Ok(_), None, _) | (Ok(_), _, None) => Verdict::NotDemanded,
+        (Ok(_), Some(v), Some(claimed)) => {
+            if encode(&v) == encode(claimed) {
+                Verdict::Consistent
+            } else {
+                Verdict::Inconsistent(v)
+            }
+        }
+    };
+    SpotCheck {
+        key: key.clone(),
+        verdict,
+        recomputed,
+        stats: m.stats(),
+    }
+}
+
+// Spot check a sample of the claims drawn with a seeded generator, so that an
+// audit which finds a bad claim can be repeated.
+pub fn audit(runtime: &mut Runtime, claims: &Claims, samples: usize, seed: u64) -> Vec<SpotCheck> {
+    let mut keys: Vec<&Key> = claims.0.keys().collect();
+    keys.sort_by_key(|key| key.digest);
+    let mut rng = Rng::new(seed);
+    let samples = samples.min(keys.len());
+    for i in 0..samples {
+        let j = rng.between(i as u64, keys.len() as u64 - 1) as usize;
+        keys.swap(i, j);
+    }
+    keys[..samples].iter().map(|key| spot_check(runtime, claims, key)).collect()
+}
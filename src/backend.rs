@@ -0,0 +1,316 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use cache::Key;
+use domain::Domain;
+use either::Either;
+use encoding::{decode, encode, encode_bytes, DecodeError, Decoder};
+use signature::Signature;
+use value::Value;
+
+// Where the cache keeps its entries. The cache itself only keeps track of the
+// demands in progress and of the retirement policies, so the entries can live
+// in memory, be shared between threads or be kept on disk.
+pub trait CacheBackend: Debug {
+    fn find(&mut self, key: &Key) -> Option<Either<Value, Domain>>;
+
+    // Find an entry with the signature it was received with, for the backends
+    // which take entries from elsewhere. The cache keeps the signatures of the
+    // entries added to it, the other backends have none to give.
+    fn find_signed(&mut self, key: &Key) -> Option<(Either<Value, Domain>, Option<Signature>)> {
+        self.find(key).map(|v| (v, None))
+    }
+
+    fn add(&mut self, key: Key, v: Either<Value, Domain>);
+
+    fn remove(&mut self, key: &Key) -> Option<Either<Value, Domain>>;
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Key, Either<Value, Domain>)> + 'a>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Every entry in a hash map, the backend of Cache::new().
+#[derive(Clone, Debug, Default)]
+pub struct HashMapBackend(pub HashMap<Key, Either<Value, Domain>>);
+
+impl HashMapBackend {
+    pub fn new() -> HashMapBackend {
+        HashMapBackend::default()
+    }
+}
+
+impl CacheBackend for HashMapBackend {
+    fn find(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        self.0.get(key).cloned()
+    }
+
+    fn add(&mut self, key: Key, v: Either<Value, Domain>) {
+        self.0.insert(key, v);
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        self.0.remove(key)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Key, Either<Value, Domain>)> + 'a> {
+        Box::new(self.0.iter().map(|(key, v)| (key.clone(), v.clone())))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+// At most capacity entries, the least recently used entry makes room for a
+// new one. Unlike the Lru retirement policy the bound holds without the
+// bookkeeping of the cache.
+#[derive(Clone, Debug)]
+pub struct LruBackend {
+    pub capacity: usize,
+    pub evictions: u64,
+    clock: u64,
+    entries: HashMap<Key, (u64, Either<Value, Domain>)>,
+    recency: BTreeMap<u64, Key>,
+}
+
+impl LruBackend {
+    pub fn new(capacity: usize) -> LruBackend {
+        LruBackend {
+            capacity,
+            evictions: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    fn touch(&mut self, key: &Key) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.0);
+            self.recency.insert(clock, key.clone());
+            entry.0 = clock;
+        }
+    }
+}
+
+impl CacheBackend for LruBackend {
+    fn find(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        self.touch(key);
+        self.entries.get(key).map(|(_, v)| v.clone())
+    }
+
+    fn add(&mut self, key: Key, v: Either<Value, Domain>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            let oldest = *self.recency.keys().next().unwrap();
+            let victim = self.recency.remove(&oldest).unwrap();
+            self.entries.remove(&victim);
+            self.evictions += 1;
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.entries.insert(key, (self.clock, v));
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        self.entries.remove(key).map(|(used, v)| {
+            self.recency.remove(&used);
+            v
+        })
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Key, Either<Value, Domain>)> + 'a> {
+        Box::new(self.entries.iter().map(|(key, (_, v))| (key.clone(), v.clone())))
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+// A hash map split into shards behind their own locks. Clones share the
+// entries, so the caches of evaluations on different threads can share what
+// each of them computes.
+#[derive(Clone, Debug)]
+pub struct ShardedBackend {
+    shards: Arc<Vec<Shard>>,
+}
+
+type Shard = Mutex<HashMap<Key, Either<Value, Domain>>>;
+
+impl ShardedBackend {
+    pub fn new(shards: usize) -> ShardedBackend {
+        ShardedBackend {
+            shards: Arc::new((0..shards.max(1)).map(|_| Mutex::new(HashMap::new())).collect()),
+        }
+    }
+
+    fn shard(&self, key: &Key) -> &Mutex<HashMap<Key, Either<Value, Domain>>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+impl CacheBackend for ShardedBackend {
+    fn find(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        self.shard(key).lock().unwrap().get(key).cloned()
+    }
+
+    fn add(&mut self, key: Key, v: Either<Value, Domain>) {
+        self.shard(&key).lock().unwrap().insert(key, v);
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        self.shard(key).lock().unwrap().remove(key)
+    }
+
+    // A snapshot, entries added meanwhile by other threads may be missing.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Key, Either<Value, Domain>)> + 'a> {
+        let mut entries = vec![];
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            entries.extend(shard.iter().map(|(key, v)| (key.clone(), v.clone())));
+        }
+        Box::new(entries.into_iter())
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().len()).sum()
+    }
+}
+
+// Entries in a log file which outlives the process. Every insertion and
+// removal is appended to the log, reopening the file replays it. Only the
+// position of each entry is kept in memory, its value is read back when it
+// is found. A record cut short by a crash ends the log.
+#[derive(Debug)]
+pub struct DiskBackend {
+    pub path: PathBuf,
+    file: File,
+    end: u64,
+    index: HashMap<Key, u64>,
+}
+
+const ADD: u8 = 1;
+const REMOVE: u8 = 0;
+
+// The tag and key of the record at the given offset, and the offset of its
+// value.
+fn read_record(r: &mut Decoder, at: u64) -> Result<(u8, Key, u64), DecodeError> {
+    let tag = r.u8()?;
+    let key_bytes = r.bytes()?;
+    let key = decode(key_bytes)?;
+    if tag == ADD {
+        r.bytes()?;
+    }
+    Ok((tag, key, at + 5 + key_bytes.len() as u64))
+}
+
+impl DiskBackend {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<DiskBackend> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+        let mut index = HashMap::new();
+        let mut r = Decoder::new(&bytes);
+        let mut end = 0;
+        while !r.is_empty() {
+            match read_record(&mut r, end) {
+                Ok((ADD, key, at)) => {
+                    index.insert(key, at);
+                }
+                Ok((_, key, _)) => {
+                    index.remove(&key);
+                }
+                Err(DecodeError(_)) => break,
+            }
+            end = (bytes.len() - r.remaining()) as u64;
+        }
+        file.set_len(end)?;
+        Ok(DiskBackend {
+            path,
+            file,
+            end,
+            index,
+        })
+    }
+
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(record)?;
+        self.end += record.len() as u64;
+        Ok(())
+    }
+
+    fn read(&self, at: u64) -> io::Result<Either<Value, Domain>> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(at))?;
+        let mut len = [0; 4];
+        file.read_exact(&mut len)?;
+        let mut bytes = vec![0; Decoder::new(&len).u32().unwrap() as usize];
+        file.read_exact(&mut bytes)?;
+        decode(&bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.0))
+    }
+
+    // Wait until the log is on disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+// The cache has no way to report a failing disk, an entry which cannot be
+// written or read back is simply not in the cache and gets recomputed.
+impl CacheBackend for DiskBackend {
+    fn find(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        let at = *self.index.get(key)?;
+        self.read(at).ok()
+    }
+
+    fn add(&mut self, key: Key, v: Either<Value, Domain>) {
+        let encoded_key = encode(&key);
+        let mut record = vec![ADD];
+        encode_bytes(&encoded_key, &mut record);
+        let at = self.end + record.len() as u64;
+        encode_bytes(&encode(&v), &mut record);
+        if self.append(&record).is_ok() {
+            self.index.insert(key, at);
+        }
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        let at = self.index.remove(key)?;
+        let mut record = vec![REMOVE];
+        encode_bytes(&encode(key), &mut record);
+        let _ = self.append(&record);
+        self.read(at).ok()
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Key, Either<Value, Domain>)> + 'a> {
+        Box::new(
+            self.index
+                .iter()
+                .filter_map(move |(key, &at)| self.read(at).ok().map(|v| (key.clone(), v))),
+        )
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+}
@@ -0,0 +1,46 @@
+extern crate gaia;
+
+use std::env;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use gaia::backend::ShardedBackend;
+use gaia::ed25519::PublicKey;
+use gaia::node::Node;
+use gaia::signature::MemoryKeystore;
+
+// Serve a cache to the gaia nodes which connect to it, taking the entries
+// signed by the public keys given, in hex.
+//
+//     gaia-node 127.0.0.1:7700 d75a9801..
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let addr = match args.first() {
+        Some(addr) => addr,
+        None => {
+            eprintln!("usage: gaia-node <address> <trusted public key>...");
+            process::exit(2);
+        }
+    };
+    let mut keystore = MemoryKeystore::new().require_signatures();
+    for key in &args[1..] {
+        match PublicKey::from_hex(key) {
+            Some(key) => keystore = keystore.trust(key),
+            None => {
+                eprintln!("gaia-node: invalid public key {}", key);
+                process::exit(2);
+            }
+        }
+    }
+    let node = match Node::bind(addr.as_str(), ShardedBackend::new(16), keystore) {
+        Ok(node) => node,
+        Err(err) => {
+            eprintln!("gaia-node: cannot listen at {}: {}", addr, err);
+            process::exit(1);
+        }
+    };
+    println!("gaia-node listening at {}", node.addr);
+    loop {
+        thread::sleep(Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,49 @@
+extern crate gaia;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::process;
+use gaia::worker::Worker;
+
+// Only the rule for whole programs is used.
+#[allow(dead_code)]
+mod grammar {
+    include!(concat!(env!("OUT_DIR"), "/grammar.rs"));
+}
+
+// Answer the demands of a coordinator on the standard input and output, for
+// the programs given, which the coordinator names by their hashes.
+//
+//     gaia worker fib.i
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(|command| command.as_str()) != Some("worker") {
+        eprintln!("usage: gaia worker <program>...");
+        process::exit(2);
+    }
+    let mut worker = Worker::new();
+    for path in &args[1..] {
+        let mut source = String::new();
+        if let Err(err) = File::open(path).and_then(|mut f| f.read_to_string(&mut source)) {
+            eprintln!("gaia worker: cannot read {}: {}", path, err);
+            process::exit(1);
+        }
+        match grammar::scope(&source) {
+            Ok(body) => {
+                worker.load(body[0].clone());
+            }
+
+            Err(err) => {
+                eprintln!("gaia worker: cannot parse {}: {}", path, err);
+                process::exit(1);
+            }
+        }
+    }
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if let Err(err) = worker.serve(stdin.lock(), stdout.lock()) {
+        eprintln!("gaia worker: {}", err);
+        process::exit(1);
+    }
+}
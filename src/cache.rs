@@ -1,41 +1,656 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::Path;
+use backend::{CacheBackend, HashMapBackend};
 use context::Context;
 use domain::Domain;
 use either::Either;
+use environment::Environment;
+use digest::{sha256, Digest};
+use encoding::{encode, encode_bytes, encode_u32, Decode, DecodeError, Decoder, Encode};
+use error::{LedgerError, LoadError, SignatureError};
+use expression::Expression;
+use ledger::{self, Head, Ledger};
+use merge::{Conflict, KeepOurs, MergePolicy, MergeReport, Resolution};
+use retirement::RetirementPolicy;
+use signature::{self, Keystore, Signature};
 use value::Value;
 
 type Identifier = String;
 
-#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+// An identifier at a context, addressed by the digest of the definition of
+// the identifier and of everything it refers to, and of the context. Caches
+// shared by programs which agree on these share the entry whatever else they
+// define, and two different computations never share one. The identifier
+// and the context are kept to report on the entry.
+#[derive(Clone, Debug)]
 pub struct Key {
     pub x: Identifier,
     pub k: Context,
+    pub digest: Digest,
 }
 
-#[derive(Clone, Debug)]
+impl Key {
+    pub fn new(x: Identifier, definition: &Digest, k: Context) -> Key {
+        let mut bytes = definition.0.to_vec();
+        k.encode(&mut bytes);
+        Key {
+            x,
+            k,
+            digest: sha256(&bytes),
+        }
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.digest == other.digest
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.digest.hash(state)
+    }
+}
+
+// The header of a saved cache, followed by the version of the format.
+const MAGIC: &[u8] = b"gaia-cache";
+const VERSION: u32 = 4;
+
+// When an entry was added and last used, in ticks of the cache clock which
+// advances on every insertion and every hit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Age {
+    pub added: u64,
+    pub used: u64,
+}
+
+// The number of entries retired from the cache.
+#[derive(Clone, Debug, Default)]
+pub struct Evictions {
+    pub total: u64,
+    pub by_policy: HashMap<String, u64>,
+    pub by_identifier: HashMap<Identifier, u64>,
+}
+
+#[derive(Debug)]
 pub struct Cache {
-    pub cache: HashMap<Key, Either<Value, Domain>>,
+    pub backend: Box<dyn CacheBackend>,
+    // The demands which are being evaluated, outermost first, and the
+    // position of each of them in that chain.
+    pub pending: Vec<Key>,
+    pub in_progress: HashMap<Key, usize>,
+    // The entries which each entry read while it was computed and the other
+    // way around, so that redefining an identifier invalidates exactly the
+    // entries which depend on it. The entries read by the demands in
+    // progress are collected alongside them.
+    reads: HashMap<Key, Vec<Key>>,
+    readers: HashMap<Key, HashSet<Key>>,
+    reading: Vec<Vec<Key>>,
+    // The retirement policies and the bookkeeping they rely on, which is only
+    // kept while there are policies.
+    pub policies: Vec<Box<dyn RetirementPolicy>>,
+    pub evictions: Evictions,
+    pub clock: u64,
+    // The digests of the definitions demanded by the evaluation in progress.
+    pub definitions: HashMap<Identifier, Digest>,
+    ages: HashMap<Key, Age>,
+    recency: BTreeMap<u64, Key>,
+    by_identifier: BTreeSet<(Identifier, u64)>,
+    counts: HashMap<Identifier, usize>,
+    // Signs the entries computed here and decides which entries from
+    // elsewhere are accepted, with the signatures of the entries.
+    pub keystore: Option<Box<dyn Keystore>>,
+    signatures: HashMap<Key, Signature>,
+    // The gas each entry computed here was charged, which a demand that finds
+    // it is charged again.
+    gas: HashMap<Key, u64>,
+    // Every insertion is appended to the ledger, if there is one.
+    pub ledger: Option<Ledger>,
+    // The keys added since the mark was set, nothing is kept without a mark.
+    added: Option<Vec<Key>>,
 }
 
 impl Cache {
     pub fn new() -> Cache {
+        Cache::with_backend(HashMapBackend::new())
+    }
+
+    // A cache which keeps its entries in the given backend, any entries
+    // already there are found by the evaluation.
+    pub fn with_backend<B: CacheBackend + 'static>(backend: B) -> Cache {
         Cache {
-            cache: HashMap::new(),
+            backend: Box::new(backend),
+            pending: vec![],
+            in_progress: HashMap::new(),
+            reads: HashMap::new(),
+            readers: HashMap::new(),
+            reading: vec![],
+            policies: vec![],
+            evictions: Evictions::default(),
+            clock: 0,
+            definitions: HashMap::new(),
+            ages: HashMap::new(),
+            recency: BTreeMap::new(),
+            by_identifier: BTreeSet::new(),
+            counts: HashMap::new(),
+            keystore: None,
+            signatures: HashMap::new(),
+            gas: HashMap::new(),
+            ledger: None,
+            added: None,
+        }
+    }
+
+    // Sign the entries computed from now on and check the entries imported
+    // with the keystore.
+    pub fn with_keystore<K: Keystore + 'static>(mut self, keystore: K) -> Cache {
+        self.keystore = Some(Box::new(keystore));
+        self
+    }
+
+    // Retire entries according to the policy in addition to the ones so far.
+    // Retired entries are recomputed when they are demanded again.
+    pub fn with_policy<P: RetirementPolicy + 'static>(mut self, policy: P) -> Cache {
+        if self.policies.is_empty() {
+            let keys: Vec<Key> = self.backend.iter().map(|(key, _)| key).collect();
+            for key in keys {
+                self.clock += 1;
+                let clock = self.clock;
+                self.ages.insert(key.clone(), Age { added: clock, used: clock });
+                self.index(key, clock);
+            }
+        }
+        self.policies.push(Box::new(policy));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.backend.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backend.is_empty()
+    }
+
+    // The digest of the definition of an identifier, which is kept for the
+    // rest of the evaluation once everything it refers to is defined.
+    pub fn definition(&mut self, x: &Identifier, e: &Environment) -> Digest {
+        if let Some(digest) = self.definitions.get(x) {
+            return *digest;
+        }
+        let (digest, complete) = e.digest(x);
+        if complete {
+            self.definitions.insert(x.clone(), digest);
+        }
+        digest
+    }
+
+    // Find an entry, which the innermost demand in progress reads.
+    pub fn find(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        let key = key.clone();
+        if let Some(reading) = self.reading.last_mut() {
+            reading.push(key.clone());
+        }
+        let v = match self.backend.find_signed(&key) {
+            Some((v, signature)) => {
+                if let Some(signature) = signature {
+                    self.signatures.insert(key.clone(), signature);
+                }
+                Some(v)
+            }
+            None => None,
+        };
+        if let Some(age) = self.ages.get(&key).cloned() {
+            self.unindex(&key, age.used);
+            if v.is_some() {
+                self.clock += 1;
+                let clock = self.clock;
+                self.index(key.clone(), clock);
+                self.ages.insert(key, Age { added: age.added, used: clock });
+            } else {
+                // The backend dropped the entry on its own.
+                self.ages.remove(&key);
+            }
+        }
+        v
+    }
+
+    pub fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Key, Either<Value, Domain>)> + 'a> {
+        self.backend.iter()
+    }
+
+    // Add an entry computed here, signed when the keystore holds a key pair.
+    pub fn add(&mut self, key: Key, v: Either<Value, Domain>) -> Either<Value, Domain> {
+        let signature = match self.keystore {
+            Some(ref keystore) => signature::sign(&**keystore, &key, &v),
+            None => None,
+        };
+        self.insert(key, v, signature)
+    }
+
+    // Add an entry computed here for the given gas.
+    pub fn add_with_gas(&mut self, key: Key, v: Either<Value, Domain>, gas: u64) {
+        self.add(key.clone(), v);
+        self.gas.insert(key, gas);
+    }
+
+    // The gas the entry for a key was charged when it was computed, unknown
+    // for the entries which came from elsewhere.
+    pub fn gas(&self, key: &Key) -> Option<u64> {
+        self.gas.get(key).cloned()
+    }
+
+    // Add an entry received from elsewhere with its signature. The entry is
+    // refused unless it passes the checks of the keystore.
+    pub fn import(
+        &mut self,
+        key: Key,
+        v: Either<Value, Domain>,
+        signature: Option<Signature>,
+    ) -> Result<(), SignatureError> {
+        signature::check(self.keystore.as_deref(), &key, &v, signature.as_ref())?;
+        self.insert(key, v, signature);
+        Ok(())
+    }
+
+    // The entries with their signatures, as they are imported elsewhere.
+    pub fn export(&self) -> Vec<(Key, Either<Value, Domain>, Option<Signature>)> {
+        self.iter()
+            .map(|(key, v)| {
+                let signature = self.signatures.get(&key).cloned();
+                (key, v, signature)
+            })
+            .collect()
+    }
+
+    // Start collecting the keys of the entries added from now on, forgetting
+    // the ones collected since an earlier mark.
+    pub fn mark(&mut self) {
+        self.added = Some(vec![]);
+    }
+
+    // The entries added since the mark with their signatures, as export gives
+    // them, in the order they were added. The mark is cleared.
+    pub fn export_since_mark(&mut self) -> Vec<(Key, Either<Value, Domain>, Option<Signature>)> {
+        let mut seen = HashSet::new();
+        let mut entries = vec![];
+        for key in self.added.take().unwrap_or_default() {
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            if let Some(v) = self.backend.find(&key) {
+                let signature = self.signatures.get(&key).cloned();
+                entries.push((key, v, signature));
+            }
         }
+        entries
+    }
+
+    // Append every entry added from now on to the ledger. The evaluation
+    // cannot report a failing disk, the ledger keeps the error and stops.
+    pub fn with_ledger(mut self, ledger: Ledger) -> Cache {
+        self.ledger = Some(ledger);
+        self
     }
 
-    pub fn find(&mut self, x: Identifier, k: Context) -> Option<&Either<Value, Domain>> {
-        self.cache.get(&Key { x: x.clone(), k: k })
+    // Add the entries recorded in a ledger which ends at the expected head,
+    // returning how many there were. The ledger is refused when it was
+    // damaged or when one of its entries fails the checks of the keystore.
+    // The entries are not recorded again in the ledger of the cache.
+    pub fn replay<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        expected: Head,
+    ) -> Result<usize, LedgerError> {
+        let records = ledger::replay(path, expected)?;
+        let keystore = self.keystore.as_deref();
+        for record in &records {
+            signature::check(keystore, &record.key, &record.v, record.signature.as_ref())
+                .map_err(|err| LedgerError::Signature(record.key.x.clone(), err))?;
+        }
+        let n = records.len();
+        for record in records {
+            self.store(record.key, record.v, record.signature);
+        }
+        Ok(n)
+    }
+
+    pub fn signature(&self, key: &Key) -> Option<&Signature> {
+        self.signatures.get(key)
+    }
+
+    fn insert(
+        &mut self,
+        key: Key,
+        v: Either<Value, Domain>,
+        signature: Option<Signature>,
+    ) -> Either<Value, Domain> {
+        if let Some(ref mut ledger) = self.ledger {
+            let _ = ledger.append(key.clone(), v.clone(), signature);
+        }
+        self.store(key, v, signature)
     }
 
-    pub fn add(
+    // Insert an entry without recording it in the ledger, for the entries
+    // which were recorded before.
+    fn store(
         &mut self,
-        x: Identifier,
-        k: Context,
+        key: Key,
         v: Either<Value, Domain>,
+        signature: Option<Signature>,
     ) -> Either<Value, Domain> {
-        // println!("Inserting {} {} = {:?}", x.clone(), k.print(), v.clone());
-        self.cache.insert(Key { x: x, k: k }, v.clone());
+        match signature {
+            Some(signature) => self.signatures.insert(key.clone(), signature),
+            None => self.signatures.remove(&key),
+        };
+        self.gas.remove(&key);
+        if let Some(ref mut added) = self.added {
+            added.push(key.clone());
+        }
+        self.backend.add(key.clone(), v.clone());
+        if !self.policies.is_empty() {
+            self.clock += 1;
+            let clock = self.clock;
+            if let Some(age) = self.ages.insert(key.clone(), Age { added: clock, used: clock }) {
+                self.unindex(&key, age.used);
+            }
+            self.index(key.clone(), clock);
+            self.retire(&key);
+        }
         v
     }
+
+    // Record the entries which the entry for key read.
+    pub fn depends_on(&mut self, key: &Key, reads: Vec<Key>) {
+        self.forget_reads(key);
+        for read in &reads {
+            self.readers.entry(read.clone()).or_default().insert(key.clone());
+        }
+        self.reads.insert(key.clone(), reads);
+    }
+
+    fn forget_reads(&mut self, key: &Key) {
+        for read in self.reads.remove(key).unwrap_or(vec![]) {
+            let unread = match self.readers.get_mut(&read) {
+                Some(readers) => {
+                    readers.remove(key);
+                    readers.is_empty()
+                }
+                None => false,
+            };
+            if unread {
+                self.readers.remove(&read);
+            }
+        }
+    }
+
+    // Add the entries of another cache, keeping ours where the two disagree.
+    pub fn merge(&mut self, other: &Cache) -> MergeReport {
+        self.merge_with(other, &mut KeepOurs)
+    }
+
+    // Add the entries of another cache, each of them checked as it is
+    // imported. Entries are the same when their keys are, which covers the
+    // identifier, its definitions and the context, so two different values
+    // for one key are a conflict, which the policy resolves.
+    pub fn merge_with(&mut self, other: &Cache, policy: &mut dyn MergePolicy) -> MergeReport {
+        let mut report = MergeReport::default();
+        let mut theirs = other.export();
+        theirs.sort_by_key(|(key, _, _)| key.digest);
+        // Every entry is compared with ours before anything changes, so that
+        // the entries removed to be recomputed are not taken from the other
+        // cache instead.
+        let mut imports = vec![];
+        let mut recompute = vec![];
+        for (key, v, signature) in theirs {
+            match self.backend.find(&key) {
+                None => imports.push((key, v, signature, true)),
+
+                Some(ref ours) if encode(ours) == encode(&v) => report.identical.push(key),
+
+                Some(ours) => {
+                    let resolution = policy.resolve(&key, &ours, &v);
+                    report.conflicts.push(Conflict {
+                        key: key.clone(),
+                        ours,
+                        theirs: v.clone(),
+                        resolution,
+                    });
+                    match resolution {
+                        Resolution::KeepOurs => (),
+                        Resolution::TakeTheirs => imports.push((key, v, signature, false)),
+                        Resolution::Recompute => recompute.push(key),
+                    }
+                }
+            }
+        }
+        for (key, v, signature, new) in imports {
+            match self.import(key.clone(), v, signature) {
+                Ok(()) if new => report.added.push(key),
+                Ok(()) => (),
+                Err(err) => report.rejected.push((key, err)),
+            }
+        }
+        // What read our value goes with it.
+        for key in recompute {
+            self.invalidate_entry(&key);
+        }
+        report
+    }
+
+    // Remove an entry, it is recomputed when it is demanded again.
+    pub fn remove(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        if let Some(age) = self.ages.remove(key) {
+            self.unindex(key, age.used);
+        }
+        self.forget_reads(key);
+        self.signatures.remove(key);
+        self.gas.remove(key);
+        self.backend.remove(key)
+    }
+
+    // Remove the entries of an identifier computed from a definition which
+    // changed and, transitively, every entry which read one of them. The
+    // entries of the same identifier defined otherwise, e.g. by another
+    // program sharing the cache, stay. The entries which were removed are
+    // returned. Entries loaded from a saved cache did not record what they
+    // read, only their own definition invalidates them.
+    pub fn invalidate(&mut self, x: &Identifier, definition: &Digest) -> Vec<Key> {
+        let queue = self.backend
+            .iter()
+            .map(|(key, _)| key)
+            .chain(self.readers.keys().cloned())
+            .filter(|key| &key.x == x && Key::new(x.clone(), definition, key.k.clone()) == *key)
+            .collect();
+        self.invalidate_from(queue)
+    }
+
+    // Remove an entry which no longer holds and, transitively, every entry
+    // which read it.
+    pub fn invalidate_entry(&mut self, key: &Key) -> Vec<Key> {
+        let mut queue = VecDeque::new();
+        queue.push_back(key.clone());
+        self.invalidate_from(queue)
+    }
+
+    fn invalidate_from(&mut self, mut queue: VecDeque<Key>) -> Vec<Key> {
+        let mut seen = HashSet::new();
+        let mut removed = vec![];
+        while let Some(key) = queue.pop_front() {
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            if let Some(readers) = self.readers.remove(&key) {
+                queue.extend(readers);
+            }
+            if self.remove(&key).is_some() {
+                removed.push(key);
+            }
+        }
+        removed
+    }
+
+    // Remove every entry.
+    pub fn clear(&mut self) {
+        let keys: Vec<Key> = self.backend.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            self.remove(&key);
+        }
+        self.reads.clear();
+        self.readers.clear();
+    }
+
+    // When the entry for a key was added and last used.
+    pub fn age(&self, key: &Key) -> Option<Age> {
+        self.ages.get(key).cloned()
+    }
+
+    // The entries from the least to the most recently used.
+    pub fn least_recently_used<'a>(&'a self) -> Box<dyn Iterator<Item = (u64, &'a Key)> + 'a> {
+        Box::new(self.recency.iter().map(|(used, key)| (*used, key)))
+    }
+
+    // The entries for an identifier from the least to the most recently used.
+    pub fn least_recently_used_of<'a>(
+        &'a self,
+        x: &Identifier,
+    ) -> Box<dyn Iterator<Item = &'a Key> + 'a> {
+        let from = (x.clone(), 0);
+        let to = (x.clone(), u64::MAX);
+        Box::new(self.by_identifier.range(from..=to).map(move |&(_, used)| &self.recency[&used]))
+    }
+
+    // The number of entries for an identifier.
+    pub fn count_of(&self, x: &Identifier) -> usize {
+        self.counts.get(x).cloned().unwrap_or(0)
+    }
+
+    fn index(&mut self, key: Key, used: u64) {
+        *self.counts.entry(key.x.clone()).or_insert(0) += 1;
+        self.by_identifier.insert((key.x.clone(), used));
+        self.recency.insert(used, key);
+    }
+
+    fn unindex(&mut self, key: &Key, used: u64) {
+        if let Some(count) = self.counts.get_mut(&key.x) {
+            *count -= 1;
+        }
+        self.by_identifier.remove(&(key.x.clone(), used));
+        self.recency.remove(&used);
+    }
+
+    // Ask every policy which entries to retire now that key was added.
+    fn retire(&mut self, key: &Key) {
+        let mut policies = mem::take(&mut self.policies);
+        for policy in policies.iter_mut() {
+            for victim in policy.retire(self, key) {
+                if self.remove(&victim).is_some() {
+                    self.evictions.total += 1;
+                    *self.evictions.by_policy.entry(policy.name()).or_insert(0) += 1;
+                    *self.evictions.by_identifier.entry(victim.x.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+        self.policies = policies;
+    }
+
+    // Save the entries to a file with their signatures. The entries only hold
+    // for the program they were computed for, so the compiled program is
+    // saved with them.
+    pub fn save<P: AsRef<Path>>(&self, path: P, program: &Expression) -> io::Result<()> {
+        let mut out = MAGIC.to_vec();
+        encode_u32(VERSION, &mut out);
+        encode_bytes(&encode(program), &mut out);
+        let mut entries: Vec<(Vec<u8>, Vec<u8>, Vec<u8>)> = self.export()
+            .into_iter()
+            .map(|(key, v, signature)| (encode(&key), encode(&v), encode(&signature)))
+            .collect();
+        entries.sort();
+        encode_u32(entries.len() as u32, &mut out);
+        for (key, v, signature) in entries {
+            out.extend(key);
+            out.extend(v);
+            out.extend(signature);
+        }
+        File::create(path)?.write_all(&out)
+    }
+
+    // Add the entries saved for the program, returning how many there were.
+    // Entries saved for any other program are refused, and so is the file
+    // when one of its entries fails the checks of the keystore. The entries
+    // are not recorded in the ledger of the cache.
+    pub fn load<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        program: &Expression,
+    ) -> Result<usize, LoadError> {
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+        let mut r = Decoder::new(&bytes);
+        if r.take(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(LoadError::Format(DecodeError("not a saved cache".to_string())));
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(LoadError::Version(version));
+        }
+        if r.bytes()? != &encode(program)[..] {
+            return Err(LoadError::ProgramChanged);
+        }
+        let n = r.u32()?;
+        let mut entries = vec![];
+        for _ in 0..n {
+            let key = Key::decode(&mut r)?;
+            let v = Decode::decode(&mut r)?;
+            let signature: Option<Signature> = Decode::decode(&mut r)?;
+            entries.push((key, v, signature));
+        }
+        if !r.is_empty() {
+            let trailing = format!("{} trailing bytes", r.remaining());
+            return Err(LoadError::Format(DecodeError(trailing)));
+        }
+        let keystore = self.keystore.as_deref();
+        for (key, v, signature) in &entries {
+            signature::check(keystore, key, v, signature.as_ref())
+                .map_err(|err| LoadError::Signature(key.x.clone(), err))?;
+        }
+        for (key, v, signature) in entries {
+            self.store(key, v, signature);
+        }
+        Ok(n as usize)
+    }
+
+    // Mark a demand as in progress. When the demand is already in progress
+    // the chain of demands which leads back to it is returned instead.
+    pub fn begin(&mut self, key: Key) -> Result<(), Vec<Key>> {
+        match self.in_progress.get(&key).cloned() {
+            Some(i) => {
+                let mut chain = self.pending[i..].to_vec();
+                chain.push(key);
+                Err(chain)
+            }
+
+            None => {
+                self.in_progress.insert(key.clone(), self.pending.len());
+                self.pending.push(key);
+                self.reading.push(vec![]);
+                Ok(())
+            }
+        }
+    }
+
+    // The demand in progress ended, returning the entries it read.
+    pub fn end(&mut self) -> Vec<Key> {
+        if let Some(key) = self.pending.pop() {
+            self.in_progress.remove(&key);
+        }
+        self.reading.pop().unwrap_or(vec![])
+    }
 }
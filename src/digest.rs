@@ -0,0 +1,28 @@
+use std::fmt;
+
+use sha2::{Digest as _, Sha256};
+
+// A SHA-256 digest. The cache is addressed by digests of what it computes so
+// that entries can be shared by programs and nodes which agree on nothing but
+// the bytes they hash.
+#[derive(Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Digest(pub [u8; 32]);
+
+impl Digest {
+    pub fn hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Digest({})", self.hex())
+    }
+}
+
+// SHA-256 as in FIPS 180-4, by the sha2 crate.
+pub fn sha256(bytes: &[u8]) -> Digest {
+    let mut digest = [0; 32];
+    digest.copy_from_slice(&Sha256::digest(bytes));
+    Digest(digest)
+}
@@ -1,5 +1,7 @@
 use colored::*;
 use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::iter::FromIterator;
 
 use value::*;
@@ -80,3 +82,19 @@ impl Domain {
         format!("{}{}", s, "}".bright_white())
     }
 }
+
+// A domain is a set, so its hash must not depend on the iteration order of
+// the underlying HashSet. Each dimension is hashed on its own and the
+// results are combined with a commutative operation.
+impl Hash for Domain {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut h: u64 = 0;
+        for dim in self.0.iter() {
+            let mut s = DefaultHasher::new();
+            dim.hash(&mut s);
+            h = h.wrapping_add(s.finish());
+        }
+        self.0.len().hash(state);
+        h.hash(state);
+    }
+}
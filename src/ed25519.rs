@@ -0,0 +1,72 @@
+use std::fmt;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+// Ed25519 signatures as in RFC 8032, by ed25519-dalek, whose arithmetic on
+// secrets runs in constant time. Only the bytes of keys and signatures leave
+// this module.
+
+#[derive(Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PublicKey(pub [u8; 32]);
+
+impl PublicKey {
+    pub fn hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // The key written in hex, as hex() writes it.
+    pub fn from_hex(s: &str) -> Option<PublicKey> {
+        if s.len() != 64 || !s.is_ascii() {
+            return None;
+        }
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(PublicKey(key))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", self.hex())
+    }
+}
+
+// A key pair derived from a secret seed of 32 bytes. The seed is all there is
+// to keep secret.
+#[derive(Clone)]
+pub struct Keypair {
+    pub public: PublicKey,
+    secret: SigningKey,
+}
+
+impl fmt::Debug for Keypair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Keypair({})", self.public.hex())
+    }
+}
+
+impl Keypair {
+    pub fn from_seed(seed: &[u8; 32]) -> Keypair {
+        let secret = SigningKey::from_bytes(seed);
+        Keypair {
+            public: PublicKey(secret.verifying_key().to_bytes()),
+            secret,
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.secret.sign(message).to_bytes()
+    }
+}
+
+// Check a signature, refusing the keys of small order and the signatures
+// which are malleable as well as the ones which do not hold.
+pub fn verify(public: &PublicKey, message: &[u8], signature: &[u8; 64]) -> bool {
+    match VerifyingKey::from_bytes(&public.0) {
+        Ok(public) => public
+            .verify_strict(message, &Signature::from_bytes(signature))
+            .is_ok(),
+        Err(_) => false,
+    }
+}
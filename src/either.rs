@@ -7,6 +7,24 @@ pub enum Either<L, R> {
     Right(R),
 }
 
+impl<L, R> Either<L, R> {
+    pub fn left(self) -> Option<L> {
+        match self {
+            Either::Left(l) => Some(l),
+
+            Either::Right(_) => None,
+        }
+    }
+
+    pub fn right(self) -> Option<R> {
+        match self {
+            Either::Left(_) => None,
+
+            Either::Right(r) => Some(r),
+        }
+    }
+}
+
 impl Either<Value, Domain> {
     pub fn expect_value(&self) -> Value {
         match *self {
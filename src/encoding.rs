@@ -0,0 +1,821 @@
+use std::collections::BTreeMap;
+use cache::Key;
+use context::Context;
+use digest::{sha256, Digest};
+use domain::Domain;
+use either::Either;
+use environment::{Definition, Environment};
+use expression::*;
+use tuple::Tuple;
+use value::*;
+
+type Identifier = String;
+
+// A compact binary encoding of the values, contexts and expressions which end
+// up in the cache, so that the extension of a program can leave the process.
+// Numbers are big endian, strings and sequences are prefixed with their
+// length and every enum variant with a tag byte.
+//
+// The encoding is canonical: whatever compares equal as a set or a map
+// encodes to the same bytes. The dimensions of a domain or of an intension
+// are written in the order of their encodings, the tuples of a context in the
+// order of the encodings of their dimensions and the definitions of an
+// environment in the order of their identifiers. Where a dimension or an
+// identifier appears twice only the first one counts, as it does for lookup.
+// Digests of the encoding are stable across processes and machines.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeError(pub String);
+
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    fn decode(r: &mut Decoder) -> Result<Self, DecodeError>;
+}
+
+pub fn encode<T: Encode>(x: &T) -> Vec<u8> {
+    let mut out = vec![];
+    x.encode(&mut out);
+    out
+}
+
+// The digest of the canonical encoding.
+pub fn digest<T: Encode>(x: &T) -> Digest {
+    sha256(&encode(x))
+}
+
+// Write the elements of a set, each of them once and in the order of their
+// encodings.
+fn encode_set<'a, T: Encode + 'a, I: Iterator<Item = &'a T>>(xs: I, out: &mut Vec<u8>) {
+    let mut xs: Vec<Vec<u8>> = xs.map(encode).collect();
+    xs.sort();
+    xs.dedup();
+    encode_u32(xs.len() as u32, out);
+    for x in xs {
+        out.extend(x);
+    }
+}
+
+// Decode a value which must span all of the bytes.
+pub fn decode<T: Decode>(bytes: &[u8]) -> Result<T, DecodeError> {
+    decode_all(Decoder::new(bytes))
+}
+
+// Decode a value received from elsewhere, which may nest no deeper than
+// MAX_DEPTH.
+pub fn decode_untrusted<T: Decode>(bytes: &[u8]) -> Result<T, DecodeError> {
+    decode_all(Decoder::untrusted(bytes))
+}
+
+fn decode_all<T: Decode>(mut r: Decoder) -> Result<T, DecodeError> {
+    let x = T::decode(&mut r)?;
+    if r.is_empty() {
+        Ok(x)
+    } else {
+        Err(DecodeError(format!("{} trailing bytes", r.remaining())))
+    }
+}
+
+// How deeply values and expressions may nest in what is decoded from
+// elsewhere. Decoding recurses on them, so such input must not nest without
+// bound; the limit leaves room on the stack of a thread of a node. What the
+// process wrote itself is decoded however deeply it nests.
+pub const MAX_DEPTH: usize = 128;
+
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize,
+    max_depth: Option<usize>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder {
+            bytes,
+            pos: 0,
+            depth: 0,
+            max_depth: None,
+        }
+    }
+
+    // A decoder for input from elsewhere, which refuses to nest deeper than
+    // MAX_DEPTH.
+    pub fn untrusted(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder {
+            max_depth: Some(MAX_DEPTH),
+            ..Decoder::new(bytes)
+        }
+    }
+
+    // Decode something nested in what is being decoded, one level deeper.
+    pub fn nested<T, F>(&mut self, f: F) -> Result<T, DecodeError>
+    where
+        F: FnOnce(&mut Decoder<'a>) -> Result<T, DecodeError>,
+    {
+        if Some(self.depth) == self.max_depth {
+            return Err(DecodeError(format!("nested too deeply at byte {}", self.pos)));
+        }
+        self.depth += 1;
+        let x = f(self);
+        self.depth -= 1;
+        x
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(DecodeError(format!("unexpected end at byte {}", self.pos)));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        let b = self.take(4)?;
+        Ok((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | (b[3] as u32))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        let hi = self.u32()? as u64;
+        let lo = self.u32()? as u64;
+        Ok(hi << 32 | lo)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let n = self.u32()? as usize;
+        self.take(n)
+    }
+}
+
+fn bad_tag(what: &str, tag: u8) -> DecodeError {
+    DecodeError(format!("invalid {} tag {}", what, tag))
+}
+
+pub fn encode_u32(n: u32, out: &mut Vec<u8>) {
+    out.extend_from_slice(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+}
+
+pub fn encode_u64(n: u64, out: &mut Vec<u8>) {
+    encode_u32((n >> 32) as u32, out);
+    encode_u32(n as u32, out);
+}
+
+pub fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    encode_u32(bytes.len() as u32, out);
+    out.extend_from_slice(bytes);
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), out);
+    }
+}
+
+impl Decode for String {
+    fn decode(r: &mut Decoder) -> Result<String, DecodeError> {
+        String::from_utf8(r.bytes()?.to_vec()).map_err(|e| DecodeError(format!("{}", e)))
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_u32(self.len() as u32, out);
+        for x in self {
+            x.encode(out);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(r: &mut Decoder) -> Result<Vec<T>, DecodeError> {
+        let n = r.u32()?;
+        let mut xs = vec![];
+        for _ in 0..n {
+            xs.push(T::decode(r)?);
+        }
+        Ok(xs)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            None => out.push(0),
+            Some(ref x) => {
+                out.push(1);
+                x.encode(out);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(r: &mut Decoder) -> Result<Option<T>, DecodeError> {
+        match r.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(r)?)),
+            tag => Err(bad_tag("option", tag)),
+        }
+    }
+}
+
+impl<T: Encode> Encode for Box<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out)
+    }
+}
+
+impl<T: Decode> Decode for Box<T> {
+    fn decode(r: &mut Decoder) -> Result<Box<T>, DecodeError> {
+        Ok(Box::new(T::decode(r)?))
+    }
+}
+
+impl Encode for Literal {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Literal::Bool(b) => out.extend_from_slice(&[0, b as u8]),
+
+            Literal::Int32(n) => {
+                out.push(1);
+                encode_u32(n, out);
+            }
+        }
+    }
+}
+
+impl Decode for Literal {
+    fn decode(r: &mut Decoder) -> Result<Literal, DecodeError> {
+        match r.u8()? {
+            0 => Ok(Literal::Bool(r.u8()? != 0)),
+            1 => Ok(Literal::Int32(r.u32()?)),
+            tag => Err(bad_tag("literal", tag)),
+        }
+    }
+}
+
+impl Encode for Dimension {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_u32(self.i, out);
+        self.v.encode(out);
+    }
+}
+
+impl Decode for Dimension {
+    fn decode(r: &mut Decoder) -> Result<Dimension, DecodeError> {
+        let i = r.u32()?;
+        let v = Value::decode(r)?;
+        Ok(Dimension { i, v })
+    }
+}
+
+impl Encode for Tuple {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.dim.encode(out);
+        self.ord.encode(out);
+    }
+}
+
+impl Decode for Tuple {
+    fn decode(r: &mut Decoder) -> Result<Tuple, DecodeError> {
+        let dim = Dimension::decode(r)?;
+        let ord = Value::decode(r)?;
+        Ok(Tuple::new(dim, ord))
+    }
+}
+
+impl Encode for Context {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut tuples: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+        for tuple in &self.0 {
+            tuples.entry(encode(&tuple.dim)).or_insert_with(|| encode(&tuple.ord));
+        }
+        encode_u32(tuples.len() as u32, out);
+        for (dim, ord) in tuples {
+            out.extend(dim);
+            out.extend(ord);
+        }
+    }
+}
+
+impl Decode for Context {
+    fn decode(r: &mut Decoder) -> Result<Context, DecodeError> {
+        Ok(Context(Vec::decode(r)?))
+    }
+}
+
+impl Encode for Domain {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_set(self.0.iter(), out)
+    }
+}
+
+impl Decode for Domain {
+    fn decode(r: &mut Decoder) -> Result<Domain, DecodeError> {
+        let dimensions: Vec<Dimension> = Vec::decode(r)?;
+        Ok(Domain::new().from_vec(dimensions))
+    }
+}
+
+impl Encode for Intension {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.k.encode(out);
+        encode_set(self.d.iter(), out);
+        self.x.encode(out);
+    }
+}
+
+impl Decode for Intension {
+    fn decode(r: &mut Decoder) -> Result<Intension, DecodeError> {
+        let k = Context::decode(r)?;
+        let d = Vec::decode(r)?;
+        let x = Box::decode(r)?;
+        Ok(Intension { k, d, x })
+    }
+}
+
+// The abstractions share their layout.
+macro_rules! abstraction {
+    ($t:ident) => {
+        impl Encode for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                self.dimensions.encode(out);
+                self.body.encode(out);
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(r: &mut Decoder) -> Result<$t, DecodeError> {
+                let dimensions = Vec::decode(r)?;
+                let body = Expression::decode(r)?;
+                Ok($t {
+                    dimensions,
+                    body,
+                })
+            }
+        }
+    };
+}
+
+abstraction!(BaseAbstraction);
+abstraction!(ValueAbstraction);
+abstraction!(NameAbstraction);
+
+// So do the applications.
+macro_rules! application {
+    ($t:ident) => {
+        impl Encode for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                self.lhs.encode(out);
+                self.args.encode(out);
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(r: &mut Decoder) -> Result<$t, DecodeError> {
+                let lhs = Expression::decode(r)?;
+                let args = Vec::decode(r)?;
+                Ok($t {
+                    lhs,
+                    args,
+                })
+            }
+        }
+    };
+}
+
+application!(BaseApplication);
+application!(ValueApplication);
+
+impl Encode for Value {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Value::Literal(ref lit) => {
+                out.push(0);
+                lit.encode(out);
+            }
+
+            Value::Dimension(ref di) => {
+                out.push(1);
+                di.encode(out);
+            }
+
+            Value::Intension(ref intens) => {
+                out.push(2);
+                intens.encode(out);
+            }
+
+            Value::BaseAbstraction(ref abstraction) => {
+                out.push(3);
+                abstraction.encode(out);
+            }
+
+            Value::ValueAbstraction(ref abstraction) => {
+                out.push(4);
+                abstraction.encode(out);
+            }
+
+            Value::NameAbstraction(ref abstraction) => {
+                out.push(5);
+                abstraction.encode(out);
+            }
+
+            Value::Identifier(ref id) => {
+                out.push(6);
+                id.encode(out);
+            }
+
+            Value::Context(ref k) => {
+                out.push(7);
+                k.encode(out);
+            }
+
+            Value::Domain(ref d) => {
+                out.push(8);
+                d.encode(out);
+            }
+
+            Value::PrimOp(ref id) => {
+                out.push(9);
+                id.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Value {
+    fn decode(r: &mut Decoder) -> Result<Value, DecodeError> {
+        r.nested(decode_value)
+    }
+}
+
+fn decode_value(r: &mut Decoder) -> Result<Value, DecodeError> {
+    Ok(match r.u8()? {
+        0 => Value::Literal(Literal::decode(r)?),
+        1 => Value::Dimension(Box::decode(r)?),
+        2 => Value::Intension(Box::decode(r)?),
+        3 => Value::BaseAbstraction(Box::decode(r)?),
+        4 => Value::ValueAbstraction(Box::decode(r)?),
+        5 => Value::NameAbstraction(Box::decode(r)?),
+        6 => Value::Identifier(String::decode(r)?),
+        7 => Value::Context(Context::decode(r)?),
+        8 => Value::Domain(Domain::decode(r)?),
+        9 => Value::PrimOp(String::decode(r)?),
+        tag => return Err(bad_tag("value", tag)),
+    })
+}
+
+impl Encode for DomainAnnotation {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(match *self {
+            DomainAnnotation::Explicit => 0,
+            DomainAnnotation::Inferred => 1,
+            DomainAnnotation::Generated => 2,
+        })
+    }
+}
+
+impl Decode for DomainAnnotation {
+    fn decode(r: &mut Decoder) -> Result<DomainAnnotation, DecodeError> {
+        match r.u8()? {
+            0 => Ok(DomainAnnotation::Explicit),
+            1 => Ok(DomainAnnotation::Inferred),
+            2 => Ok(DomainAnnotation::Generated),
+            tag => Err(bad_tag("annotation", tag)),
+        }
+    }
+}
+
+impl Encode for Definition {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.id.encode(out);
+        self.equation.encode(out);
+    }
+}
+
+impl Decode for Definition {
+    fn decode(r: &mut Decoder) -> Result<Definition, DecodeError> {
+        let id = String::decode(r)?;
+        let equation = Expression::decode(r)?;
+        Ok(Definition {
+            id,
+            equation,
+        })
+    }
+}
+
+impl Encode for Environment {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut definitions: BTreeMap<&Identifier, &Definition> = BTreeMap::new();
+        for definition in &self.0 {
+            definitions.entry(&definition.id).or_insert(definition);
+        }
+        encode_u32(definitions.len() as u32, out);
+        for (_, definition) in definitions {
+            definition.encode(out);
+        }
+    }
+}
+
+impl Decode for Environment {
+    fn decode(r: &mut Decoder) -> Result<Environment, DecodeError> {
+        Ok(Environment(Vec::decode(r)?))
+    }
+}
+
+impl Encode for Expression {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Expression::Literal(ref lit) => {
+                out.push(0);
+                lit.encode(out);
+            }
+
+            Expression::Dimension(ref di) => {
+                out.push(1);
+                di.encode(out);
+            }
+
+            Expression::Identifier(ref id) => {
+                out.push(2);
+                id.encode(out);
+            }
+
+            Expression::Operator(ref id) => {
+                out.push(3);
+                id.encode(out);
+            }
+
+            Expression::Sequence(ref xs) => {
+                out.push(4);
+                xs.encode(out);
+            }
+
+            Expression::TupleBuilder(ref tuples) => {
+                out.push(5);
+                encode_u32(tuples.len() as u32, out);
+                for tuple in tuples {
+                    tuple.lhs.encode(out);
+                    tuple.rhs.encode(out);
+                }
+            }
+
+            Expression::BaseAbstraction(ref abstraction) => {
+                out.push(6);
+                abstraction.encode(out);
+            }
+
+            Expression::BaseApplication(ref application) => {
+                out.push(7);
+                application.encode(out);
+            }
+
+            Expression::ValueAbstraction(ref abstraction) => {
+                out.push(8);
+                abstraction.encode(out);
+            }
+
+            Expression::ValueApplication(ref application) => {
+                out.push(9);
+                application.encode(out);
+            }
+
+            Expression::FunctionApplication(ref application) => {
+                out.push(10);
+                application.id.encode(out);
+                application.base_args.encode(out);
+                application.value_args.encode(out);
+            }
+
+            Expression::IntensionBuilder(ref intens) => {
+                out.push(11);
+                intens.annotation.encode(out);
+                intens.domain.encode(out);
+                intens.value.encode(out);
+            }
+
+            Expression::IntensionApplication(ref x) => {
+                out.push(12);
+                x.encode(out);
+            }
+
+            Expression::Application(ref xs) => {
+                out.push(13);
+                xs.encode(out);
+            }
+
+            Expression::If(ref if_expr) => {
+                out.push(14);
+                if_expr.condition.encode(out);
+                if_expr.consequent.encode(out);
+                if_expr.alternate.encode(out);
+            }
+
+            Expression::WhereVar(ref wv) => {
+                out.push(15);
+                wv.lhs.encode(out);
+                wv.rhs.encode(out);
+            }
+
+            Expression::Query(ref x) => {
+                out.push(16);
+                x.encode(out);
+            }
+
+            Expression::ContextQuery => out.push(17),
+
+            Expression::Perturb(ref perturb) => {
+                out.push(18);
+                perturb.lhs.encode(out);
+                perturb.rhs.encode(out);
+            }
+
+            Expression::WhereDim(ref wd) => {
+                out.push(19);
+                encode_u32(wd.nat_q, out);
+                wd.dim_q.encode(out);
+                wd.lhs.encode(out);
+                encode_u32(wd.rhs.0.len() as u32, out);
+                for dimension_expr in &wd.rhs.0 {
+                    dimension_expr.lhs.encode(out);
+                    dimension_expr.rhs.encode(out);
+                }
+            }
+
+            Expression::Constant(ref v) => {
+                out.push(20);
+                v.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Expression {
+    fn decode(r: &mut Decoder) -> Result<Expression, DecodeError> {
+        r.nested(decode_expression)
+    }
+}
+
+fn decode_expression(r: &mut Decoder) -> Result<Expression, DecodeError> {
+    Ok(match r.u8()? {
+        0 => Expression::Literal(Literal::decode(r)?),
+        1 => Expression::Dimension(Dimension::decode(r)?),
+        2 => Expression::Identifier(String::decode(r)?),
+        3 => Expression::Operator(String::decode(r)?),
+        4 => Expression::Sequence(Vec::decode(r)?),
+        5 => {
+            let n = r.u32()?;
+            let mut tuples = vec![];
+            for _ in 0..n {
+                let lhs = Expression::decode(r)?;
+                let rhs = Expression::decode(r)?;
+                tuples.push(TupleExpression { lhs, rhs });
+            }
+            Expression::TupleBuilder(tuples)
+        }
+        6 => Expression::BaseAbstraction(Box::decode(r)?),
+        7 => Expression::BaseApplication(Box::decode(r)?),
+        8 => Expression::ValueAbstraction(Box::decode(r)?),
+        9 => Expression::ValueApplication(Box::decode(r)?),
+        10 => {
+            let id = String::decode(r)?;
+            let base_args = Vec::decode(r)?;
+            let value_args = Vec::decode(r)?;
+            Expression::FunctionApplication(Box::new(FunctionApplication {
+                id,
+                base_args,
+                value_args,
+            }))
+        }
+        11 => {
+            let annotation = DomainAnnotation::decode(r)?;
+            let domain = Vec::decode(r)?;
+            let value = Expression::decode(r)?;
+            Expression::IntensionBuilder(Box::new(IntensionExpression {
+                annotation,
+                domain,
+                value,
+            }))
+        }
+        12 => Expression::IntensionApplication(Box::decode(r)?),
+        13 => Expression::Application(Vec::decode(r)?),
+        14 => {
+            let condition = Expression::decode(r)?;
+            let consequent = Expression::decode(r)?;
+            let alternate = Expression::decode(r)?;
+            Expression::If(Box::new(IfExpression {
+                condition,
+                consequent,
+                alternate,
+            }))
+        }
+        15 => {
+            let lhs = Expression::decode(r)?;
+            let rhs = Environment::decode(r)?;
+            Expression::WhereVar(Box::new(WhereVarExpression { lhs, rhs }))
+        }
+        16 => Expression::Query(Box::decode(r)?),
+        17 => Expression::ContextQuery,
+        18 => {
+            let lhs = Expression::decode(r)?;
+            let rhs = Expression::decode(r)?;
+            Expression::Perturb(Box::new(PerturbExpression { lhs, rhs }))
+        }
+        19 => {
+            let nat_q = r.u32()?;
+            let dim_q = Dimension::decode(r)?;
+            let lhs = Expression::decode(r)?;
+            let n = r.u32()?;
+            let mut rhs = vec![];
+            for _ in 0..n {
+                let di = Dimension::decode(r)?;
+                let x = Expression::decode(r)?;
+                rhs.push(DimensionExpression { lhs: di, rhs: x });
+            }
+            Expression::WhereDim(Box::new(WhereDimExpression {
+                nat_q,
+                dim_q,
+                lhs,
+                rhs: ContextExpression(rhs),
+            }))
+        }
+        20 => Expression::Constant(Box::decode(r)?),
+        tag => return Err(bad_tag("expression", tag)),
+    })
+}
+
+impl Encode for Digest {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0);
+    }
+}
+
+impl Decode for Digest {
+    fn decode(r: &mut Decoder) -> Result<Digest, DecodeError> {
+        let mut digest = [0; 32];
+        digest.copy_from_slice(r.take(32)?);
+        Ok(Digest(digest))
+    }
+}
+
+impl Encode for Key {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.x.encode(out);
+        self.k.encode(out);
+        self.digest.encode(out);
+    }
+}
+
+impl Decode for Key {
+    fn decode(r: &mut Decoder) -> Result<Key, DecodeError> {
+        let x = String::decode(r)?;
+        let k = Context::decode(r)?;
+        let digest = Digest::decode(r)?;
+        Ok(Key {
+            x,
+            k,
+            digest,
+        })
+    }
+}
+
+impl Encode for Either<Value, Domain> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Either::Left(ref v) => {
+                out.push(0);
+                v.encode(out);
+            }
+
+            Either::Right(ref d) => {
+                out.push(1);
+                d.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Either<Value, Domain> {
+    fn decode(r: &mut Decoder) -> Result<Either<Value, Domain>, DecodeError> {
+        match r.u8()? {
+            0 => Ok(Either::Left(Value::decode(r)?)),
+            1 => Ok(Either::Right(Domain::decode(r)?)),
+            tag => Err(bad_tag("result", tag)),
+        }
+    }
+}
@@ -1,5 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use digest::{sha256, Digest};
+use encoding::Encode;
 use expression::*;
+use inference::map_children;
 
 type Identifier = String;
 
@@ -46,12 +49,70 @@ impl Environment {
     }
 
     pub fn lookup(&self, id: Identifier) -> Expression {
-        for x in self.0.clone() {
-            if x.id == id {
-                return x.equation.clone();
+        match self.get(&id) {
+            Some(x) => x.clone(),
+            None => panic!("Undefined identifier {}", id),
+        }
+    }
+
+    pub fn get(&self, id: &Identifier) -> Option<&Expression> {
+        self.0.iter().find(|x| &x.id == id).map(|x| &x.equation)
+    }
+
+    // The digest of the definition of an identifier together with the
+    // definitions of every identifier it refers to, transitively. The second
+    // result tells whether all of those were defined; an identifier defined
+    // later by a where clause would change the digest.
+    pub fn digest(&self, id: &Identifier) -> (Digest, bool) {
+        let closure = self.reachable(vec![id.clone()]);
+        let complete = closure.values().all(|equation| equation.is_some());
+        let mut bytes = vec![];
+        id.encode(&mut bytes);
+        for (y, equation) in closure {
+            y.encode(&mut bytes);
+            match equation {
+                Some(x) => {
+                    bytes.push(1);
+                    x.encode(&mut bytes);
+                }
+                None => bytes.push(0),
+            }
+        }
+        (sha256(&bytes), complete)
+    }
+
+    // The definitions an expression refers to, transitively, and the
+    // identifiers among them which are not defined.
+    pub fn closure(&self, x: &Expression) -> (Environment, Vec<Identifier>) {
+        let mut todo = vec![];
+        identifiers(x, &mut todo);
+        let mut closure = Environment::new();
+        let mut undefined = vec![];
+        for (y, equation) in self.reachable(todo) {
+            match equation {
+                Some(x) => closure.define(y, x.clone()),
+                None => undefined.push(y),
+            }
+        }
+        (closure, undefined)
+    }
+
+    // The equations of the identifiers and of every identifier they refer to,
+    // None for the ones which are not defined.
+    fn reachable(&self, todo: Vec<Identifier>) -> BTreeMap<Identifier, Option<&Expression>> {
+        let mut closure = BTreeMap::new();
+        let mut todo = todo;
+        while let Some(y) = todo.pop() {
+            if closure.contains_key(&y) {
+                continue;
+            }
+            let equation = self.get(&y);
+            if let Some(x) = equation {
+                identifiers(x, &mut todo);
             }
+            closure.insert(y, equation);
         }
-        panic!("Undefined identifier {}", id)
+        closure
     }
 
     pub fn define(&mut self, id: Identifier, x: Expression) {
@@ -65,3 +126,24 @@ impl Environment {
         self.0.extend(other.0)
     }
 }
+
+// The identifiers an expression refers to.
+fn identifiers(x: &Expression, out: &mut Vec<Identifier>) {
+    match x {
+        Expression::Identifier(id) => out.push(id.clone()),
+
+        Expression::FunctionApplication(application) => {
+            out.push(application.id.clone());
+            for arg in application.base_args.iter().chain(&application.value_args) {
+                identifiers(arg, out);
+            }
+        }
+
+        other => {
+            map_children(other.clone(), |child| {
+                identifiers(&child, out);
+                child
+            });
+        }
+    }
+}
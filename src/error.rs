@@ -0,0 +1,215 @@
+use colored::*;
+
+use std::io;
+use cache::Key;
+use ed25519::PublicKey;
+use encoding::DecodeError;
+use ledger::Head;
+use limits::{EvalStats, Limit};
+
+// An evaluation which cannot produce a value, as opposed to one which is only
+// missing some dimensions.
+#[derive(Clone, Debug)]
+pub enum EvalError {
+    // The demand for an identifier at a context depends on itself. The chain
+    // starts and ends with the repeated demand.
+    CyclicDemand(Vec<Key>),
+    // The evaluation went over one of its limits, with what it had used by
+    // then.
+    LimitExceeded(Limit, EvalStats),
+    // The evaluation was cancelled through its token.
+    Cancelled(EvalStats),
+    // An expression which cannot be evaluated as it was written, e.g. a
+    // condition which is not a boolean.
+    Invalid(String),
+}
+
+impl EvalError {
+    pub fn print(&self) -> String {
+        match self {
+            EvalError::CyclicDemand(chain) => {
+                let demands: Vec<String> = chain
+                    .iter()
+                    .map(|key| format!("{} @ {}", key.x, key.k.print()))
+                    .collect();
+                format!("{}\n  {}", "cyclic demand".bright_red(), demands.join("\n  -> "))
+            }
+
+            EvalError::LimitExceeded(limit, stats) => format!(
+                "{} after {}",
+                format!("{} limit exceeded", limit.print()).bright_red(),
+                stats.print()
+            ),
+
+            EvalError::Cancelled(stats) => {
+                format!("{} after {}", "cancelled".bright_red(), stats.print())
+            }
+
+            EvalError::Invalid(message) => {
+                format!("{} {}", "invalid expression:".bright_red(), message)
+            }
+        }
+    }
+}
+
+// Why an entry received from elsewhere was refused.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignatureError {
+    // The entry is not signed and the keystore requires signatures.
+    Missing,
+    // The signature does not hold for the entry.
+    Invalid(PublicKey),
+    // The signature holds but the keystore does not trust the signer.
+    Untrusted(PublicKey),
+}
+
+impl SignatureError {
+    pub fn print(&self) -> String {
+        match self {
+            &SignatureError::Missing => "is not signed".to_string(),
+            SignatureError::Invalid(signer) => {
+                format!("has an invalid signature by {}", signer.hex())
+            }
+            SignatureError::Untrusted(signer) => {
+                format!("is signed by {}, which is not trusted", signer.hex())
+            }
+        }
+    }
+}
+
+// A saved cache which cannot be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    // The file is not a saved cache or it is damaged.
+    Format(DecodeError),
+    // The file was saved in another version of the format.
+    Version(u32),
+    // The program was changed since the cache was saved, its entries may
+    // no longer hold.
+    ProgramChanged,
+    // An entry of the identifier failed its signature check.
+    Signature(String, SignatureError),
+}
+
+impl LoadError {
+    pub fn print(&self) -> String {
+        let message = match self {
+            LoadError::Io(err) => format!("{}", err),
+            LoadError::Format(err) => err.0.clone(),
+            &LoadError::Version(version) => format!("unsupported version {}", version),
+            &LoadError::ProgramChanged => "the program changed since it was saved".to_string(),
+            LoadError::Signature(x, err) => format!("an entry of {} {}", x, err.print()),
+        };
+        format!("{} {}", "cannot load cache:".bright_red(), message)
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> LoadError {
+        LoadError::Io(err)
+    }
+}
+
+impl From<DecodeError> for LoadError {
+    fn from(err: DecodeError) -> LoadError {
+        LoadError::Format(err)
+    }
+}
+
+// A ledger which cannot be read or extended. Records are numbered from 0.
+#[derive(Debug)]
+pub enum LedgerError {
+    Io(io::Error),
+    // The file is not a ledger or a record cannot be decoded.
+    Format(DecodeError),
+    // The ledger ends within the record.
+    Truncated(u64),
+    // The record does not match the hash written after it.
+    Tampered(u64),
+    // The record does not follow the one before it.
+    BrokenChain(u64),
+    // The ledger ends at the second head instead of the expected first one.
+    UnexpectedHead(Head, Head),
+    // An entry of the identifier failed its signature check.
+    Signature(String, SignatureError),
+}
+
+impl LedgerError {
+    pub fn print(&self) -> String {
+        let message = match self {
+            LedgerError::Io(err) => format!("{}", err),
+            LedgerError::Format(err) => err.0.clone(),
+            &LedgerError::Truncated(n) => format!("it ends within record {}", n),
+            &LedgerError::Tampered(n) => format!("record {} was tampered with", n),
+            &LedgerError::BrokenChain(n) => format!("record {} breaks the chain", n),
+            &LedgerError::UnexpectedHead(expected, found) => format!(
+                "it ends after {} records at {}, not after {} at {}",
+                found.len,
+                found.hash.hex(),
+                expected.len,
+                expected.hash.hex()
+            ),
+            LedgerError::Signature(x, err) => format!("an entry of {} {}", x, err.print()),
+        };
+        format!("{} {}", "bad ledger:".bright_red(), message)
+    }
+}
+
+impl From<io::Error> for LedgerError {
+    fn from(err: io::Error) -> LedgerError {
+        LedgerError::Io(err)
+    }
+}
+
+impl From<DecodeError> for LedgerError {
+    fn from(err: DecodeError) -> LedgerError {
+        LedgerError::Format(err)
+    }
+}
+
+// An intension which cannot be shipped from one runtime to another.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShipError {
+    // The intension refers to an identifier which the runtime does not
+    // define at the top of the program.
+    Undefined(String),
+    // The receiving runtime defines the identifier with another equation.
+    Conflict(String),
+}
+
+impl ShipError {
+    pub fn print(&self) -> String {
+        let message = match self {
+            ShipError::Undefined(x) => format!("{} is not defined", x),
+            ShipError::Conflict(x) => format!("{} is defined differently here", x),
+        };
+        format!("{} {}", "cannot ship intension:".bright_red(), message)
+    }
+}
+
+// A demand which a pool of workers did not answer with a value.
+#[derive(Debug)]
+pub enum WorkerError {
+    // The worker exited or its pipes failed.
+    Io(io::Error),
+    // The worker sent something which is not a response to the demand.
+    Protocol(String),
+    // The worker could not evaluate the demand, e.g. it does not know the
+    // program.
+    Remote(String),
+    // Every worker of the pool failed before the demand was sent.
+    Unavailable,
+}
+
+impl WorkerError {
+    pub fn print(&self) -> String {
+        let message = match self {
+            WorkerError::Io(err) => format!("{}", err),
+            WorkerError::Protocol(err) => format!("bad response: {}", err),
+            WorkerError::Remote(err) => err.clone(),
+            &WorkerError::Unavailable => "no worker is left".to_string(),
+        };
+        format!("{} {}", "worker failed:".bright_red(), message)
+    }
+}
@@ -1,10 +1,15 @@
 use std::collections::HashSet;
 use environment::Environment;
-use expression::{Expression, Literal};
+use expression::*;
 use context::Context;
 use domain::Domain;
-use cache::Cache;
+use cache::{Cache, Key};
+use digest::Digest;
 use either::Either;
+use error::EvalError;
+use provider::ContextProvider;
+use limits::Meter;
+use residual::closed;
 use tuple::Tuple;
 use value::*;
 
@@ -18,97 +23,227 @@ pub fn generate_dimension(i: u32, q: u32, d: u32) -> (Dimension, Value) {
     (di.clone(), Value::Dimension(Box::new(di)))
 }
 
-pub fn evaluate_id1(
-    x: Identifier,
-    e: &mut Environment,
+// Apply a primitive operator to a list of fully evaluated arguments.
+pub fn apply_primitive(op: Identifier, params: Vec<Value>) -> Either<Value, Domain> {
+    match op.as_ref() {
+        "*" => {
+            let a = params[0].expect_integer();
+            let b = params[1].expect_integer();
+            Either::Left(Value::Literal(Literal::Int32(a * b)))
+        }
+
+        "+" => {
+            let paramsi: Vec<u32> = params.iter().map(|p| p.expect_integer()).collect();
+            Either::Left(Value::Literal(Literal::Int32(
+                paramsi.iter().sum(),
+            )))
+        }
+
+        "-" => {
+            let a = params[0].expect_integer();
+            let b = params[1].expect_integer();
+            Either::Left(Value::Literal(Literal::Int32(a - b)))
+        }
+
+        "<=" => {
+            let a = params[0].expect_integer();
+            let b = params[1].expect_integer();
+            Either::Left(Value::Literal(Literal::Bool(a <= b)))
+        }
+
+        ">" => {
+            let a = params[0].expect_integer();
+            let b = params[1].expect_integer();
+            Either::Left(Value::Literal(Literal::Bool(a > b)))
+        }
+
+        // Union of two contexts, the ordinates of the rhs take precedence.
+        "++" => {
+            let mut a = params[0].expect_context();
+            let b = params[1].expect_context();
+            Either::Left(Value::Context(a.perturb(b)))
+        }
+
+        // Restriction of a context to a domain.
+        "<|" => {
+            let mut a = params[0].expect_context();
+            let b = params[1].expect_domain();
+            Either::Left(Value::Context(a.restrict(b)))
+        }
+
+        // The domain of a context.
+        "dom" => {
+            let a = params[0].expect_context();
+            Either::Left(Value::Domain(a.domain()))
+        }
+
+        _ => panic!("Unrecognised primitive."),
+    }
+}
+
+// Lookup the ordinate of a dimension in the context. A dimension outside of d
+// but inside of init_d is only missing from the domain of an identifier being
+// demanded, one outside of init_d is missing from the evaluation as a whole and
+// the context provider gets a chance to supply it.
+pub fn lookup_dimension(
+    di: Dimension,
     k: Context,
     init_d: Domain,
-    dd: Domain,
     d: Domain,
-    c: &mut Cache,
+    p: &mut dyn ContextProvider,
 ) -> Either<Value, Domain> {
-    let v0 = evaluate_id2(x.clone(), e, k.clone(), init_d.clone(), dd.clone(), c);
-    match v0.clone() {
-        Either::Left(_) => v0,
-
-        Either::Right(mut d0) => {
-            if d0.clone().is_subset(d.clone()) {
-                // println!("dd => {} is a subset of d => {}",
-                //          dd.clone().print(),
-                //          d.clone().print());
-                if d0.is_subset(k.clone().domain()) {
-                    // println!("d0 => {} is a subset of kd => {}",
-                    //          d0.clone().print(),
-                    //          k.clone().domain().print());
-                    evaluate_id1(x, e, k, init_d, dd.clone().union(d0), d, c)
-                } else {
-                    println!(
-                        "i: {} is not a subset of {}",
-                        d0.clone().print(),
-                        k.clone().domain().print()
-                    );
-                    v0
-                }
-            } else {
-                println!(
-                    "j: {} is not a subset of {}",
-                    d0.clone().print(),
-                    d.clone().print()
-                );
-                Either::Right(d0.difference(d))
-            }
+    if d.contains(di.clone()) {
+        let v = k.lookup(di).expect("Expected dimension in context.");
+        return Either::Left(v);
+    }
+    if !init_d.contains(di.clone()) {
+        if let Some(v) = p.provide(&di, &k) {
+            return Either::Left(v);
         }
     }
+    let mut h = HashSet::new();
+    h.insert(di);
+    Either::Right(Domain(h))
 }
 
-pub fn evaluate_id2(
-    x: Identifier,
-    e: &mut Environment,
+// The evaluator is an eduction engine in the style of the Lucid warehouse:
+// demands for the values of expressions at contexts are pushed onto an
+// explicit stack of frames instead of the native stack, and the values of
+// identifiers are stored in the warehouse (the cache) as they are computed.
+// The depth of a stream is therefore only bounded by memory. In residual mode
+// the engine also rebuilds what could not be evaluated for lack of some
+// dimensions into a residual expression, with every value computed so far
+// substituted in place.
+
+// The point at which an expression is evaluated. Only the dimensions in d may
+// be queried, init_d holds every dimension available to the evaluation, which
+// is more than d while the domain of an identifier is being discovered.
+#[derive(Clone, Debug)]
+struct At {
     k: Context,
     init_d: Domain,
     d: Domain,
-    c: &mut Cache,
-) -> Either<Value, Domain> {
-    let v0 = c.find(x.clone(), k.clone().restrict(d.clone()))
-        .map(|v| v.clone());
-    match v0 {
-        Some(v) => v.clone(),
+}
 
-        None => {
-            let v1 = evaluate(
-                e.lookup(x.clone()).clone(),
-                e,
-                k.clone(),
-                init_d,
-                d.clone(),
-                c,
-            );
-            match v1.clone() {
-                Either::Left(v) => {
-                    // println!(
-                    //     "{} {} <| {} = {}",
-                    //     x,
-                    //     k.clone().restrict(d.clone()).print(),
-                    //     d.clone().print(),
-                    //     print_value(v)
-                    // );
-                }
-                Either::Right(d1) => {
-                    // println!(
-                    //     "{} {} <| {} = {}",
-                    //     x,
-                    //     k.clone().restrict(d.clone()).print(),
-                    //     d.clone().print(),
-                    //     d1.clone().print()
-                    // );
-                }
-            }
-            c.add(x, k.clone().restrict(d), v1.clone());
-            v1
+impl At {
+    // The same point with the context perturbed and the domain extended.
+    fn perturb(&self, k: Context, domain: Domain) -> At {
+        At {
+            k: self.k.clone().perturb(k),
+            init_d: self.init_d.clone().union(domain.clone()),
+            d: self.d.clone().union(domain),
         }
     }
 }
 
+enum Control {
+    // Evaluate an expression.
+    Eval(Expression, At),
+    // Evaluate an expression in residual mode.
+    Residualize(Expression, At),
+    // Hand a result to the frame on top of the stack.
+    Return(Either<Value, Domain>),
+    // Hand a residual expression and the dimensions it is missing to the
+    // frame on top of the stack. Frames which do not rebuild residuals only
+    // see the missing dimensions.
+    Residual(Expression, Domain),
+}
+
+// The end of an evaluation, a residual expression is only kept in residual
+// mode.
+enum Outcome {
+    Return(Either<Value, Domain>),
+    Residual(Expression, Domain),
+}
+
+// How the results of the subexpressions of an expression are combined.
+enum Combine {
+    Sequence,
+    TupleBuilder,
+    Application,
+    BaseApplication,
+    ValueApplication,
+    // The number of base arguments, the value arguments come after them.
+    FunctionApplication(usize),
+    IntensionBuilder(Expression),
+    WhereDim(Box<WhereDimExpression>),
+}
+
+// How the results of the subexpressions of an expression are rebuilt into its
+// residual in residual mode, or combined when none of them is missing a
+// dimension.
+enum Rebuild {
+    Sequence,
+    Application,
+    // The dimension expressions, which are kept as they are in a residual.
+    TupleBuilder(Vec<Expression>),
+    If {
+        consequent: Expression,
+        alternate: Expression,
+    },
+    WhereVar(Environment),
+    // The lhs of the perturbation.
+    Perturb(Expression),
+    IntensionApplication,
+    WhereDim(Box<WhereDimExpression>),
+    // The residual of an expression evaluated at another point than the one
+    // around it is closed over that point.
+    Close,
+}
+
+// What remains to be done with the result of the expression being evaluated.
+enum Frame {
+    // Evaluate the remaining subexpressions (in reverse order) and combine
+    // their results with the ones so far.
+    Collect {
+        rest: Vec<Expression>,
+        results: Vec<Either<Value, Domain>>,
+        then: Combine,
+        at: At,
+    },
+    If {
+        consequent: Expression,
+        alternate: Expression,
+        at: At,
+    },
+    Query(At),
+    Perturb(Expression, At),
+    IntensionApplication(At),
+    // The body of a base abstraction evaluates to a value abstraction which
+    // is applied to the value arguments.
+    BaseBody(Vec<Value>, At),
+    // The demand for an identifier at the trial domain dd, with the gas
+    // charged before it missed the cache.
+    Demand {
+        x: Identifier,
+        definition: Digest,
+        dd: Domain,
+        at: At,
+        gas: u64,
+    },
+    // Residualize the remaining subexpressions (in reverse order) and
+    // rebuild the residual of the expression. The subexpression being
+    // evaluated is its own residual when it returns missing dimensions.
+    Rebuild {
+        rest: Vec<Expression>,
+        current: Expression,
+        results: Vec<Either<Value, (Expression, Domain)>>,
+        then: Rebuild,
+        at: At,
+    },
+}
+
+// The outcome of the demand for an identifier at a trial domain.
+enum Settle {
+    Done(Either<Value, Domain>),
+    // Demand the identifier again at a larger trial domain.
+    Retry(Domain),
+}
+
+// Evaluate x in the context k. Only the dimensions in d may be queried, init_d
+// holds every dimension available to the evaluation, which is more than d
+// while the domain of an identifier is being discovered.
+#[allow(clippy::too_many_arguments)]
 pub fn evaluate(
     x: Expression,
     e: &mut Environment,
@@ -116,624 +251,1070 @@ pub fn evaluate(
     init_d: Domain,
     d: Domain,
     c: &mut Cache,
-) -> Either<Value, Domain> {
-    match x {
-        Expression::Literal(lit) => Either::Left(Value::Literal(lit)),
+    p: &mut dyn ContextProvider,
+    m: &mut Meter,
+) -> Result<Either<Value, Domain>, EvalError> {
+    let at = At {
+        k,
+        init_d,
+        d,
+    };
+    Ok(match run(Control::Eval(x, at), e, c, p, m)? {
+        Outcome::Return(v) => v,
 
-        // Constant dimensional query
-        Expression::Dimension(di) => {
-            if d.contains(di.clone()) {
-                let v = k.lookup(di).expect("Expected dimension in context.");
-                Either::Left(v)
-            } else {
-                let mut h = HashSet::new();
-                h.insert(di);
-                Either::Right(Domain(h))
-            }
+        Outcome::Residual(_, missing) => Either::Right(missing),
+    })
+}
+
+// Evaluate x in the context k as evaluate does, returning a residual
+// expression together with the missing dimensions when x cannot be evaluated.
+// The residual expression is to be evaluated at (k, d) extended with the
+// missing dimensions.
+#[allow(clippy::too_many_arguments)]
+pub fn residualize(
+    x: Expression,
+    e: &mut Environment,
+    k: Context,
+    init_d: Domain,
+    d: Domain,
+    c: &mut Cache,
+    p: &mut dyn ContextProvider,
+    m: &mut Meter,
+) -> Result<Either<Value, (Expression, Domain)>, EvalError> {
+    let at = At {
+        k,
+        init_d,
+        d,
+    };
+    Ok(match run(Control::Residualize(x.clone(), at), e, c, p, m)? {
+        Outcome::Return(Either::Left(v)) => Either::Left(v),
+
+        Outcome::Return(Either::Right(missing)) => Either::Right((x, missing)),
+
+        Outcome::Residual(r, missing) => Either::Right((r, missing)),
+    })
+}
+
+fn run(
+    control: Control,
+    e: &mut Environment,
+    c: &mut Cache,
+    p: &mut dyn ContextProvider,
+    m: &mut Meter,
+) -> Result<Outcome, EvalError> {
+    let pending = c.pending.len();
+    // The definitions may have changed since the last evaluation.
+    if pending == 0 {
+        c.definitions.clear();
+    }
+    let mut stack: Vec<Frame> = vec![];
+    let v = educe(control, &mut stack, e, c, p, m);
+    // The demands which were in progress when evaluation stopped will never
+    // complete, none of them made it to the cache.
+    if v.is_err() {
+        while c.pending.len() > pending {
+            c.end();
         }
+    }
+    v
+}
 
-        Expression::Operator(id) => Either::Left(Value::Identifier(id)),
+fn educe(
+    control: Control,
+    stack: &mut Vec<Frame>,
+    e: &mut Environment,
+    c: &mut Cache,
+    p: &mut dyn ContextProvider,
+    m: &mut Meter,
+) -> Result<Outcome, EvalError> {
+    let mut control = control;
+    loop {
+        control = match control {
+            Control::Eval(x, at) => {
+                m.step(&x)?;
+                eval(x, at, stack, e, c, p, m)?
+            }
 
-        Expression::Sequence(expr_vec) => {
-            let mut r = Either::Right(Domain::new());
-            for expr in expr_vec {
-                r = evaluate(expr, e, k.clone(), init_d.clone(), d.clone(), c);
+            Control::Residualize(x, at) => {
+                m.step(&x)?;
+                residualize_step(x, at, stack, e, c, p, m)?
             }
-            r
+
+            Control::Return(v) => match stack.pop() {
+                Some(frame) => resume(frame, v, stack, e, c, p, m)?,
+
+                None => return Ok(Outcome::Return(v)),
+            },
+
+            Control::Residual(r, missing) => match stack.pop() {
+                Some(Frame::Rebuild {
+                    rest,
+                    mut results,
+                    then,
+                    at,
+                    ..
+                }) => {
+                    results.push(Either::Right((r, missing)));
+                    rebuild_next(rest, results, then, at, stack, e, m)?
+                }
+
+                Some(frame) => resume(frame, Either::Right(missing), stack, e, c, p, m)?,
+
+                None => return Ok(Outcome::Residual(r, missing)),
+            },
+        }
+    }
+}
+
+// Take one step in the evaluation of an expression, either to a result or to
+// the evaluation of a subexpression with a frame for what to do with it.
+fn eval(
+    x: Expression,
+    at: At,
+    stack: &mut Vec<Frame>,
+    e: &mut Environment,
+    c: &mut Cache,
+    p: &mut dyn ContextProvider,
+    m: &mut Meter,
+) -> Result<Control, EvalError> {
+    Ok(match x {
+        Expression::Literal(lit) => Control::Return(Either::Left(Value::Literal(lit))),
+
+        Expression::Constant(v) => Control::Return(Either::Left(*v)),
+
+        // Constant dimensional query
+        Expression::Dimension(di) => {
+            Control::Return(lookup_dimension(di, at.k, at.init_d, at.d, p))
         }
 
+        Expression::Operator(id) => Control::Return(Either::Left(Value::Identifier(id))),
+
+        Expression::Sequence(expr_vec) => collect(expr_vec, Combine::Sequence, at, stack)?,
+
         Expression::TupleBuilder(tuple_expr) => {
-            let mut result: Vec<Tuple> = vec![];
-            let mut missing: Domain = Domain::new();
+            let mut xs = vec![];
             for tuple in tuple_expr {
-                let lhs = evaluate(tuple.lhs, e, k.clone(), init_d.clone(), d.clone(), c);
-                let rhs = evaluate(tuple.rhs, e, k.clone(), init_d.clone(), d.clone(), c);
-                match lhs {
-                    Either::Left(l) => match rhs {
-                        Either::Left(rl) => {
-                            let dim = l.expect_dimension();
-                            result.push(Tuple::new(dim, rl))
-                        }
+                xs.push(tuple.lhs);
+                xs.push(tuple.rhs);
+            }
+            collect(xs, Combine::TupleBuilder, at, stack)?
+        }
+
+        Expression::Application(application_expr) => {
+            collect(application_expr, Combine::Application, at, stack)?
+        }
 
-                        Either::Right(rr) => missing = missing.union(rr),
-                    },
-                    Either::Right(r) => match rhs {
-                        Either::Left(_) => missing = missing.union(r),
-                        Either::Right(rr) => missing = missing.union(r).union(rr),
-                    },
+        Expression::If(if_expr) => {
+            let if_expr = *if_expr;
+            stack.push(Frame::If {
+                consequent: if_expr.consequent,
+                alternate: if_expr.alternate,
+                at: at.clone(),
+            });
+            Control::Eval(if_expr.condition, at)
+        }
+
+        Expression::WhereVar(wv) => {
+            e.merge(wv.rhs.clone());
+            Control::Eval(wv.lhs.clone(), at)
+        }
+
+        Expression::Query(e0) => {
+            stack.push(Frame::Query(at.clone()));
+            Control::Eval(*e0, at)
+        }
+
+        // The current context, restricted to the dimensions which are
+        // visible at this point of the evaluation. `#` reads every one of
+        // them, so while the domain of an identifier is being discovered it
+        // asks for the dimensions not in the trial domain yet.
+        Expression::ContextQuery => {
+            let mut missing = Domain::new();
+            for di in at.k.domain().to_vec() {
+                if at.init_d.contains(di.clone()) && !at.d.contains(di.clone()) {
+                    missing.push(di);
                 }
             }
-
-            if missing.len() > 0 {
-                Either::Right(missing)
+            if !missing.0.is_empty() {
+                Control::Return(Either::Right(missing))
             } else {
-                Either::Left(Value::Context(Context(result)))
+                Control::Return(Either::Left(Value::Context(at.k.clone().restrict(at.d))))
             }
         }
 
+        Expression::Perturb(perturb_expr) => {
+            let perturb_expr = *perturb_expr;
+            stack.push(Frame::Perturb(perturb_expr.lhs, at.clone()));
+            Control::Eval(perturb_expr.rhs, at)
+        }
+
+        Expression::BaseAbstraction(base_abstraction) => {
+            Control::Return(Either::Left(Value::BaseAbstraction(base_abstraction)))
+        }
+
+        Expression::BaseApplication(base_application) => {
+            let base_application = *base_application;
+            let mut xs = vec![base_application.lhs];
+            xs.extend(base_application.args);
+            collect(xs, Combine::BaseApplication, at, stack)?
+        }
+
+        Expression::ValueAbstraction(value_abstraction) => {
+            Control::Return(Either::Left(Value::ValueAbstraction(value_abstraction)))
+        }
+
+        Expression::ValueApplication(value_application) => {
+            let value_application = *value_application;
+            let mut xs = vec![value_application.lhs];
+            xs.extend(value_application.args);
+            collect(xs, Combine::ValueApplication, at, stack)?
+        }
+
+        Expression::FunctionApplication(function_application) => {
+            let function_application = *function_application;
+            // Evaluate the function in order to obtain a set of abstractions,
+            // then the base arguments and then the value arguments.
+            let n = function_application.base_args.len();
+            let mut xs = vec![e.lookup(function_application.id.clone()).clone()];
+            xs.extend(function_application.base_args);
+            xs.extend(function_application.value_args);
+            // TODO: Ignore the name arguments.
+            collect(xs, Combine::FunctionApplication(n), at, stack)?
+        }
+
+        Expression::IntensionBuilder(intens_expr) => {
+            let intens_expr = *intens_expr;
+            let then = Combine::IntensionBuilder(intens_expr.value);
+            collect(intens_expr.domain, then, at, stack)?
+        }
+
+        Expression::IntensionApplication(intens_app) => {
+            stack.push(Frame::IntensionApplication(at.clone()));
+            Control::Eval(*intens_app, at)
+        }
+
+        Expression::Identifier(id) => demand(id, Domain::new(), at, stack, e, c, m)?,
+
+        Expression::WhereDim(wd) => {
+            let xs = wd.rhs.0.iter().map(|dimension_expr| dimension_expr.rhs.clone()).collect();
+            collect(xs, Combine::WhereDim(wd), at, stack)?
+        }
+    })
+}
+
+// Evaluate the subexpressions xs in order and combine their results.
+fn collect(
+    xs: Vec<Expression>,
+    then: Combine,
+    at: At,
+    stack: &mut Vec<Frame>,
+) -> Result<Control, EvalError> {
+    let mut rest = xs;
+    rest.reverse();
+    match rest.pop() {
+        Some(x) => {
+            stack.push(Frame::Collect {
+                rest,
+                results: vec![],
+                then,
+                at: at.clone(),
+            });
+            Ok(Control::Eval(x, at))
+        }
+
+        None => combine(then, vec![], at, stack),
+    }
+}
+
+// Take one step in the evaluation of an expression in residual mode. The
+// expressions which hold others are taken apart so that the residuals of the
+// subexpressions can be rebuilt into theirs, the others are evaluated as a
+// whole, identifiers keep the values computed so far in the cache.
+#[allow(clippy::too_many_arguments)]
+fn residualize_step(
+    x: Expression,
+    at: At,
+    stack: &mut Vec<Frame>,
+    e: &mut Environment,
+    c: &mut Cache,
+    p: &mut dyn ContextProvider,
+    m: &mut Meter,
+) -> Result<Control, EvalError> {
+    Ok(match x {
+        Expression::Sequence(expr_vec) => rebuild_from(expr_vec, Rebuild::Sequence, at, stack, e, m)?,
+
         Expression::Application(application_expr) => {
-            let e0 = evaluate(
-                application_expr[0].clone(),
-                e,
-                k.clone(),
-                init_d.clone(),
-                d.clone(),
-                c,
-            );
-            let mut params: Vec<Value> = vec![];
-            let mut missing: Domain = Domain::new();
-            for i in 1..application_expr.len() {
-                let param = evaluate(
-                    application_expr[i].clone(),
-                    e,
-                    k.clone(),
-                    init_d.clone(),
-                    d.clone(),
-                    c,
-                );
-                match param {
-                    Either::Left(l) => params.push(l),
-                    Either::Right(r) => {
-                        missing = missing.union(r);
+            rebuild_from(application_expr, Rebuild::Application, at, stack, e, m)?
+        }
+
+        Expression::TupleBuilder(tuple_expr) => {
+            let mut xs = vec![];
+            let mut dimensions = vec![];
+            for tuple in tuple_expr {
+                xs.push(tuple.lhs.clone());
+                xs.push(tuple.rhs);
+                dimensions.push(tuple.lhs);
+            }
+            rebuild_from(xs, Rebuild::TupleBuilder(dimensions), at, stack, e, m)?
+        }
+
+        Expression::If(if_expr) => {
+            let if_expr = *if_expr;
+            let then = Rebuild::If {
+                consequent: if_expr.consequent,
+                alternate: if_expr.alternate,
+            };
+            rebuild_from(vec![if_expr.condition], then, at, stack, e, m)?
+        }
+
+        Expression::WhereVar(wv) => {
+            let wv = *wv;
+            e.merge(wv.rhs.clone());
+            rebuild_from(vec![wv.lhs], Rebuild::WhereVar(wv.rhs), at, stack, e, m)?
+        }
+
+        Expression::Perturb(perturb_expr) => {
+            let perturb_expr = *perturb_expr;
+            let then = Rebuild::Perturb(perturb_expr.lhs);
+            rebuild_from(vec![perturb_expr.rhs], then, at, stack, e, m)?
+        }
+
+        Expression::IntensionApplication(intens_app) => {
+            rebuild_from(vec![*intens_app], Rebuild::IntensionApplication, at, stack, e, m)?
+        }
+
+        Expression::WhereDim(wd) => {
+            let xs = wd.rhs.0.iter().map(|dimension_expr| dimension_expr.rhs.clone()).collect();
+            rebuild_from(xs, Rebuild::WhereDim(wd), at, stack, e, m)?
+        }
+
+        other => eval(other, at, stack, e, c, p, m)?,
+    })
+}
+
+// Residualize the subexpressions xs in order and rebuild the residual of the
+// expression from theirs.
+fn rebuild_from(
+    xs: Vec<Expression>,
+    then: Rebuild,
+    at: At,
+    stack: &mut Vec<Frame>,
+    e: &mut Environment,
+    m: &mut Meter,
+) -> Result<Control, EvalError> {
+    let mut rest = xs;
+    rest.reverse();
+    rebuild_next(rest, vec![], then, at, stack, e, m)
+}
+
+// Residualize the next of the remaining subexpressions, or rebuild the
+// residual once there are none left.
+fn rebuild_next(
+    mut rest: Vec<Expression>,
+    results: Vec<Either<Value, (Expression, Domain)>>,
+    then: Rebuild,
+    at: At,
+    stack: &mut Vec<Frame>,
+    e: &mut Environment,
+    m: &mut Meter,
+) -> Result<Control, EvalError> {
+    match rest.pop() {
+        Some(x) => {
+            stack.push(Frame::Rebuild {
+                rest,
+                current: x.clone(),
+                results,
+                then,
+                at: at.clone(),
+            });
+            Ok(Control::Residualize(x, at))
+        }
+
+        None => rebuild(then, results, at, stack, e, m),
+    }
+}
+
+// Turn the result of residualizing a subexpression back into an expression, a
+// value is substituted as a constant.
+fn reify(v: Either<Value, (Expression, Domain)>) -> Expression {
+    match v {
+        Either::Left(l) => Expression::Constant(Box::new(l)),
+        Either::Right((r, _)) => r,
+    }
+}
+
+// The dimensions the residuals among the results are missing, or None when
+// every result is a value.
+fn residual_missing(results: &[Either<Value, (Expression, Domain)>]) -> Option<Domain> {
+    let mut missing = None;
+    for v in results {
+        if let Either::Right((_, ref d)) = *v {
+            missing = Some(missing.unwrap_or_else(Domain::new).union(d.clone()));
+        }
+    }
+    missing
+}
+
+// The result of a subexpression as the result of the expression around it.
+fn pass(v: Either<Value, (Expression, Domain)>) -> Control {
+    match v {
+        Either::Left(l) => Control::Return(Either::Left(l)),
+
+        Either::Right((r, missing)) => Control::Residual(r, missing),
+    }
+}
+
+// Rebuild the residual of an expression from the results of its
+// subexpressions, or go on evaluating it when none of them is missing a
+// dimension.
+fn rebuild(
+    then: Rebuild,
+    results: Vec<Either<Value, (Expression, Domain)>>,
+    at: At,
+    stack: &mut Vec<Frame>,
+    e: &mut Environment,
+    m: &mut Meter,
+) -> Result<Control, EvalError> {
+    let missing = residual_missing(&results);
+    let mut results = results.into_iter();
+    let first = results.next();
+    Ok(match then {
+        Rebuild::Sequence => match results.last().or(first) {
+            Some(v) => pass(v),
+
+            None => Control::Return(Either::Right(Domain::new())),
+        },
+
+        Rebuild::Application => {
+            let mut xs = vec![];
+            xs.extend(first);
+            xs.extend(results);
+            if let Some(missing) = missing {
+                let r = xs.into_iter().map(reify).collect();
+                return Ok(Control::Residual(Expression::Application(r), missing));
+            }
+            let mut values: Vec<Value> = xs.into_iter().filter_map(Either::left).collect();
+            if values.is_empty() {
+                return Err(EvalError::Invalid("expected an operator".to_string()));
+            }
+            match values.remove(0) {
+                Value::Identifier(op) => {
+                    m.primitive(&op)?;
+                    match apply_primitive(op.clone(), values.clone()) {
+                        Either::Left(l) => Control::Return(Either::Left(l)),
+
+                        Either::Right(missing) => {
+                            let mut r = vec![Expression::Operator(op)];
+                            r.extend(values.into_iter().map(|v| Expression::Constant(Box::new(v))));
+                            Control::Residual(Expression::Application(r), missing)
+                        }
                     }
                 }
+
+                other => return Err(invalid("an operator", &other)),
             }
+        }
 
-            if missing.len() > 0 {
-                match e0 {
-                    Either::Left(_) => Either::Right(missing),
-                    Either::Right(r) => Either::Right(missing.union(r)),
+        Rebuild::TupleBuilder(dimensions) => {
+            let mut xs = vec![];
+            xs.extend(first);
+            xs.extend(results);
+            let mut pairs = vec![];
+            let mut xs = xs.into_iter();
+            while let (Some(lhs), Some(rhs)) = (xs.next(), xs.next()) {
+                pairs.push((lhs, rhs));
+            }
+            match missing {
+                // The lhs evaluates to a dimension which is looked up again
+                // when the residual is completed.
+                Some(missing) => {
+                    let tuples = dimensions
+                        .into_iter()
+                        .zip(pairs)
+                        .map(|(lhs, (_, rhs))| TupleExpression {
+                            lhs,
+                            rhs: reify(rhs),
+                        })
+                        .collect();
+                    Control::Residual(Expression::TupleBuilder(tuples), missing)
                 }
-            } else {
-                match e0 {
-                    Either::Left(l) => {
-                        match l {
-                            Value::Identifier(op) => {
-                                // lookup primitive operator & apply
-                                match op.as_ref() {
-                                    "*" => {
-                                        let a = params[0].expect_integer();
-                                        let b = params[1].expect_integer();
-                                        Either::Left(Value::Literal(Literal::Int32(a * b)))
-                                    }
-
-                                    "+" => {
-                                        let paramsi: Vec<
-                                            u32,
-                                        > = params.iter().map(|p| p.expect_integer()).collect();
-                                        Either::Left(Value::Literal(Literal::Int32(
-                                            paramsi.iter().fold(0, |sum, n| sum + n),
-                                        )))
-                                    }
-
-                                    "-" => {
-                                        let a = params[0].expect_integer();
-                                        let b = params[1].expect_integer();
-                                        Either::Left(Value::Literal(Literal::Int32(a - b)))
-                                    }
-
-                                    "<=" => {
-                                        let a = params[0].expect_integer();
-                                        let b = params[1].expect_integer();
-                                        Either::Left(Value::Literal(Literal::Bool(a <= b)))
-                                    }
-
-                                    ">" => {
-                                        let a = params[0].expect_integer();
-                                        let b = params[1].expect_integer();
-                                        Either::Left(Value::Literal(Literal::Bool(a > b)))
-                                    }
-
-                                    _ => panic!("Unrecognised primitive."),
-                                }
-                            }
-
-                            other => panic!("Expected operator but here found {:?}", other),
+
+                None => {
+                    let mut result = vec![];
+                    for pair in pairs {
+                        if let (Either::Left(l), Either::Left(r)) = pair {
+                            result.push(Tuple::new(l.expect_dimension(), r));
                         }
                     }
-                    Either::Right(r) => Either::Right(r),
+                    Control::Return(Either::Left(Value::Context(Context(result))))
                 }
             }
         }
 
-        Expression::If(if_expr) => {
-            let condition = if_expr.condition.clone();
-            let consequent = if_expr.consequent.clone();
-            let alternate = if_expr.alternate.clone();
-            let condition = evaluate(condition, e, k.clone(), init_d.clone(), d.clone(), c);
-            match condition {
-                Either::Left(l) => match l {
-                    Value::Literal(Literal::Bool(true)) => {
-                        evaluate(consequent, e, k.clone(), init_d, d.clone(), c)
-                    }
+        Rebuild::If {
+            consequent,
+            alternate,
+        } => match first {
+            Some(Either::Left(Value::Literal(Literal::Bool(true)))) => {
+                Control::Residualize(consequent, at)
+            }
 
-                    Value::Literal(Literal::Bool(false)) => {
-                        let x = evaluate(alternate, e, k.clone(), init_d, d.clone(), c);
-                        x
-                    }
+            Some(Either::Left(Value::Literal(Literal::Bool(false)))) => {
+                Control::Residualize(alternate, at)
+            }
+
+            Some(Either::Right((r, missing))) => {
+                let if_expr = IfExpression {
+                    condition: r,
+                    consequent,
+                    alternate,
+                };
+                Control::Residual(Expression::If(Box::new(if_expr)), missing)
+            }
 
-                    _ => panic!("Expected boolean expression"),
-                },
+            other => return Err(invalid_result("a boolean", other)),
+        },
 
-                Either::Right(r) => Either::Right(r),
+        Rebuild::WhereVar(rhs) => match first {
+            Some(Either::Right((r, missing))) => {
+                let wv = WhereVarExpression {
+                    lhs: r,
+                    rhs,
+                };
+                Control::Residual(Expression::WhereVar(Box::new(wv)), missing)
             }
-        }
 
-        Expression::WhereVar(wv) => {
-            e.merge(wv.rhs.clone());
-            evaluate(wv.lhs.clone(), e, k.clone(), init_d, d.clone(), c)
-        }
+            Some(v) => pass(v),
 
-        Expression::Query(e0) => {
-            let e0 = (*e0).clone();
-            let v0 = evaluate(e0, e, k.clone(), init_d, d.clone(), c);
-            match v0 {
-                Either::Left(l) => {
-                    let di = l.expect_dimension();
-                    if d.contains(di.clone()) {
-                        let v = k.lookup(di).expect("Expected dimension in context.");
-                        Either::Left(v)
-                    } else {
-                        let mut h = HashSet::new();
-                        h.insert(di);
-                        Either::Right(Domain(h))
-                    }
-                }
+            None => Control::Return(Either::Right(Domain::new())),
+        },
 
-                Either::Right(d0) => Either::Right(d0),
+        Rebuild::Perturb(lhs) => match first {
+            Some(Either::Left(Value::Context(v1))) => {
+                let domain = v1.domain();
+                rebuild_from(vec![lhs], Rebuild::Close, at.perturb(v1, domain), stack, e, m)?
             }
-        }
 
-        Expression::Perturb(perturb_expr) => {
-            let rhs = evaluate(
-                perturb_expr.clone().rhs,
-                e,
-                k.clone(),
-                init_d.clone(),
-                d.clone(),
-                c,
-            );
-            match rhs.clone() {
-                Either::Left(l) => match l {
-                    Value::Context(v1) => {
-                        let v = evaluate(
-                            perturb_expr.lhs,
-                            e,
-                            k.clone().perturb(v1.clone()),
-                            init_d,
-                            d.union(v1.domain()),
-                            c,
-                        );
-                        v
-                    }
+            Some(Either::Right((r, missing))) => {
+                let perturb_expr = PerturbExpression {
+                    lhs,
+                    rhs: r,
+                };
+                Control::Residual(Expression::Perturb(Box::new(perturb_expr)), missing)
+            }
 
-                    _ => panic!("Invalid expression in rhs of perturbation."),
-                },
+            other => return Err(invalid_result("a context to perturb with", other)),
+        },
 
-                Either::Right(_) => rhs.clone(),
+        Rebuild::IntensionApplication => match first {
+            Some(Either::Left(Value::Intension(intens))) => {
+                let idom = Domain::new().from_vec(intens.d.clone());
+                let at = at.perturb(intens.k, idom);
+                rebuild_from(vec![*intens.x], Rebuild::Close, at, stack, e, m)?
             }
-        }
 
-        Expression::BaseAbstraction(base_abstraction) => {
-            Either::Left(Value::BaseAbstraction(base_abstraction.clone()))
+            Some(Either::Right((r, missing))) => {
+                Control::Residual(Expression::IntensionApplication(Box::new(r)), missing)
+            }
+
+            other => return Err(invalid_result("an intension", other)),
+        },
+
+        Rebuild::WhereDim(wd) => match missing {
+            Some(missing) => Control::Residual(Expression::WhereDim(wd), missing),
+
+            None => {
+                let mut values = vec![];
+                values.extend(first.and_then(Either::left));
+                values.extend(results.filter_map(Either::left));
+                let (lhs, context, domain) = where_dim(*wd, values, &at)?;
+                rebuild_from(vec![lhs], Rebuild::Close, at.perturb(context, domain), stack, e, m)?
+            }
+        },
+
+        Rebuild::Close => match first {
+            Some(Either::Right((r, missing))) => {
+                Control::Residual(closed(r, at.k, at.d), missing)
+            }
+
+            Some(v) => pass(v),
+
+            None => Control::Return(Either::Right(Domain::new())),
+        },
+    })
+}
+
+// The error for a value which is not what the expression around it expects.
+fn invalid(expected: &str, found: &Value) -> EvalError {
+    EvalError::Invalid(format!("expected {} but found {:?}", expected, found))
+}
+
+fn invalid_result(expected: &str, found: Option<Either<Value, (Expression, Domain)>>) -> EvalError {
+    match found {
+        Some(Either::Left(v)) => invalid(expected, &v),
+
+        _ => EvalError::Invalid(format!("expected {}", expected)),
+    }
+}
+
+// The context which binds the dimensions of a where dim clause, each to a
+// hidden dimension at the depth of the clause which holds the value of its
+// rhs, their domain and the lhs to evaluate there.
+fn where_dim(
+    wd: WhereDimExpression,
+    values: Vec<Value>,
+    at: &At,
+) -> Result<(Expression, Context, Domain), EvalError> {
+    let depth = match at.k.lookup(wd.dim_q.clone()) {
+        Some(depth) => depth.expect_integer(),
+
+        None => {
+            let message = format!("{:?} is not in the context of its where dim clause", wd.dim_q);
+            return Err(EvalError::Invalid(message));
         }
+    };
+    let mut context = Context::new();
+    let mut domain = Domain::new();
+    for (dimension_expr, v) in wd.rhs.0.into_iter().zip(values) {
+        let xi = dimension_expr.lhs;
+        let (di, div) = generate_dimension(xi.i, wd.nat_q, depth);
+        context.push(xi.clone(), div);
+        context.push(di.clone(), v);
+        domain.push(xi.clone());
+    }
+    Ok((wd.lhs, context, domain))
+}
 
-        Expression::BaseApplication(base_application) => {
-            let base_abstraction = evaluate(
-                base_application.lhs.clone(),
-                e,
-                k.clone(),
-                init_d.clone(),
-                d.clone(),
-                c,
-            );
-            let mut args: Vec<Value> = vec![];
-            let mut missing: Domain = Domain::new();
-            for i in 0..base_application.args.len() {
-                let arg = evaluate(
-                    base_application.args[i].clone(),
-                    e,
-                    k.clone(),
-                    init_d.clone(),
-                    d.clone(),
-                    c,
-                );
-                match arg {
-                    Either::Left(l) => args.push(l),
-                    Either::Right(r) => {
-                        missing = missing.union(r);
+// Continue with the frame on top of the stack now that the expression it
+// waited on evaluated to v.
+fn resume(
+    frame: Frame,
+    v: Either<Value, Domain>,
+    stack: &mut Vec<Frame>,
+    e: &mut Environment,
+    c: &mut Cache,
+    p: &mut dyn ContextProvider,
+    m: &mut Meter,
+) -> Result<Control, EvalError> {
+    Ok(match frame {
+        Frame::Collect {
+            mut rest,
+            mut results,
+            then,
+            at,
+        } => {
+            results.push(v);
+            match rest.pop() {
+                Some(x) => {
+                    stack.push(Frame::Collect {
+                        rest,
+                        results,
+                        then,
+                        at: at.clone(),
+                    });
+                    Control::Eval(x, at)
+                }
+
+                None => {
+                    if let Some(op) = primitive(&then, &results) {
+                        m.primitive(op)?;
                     }
+                    combine(then, results, at, stack)?
                 }
             }
+        }
 
-            if missing.len() > 0 {
-                match base_abstraction {
-                    Either::Left(_) => Either::Right(missing),
-                    Either::Right(r) => Either::Right(missing.union(r)),
-                }
-            } else {
-                // Verify that base_application.lhs is a BaseAbstraction.
-                match base_abstraction {
-                    Either::Left(l) => match l {
-                        Value::BaseAbstraction(base_abstraction) => {
-                            // Check that the arity of BaseAbstraction matches the application.
-                            if base_abstraction.dimensions.len() == args.len() {
-                                let mut context = Context::new();
-                                for i in 0..args.len() {
-                                    context.push(
-                                        base_abstraction.dimensions[i].clone(),
-                                        args[i].clone(),
-                                    );
-                                }
-                                // let domain = Domain::new();
-                                // let domain = domain.from_vec(base_abstraction.dimensions);
-                                // Apply the base application to the arguments.
-                                evaluate(
-                                    base_abstraction.body.clone(),
-                                    e,
-                                    k.clone(),
-                                    init_d.clone(),
-                                    d.clone(),
-                                    c,
-                                )
-                            } else {
-                                panic!("Invalid arity in base application.")
-                            }
-                        }
+        Frame::If {
+            consequent,
+            alternate,
+            at,
+        } => match v {
+            Either::Left(Value::Literal(Literal::Bool(true))) => Control::Eval(consequent, at),
 
-                        _ => panic!("Expected base abstraction but here found other."),
-                    },
+            Either::Left(Value::Literal(Literal::Bool(false))) => Control::Eval(alternate, at),
 
-                    Either::Right(r) => Either::Right(r),
-                }
+            Either::Left(other) => return Err(invalid("a boolean", &other)),
+
+            Either::Right(r) => Control::Return(Either::Right(r)),
+        },
+
+        Frame::Query(at) => match v {
+            Either::Left(l) => {
+                Control::Return(lookup_dimension(l.expect_dimension(), at.k, at.init_d, at.d, p))
+            }
+
+            Either::Right(d0) => Control::Return(Either::Right(d0)),
+        },
+
+        Frame::Perturb(lhs, at) => match v {
+            Either::Left(Value::Context(v1)) => {
+                let domain = v1.domain();
+                Control::Eval(lhs, at.perturb(v1, domain))
+            }
+
+            Either::Left(other) => return Err(invalid("a context to perturb with", &other)),
+
+            Either::Right(r) => Control::Return(Either::Right(r)),
+        },
+
+        Frame::IntensionApplication(at) => match v {
+            Either::Left(Value::Intension(intens)) => {
+                let idom = Domain::new().from_vec(intens.d.clone());
+                Control::Eval(*intens.x, at.perturb(intens.k, idom))
+            }
+
+            Either::Left(other) => return Err(invalid("an intension", &other)),
+
+            Either::Right(r) => Control::Return(Either::Right(r)),
+        },
+
+        Frame::BaseBody(value_args, at) => match v {
+            Either::Left(Value::ValueAbstraction(value_abstraction)) => {
+                apply_value_abstraction(*value_abstraction, value_args, at)
+            }
+
+            Either::Left(other_value) => Control::Return(Either::Left(other_value)),
+
+            Either::Right(r) => Control::Return(Either::Right(r)),
+        },
+
+        Frame::Demand { x, definition, dd, at, gas } => {
+            let reads = c.end();
+            m.cached(c.len() + 1)?;
+            let key = Key::new(x.clone(), &definition, at.k.clone().restrict(dd.clone()));
+            c.depends_on(&key, reads);
+            c.add_with_gas(key, v.clone(), m.stats.gas - gas);
+            match settle(v, dd, &at) {
+                Settle::Done(v) => Control::Return(v),
+
+                Settle::Retry(dd) => demand(x, dd, at, stack, e, c, m)?,
             }
         }
 
-        Expression::ValueAbstraction(value_abstraction) => {
-            Either::Left(Value::ValueAbstraction(value_abstraction.clone()))
+        // What could not be evaluated is left as it was written.
+        Frame::Rebuild {
+            rest,
+            current,
+            mut results,
+            then,
+            at,
+        } => {
+            results.push(match v {
+                Either::Left(l) => Either::Left(l),
+
+                Either::Right(missing) => Either::Right((current, missing)),
+            });
+            rebuild_next(rest, results, then, at, stack, e, m)?
         }
+    })
+}
 
-        Expression::ValueApplication(value_application) => {
-            let value_abstraction = evaluate(
-                value_application.lhs.clone(),
-                e,
-                k.clone(),
-                init_d.clone(),
-                d.clone(),
-                c,
-            );
-            let mut args: Vec<Value> = vec![];
+// Separate the values from the dimensions which are missing for the others.
+fn partition(results: Vec<Either<Value, Domain>>) -> (Vec<Value>, Domain) {
+    let mut values = vec![];
+    let mut missing = Domain::new();
+    for v in results {
+        match v {
+            Either::Left(l) => values.push(l),
+            Either::Right(r) => missing = missing.union(r),
+        }
+    }
+    (values, missing)
+}
+
+// Apply a value abstraction to its arguments.
+fn apply_value_abstraction(
+    value_abstraction: ValueAbstraction,
+    value_args: Vec<Value>,
+    at: At,
+) -> Control {
+    let mut context = Context::new();
+    let mut domain = Domain::new();
+    for (di, v) in value_abstraction.dimensions.iter().zip(value_args) {
+        context.push(di.clone(), v);
+        domain.push(di.clone());
+    }
+    Control::Eval(value_abstraction.body, at.perturb(context, domain))
+}
+
+// The primitive operator which combining the results applies, if any. It is
+// applied to the arguments once none of them is missing a dimension.
+fn primitive<'a>(then: &Combine, results: &'a [Either<Value, Domain>]) -> Option<&'a str> {
+    match then {
+        &Combine::Application | &Combine::FunctionApplication(_) => {}
+
+        _ => return None,
+    }
+    match results.split_first() {
+        Some((&Either::Left(Value::Identifier(ref op)), args)) => {
+            let applied = args.iter().all(|v| match *v {
+                Either::Left(_) => true,
+                Either::Right(_) => false,
+            });
+            if applied {
+                Some(op)
+            } else {
+                None
+            }
+        }
+
+        _ => None,
+    }
+}
+
+// Combine the results of the subexpressions of an expression into its result,
+// or into the evaluation of a further expression.
+fn combine(
+    then: Combine,
+    results: Vec<Either<Value, Domain>>,
+    at: At,
+    stack: &mut Vec<Frame>,
+) -> Result<Control, EvalError> {
+    Ok(match then {
+        Combine::Sequence => match results.into_iter().last() {
+            Some(v) => Control::Return(v),
+
+            None => Control::Return(Either::Right(Domain::new())),
+        },
+
+        Combine::TupleBuilder => {
+            let mut result: Vec<Tuple> = vec![];
             let mut missing: Domain = Domain::new();
-            for i in 0..value_application.args.len() {
-                let arg = evaluate(
-                    value_application.args[i].clone(),
-                    e,
-                    k.clone(),
-                    init_d.clone(),
-                    d.clone(),
-                    c,
-                );
-                match arg {
-                    Either::Left(l) => args.push(l),
-                    Either::Right(r) => {
-                        missing = missing.union(r);
+            let mut results = results.into_iter();
+            while let (Some(lhs), Some(rhs)) = (results.next(), results.next()) {
+                match (lhs, rhs) {
+                    (Either::Left(l), Either::Left(rl)) => {
+                        result.push(Tuple::new(l.expect_dimension(), rl))
                     }
+
+                    (Either::Left(_), Either::Right(rr)) => missing = missing.union(rr),
+
+                    (Either::Right(r), Either::Left(_)) => missing = missing.union(r),
+
+                    (Either::Right(r), Either::Right(rr)) => missing = missing.union(r).union(rr),
                 }
             }
 
-            if missing.len() > 0 {
-                match value_abstraction {
-                    Either::Left(_) => Either::Right(missing),
-                    Either::Right(r) => Either::Right(missing.union(r)),
-                }
+            if !missing.0.is_empty() {
+                Control::Return(Either::Right(missing))
             } else {
-                // Verify that value_application.lhs is a ValueAbstraction.
-                match value_abstraction {
-                    Either::Left(l) => match l {
-                        Value::ValueAbstraction(value_abstraction) => {
-                            // Check that the arity of ValueAbstraction matches the application.
-                            if value_abstraction.dimensions.len() == args.len() {
-                                let mut context = Context::new();
-                                for i in 0..args.len() {
-                                    context.push(
-                                        value_abstraction.dimensions[i].clone(),
-                                        args[i].clone(),
-                                    );
-                                }
-                                // let domain = Domain::new();
-                                // let domain = domain.from_vec(base_abstraction.dimensions);
-                                // Apply the value application to the arguments.
-                                evaluate(
-                                    value_abstraction.body.clone(),
-                                    e,
-                                    k.clone().perturb(context),
-                                    init_d.clone(),
-                                    d.clone(),
-                                    c,
-                                )
-                            } else {
-                                panic!("Invalid arity in value application.")
-                            }
-                        }
+                Control::Return(Either::Left(Value::Context(Context(result))))
+            }
+        }
 
-                        _ => panic!("Expected value abstraction but here found other."),
-                    },
+        Combine::Application => {
+            let mut results = results;
+            let e0 = results.remove(0);
+            let (params, missing) = partition(results);
+            match e0 {
+                Either::Left(_) if !missing.0.is_empty() => Control::Return(Either::Right(missing)),
 
-                    Either::Right(r) => Either::Right(r),
-                }
+                Either::Left(Value::Identifier(op)) => Control::Return(apply_primitive(op, params)),
+
+                Either::Left(other) => return Err(invalid("an operator", &other)),
+
+                Either::Right(r) => Control::Return(Either::Right(missing.union(r))),
             }
         }
 
-        Expression::FunctionApplication(function_application) => {
-            let abstraction = e.lookup(function_application.id.clone());
-
-            // Evaluate the function in order to obtain a set of abstractions.
-            let abstraction = evaluate(
-                abstraction.clone(),
-                e,
-                k.clone(),
-                init_d.clone(),
-                d.clone(),
-                c,
-            );
-
-            // Evaluate the function arguments.
-            let mut base_args: Vec<Value> = vec![];
-            let mut value_args: Vec<Value> = vec![];
-            let mut missing: Domain = Domain::new();
+        Combine::BaseApplication => {
+            let mut results = results;
+            let base_abstraction = results.remove(0);
+            let (args, missing) = partition(results);
+            match base_abstraction {
+                Either::Left(_) if !missing.0.is_empty() => Control::Return(Either::Right(missing)),
 
-            // Evaluate the base arguments.
-            for i in 0..function_application.base_args.len() {
-                let base_arg = evaluate(
-                    function_application.base_args[i].clone(),
-                    e,
-                    k.clone(),
-                    init_d.clone(),
-                    d.clone(),
-                    c,
-                );
-                match base_arg {
-                    Either::Left(l) => base_args.push(l),
-                    Either::Right(r) => missing = missing.union(r),
+                Either::Left(Value::BaseAbstraction(base_abstraction)) => {
+                    // Check that the arity of BaseAbstraction matches the application.
+                    if base_abstraction.dimensions.len() == args.len() {
+                        // Apply the base application to the arguments.
+                        Control::Eval(base_abstraction.body, at)
+                    } else {
+                        let message = "wrong number of arguments in a base application";
+                        return Err(EvalError::Invalid(message.to_string()));
+                    }
                 }
+
+                Either::Left(other) => return Err(invalid("a base abstraction", &other)),
+
+                Either::Right(r) => Control::Return(Either::Right(missing.union(r))),
             }
+        }
+
+        Combine::ValueApplication => {
+            let mut results = results;
+            let value_abstraction = results.remove(0);
+            let (args, missing) = partition(results);
+            match value_abstraction {
+                Either::Left(_) if !missing.0.is_empty() => Control::Return(Either::Right(missing)),
 
-            // Evaluate the value arguments.
-            for i in 0..function_application.value_args.len() {
-                let value_arg = evaluate(
-                    function_application.value_args[i].clone(),
-                    e,
-                    k.clone(),
-                    init_d.clone(),
-                    d.clone(),
-                    c,
-                );
-                match value_arg {
-                    Either::Left(l) => value_args.push(l),
-                    Either::Right(r) => missing = missing.union(r),
+                Either::Left(Value::ValueAbstraction(value_abstraction)) => {
+                    // Check that the arity of ValueAbstraction matches the application.
+                    if value_abstraction.dimensions.len() == args.len() {
+                        let mut context = Context::new();
+                        for (di, v) in value_abstraction.dimensions.iter().zip(args) {
+                            context.push(di.clone(), v);
+                        }
+                        // Apply the value application to the arguments.
+                        let at = At {
+                            k: at.k.clone().perturb(context),
+                            init_d: at.init_d,
+                            d: at.d,
+                        };
+                        Control::Eval(value_abstraction.body, at)
+                    } else {
+                        let message = "wrong number of arguments in a value application";
+                        return Err(EvalError::Invalid(message.to_string()));
+                    }
                 }
+
+                Either::Left(other) => return Err(invalid("a value abstraction", &other)),
+
+                Either::Right(r) => Control::Return(Either::Right(missing.union(r))),
             }
+        }
 
-            // TODO: Ignore the name arguments.
+        Combine::FunctionApplication(n) => {
+            let mut results = results;
+            let abstraction = results.remove(0);
+            let value_results = results.split_off(n);
+            let (_, base_missing) = partition(results);
+            let (value_args, value_missing) = partition(value_results);
+            let missing = base_missing.union(value_missing);
+            match abstraction {
+                Either::Left(_) if !missing.0.is_empty() => Control::Return(Either::Right(missing)),
 
-            if missing.len() > 0 {
-                match abstraction {
-                    Either::Left(_) => Either::Right(missing),
-                    Either::Right(r) => Either::Right(missing.union(r)),
+                Either::Left(Value::BaseAbstraction(base_abstraction)) => {
+                    // Apply base args to the abstraction then apply value args
+                    // to the value abstraction it evaluates to.
+                    stack.push(Frame::BaseBody(value_args, at.clone()));
+                    Control::Eval(base_abstraction.body, at)
                 }
-            } else {
-                match abstraction {
-                    Either::Left(l) => match l {
-                        Value::BaseAbstraction(base_abstraction) => {
-                            // Apply base args to the abstraction then apply value args if there are any.
-                            let mut context = Context::new();
-                            for i in 0..base_args.len() {
-                                context.push(
-                                    base_abstraction.dimensions[i].clone(),
-                                    base_args[i].clone(),
-                                );
-                            }
-                            // Apply the application to the arguments.
-                            let maybe_value_abstraction = evaluate(
-                                base_abstraction.body.clone(),
-                                e,
-                                k.clone(),
-                                init_d.clone(),
-                                d.clone(),
-                                c,
-                            );
-
-                            match maybe_value_abstraction {
-                                Either::Left(l) => match l {
-                                    Value::ValueAbstraction(value_abstraction) => {
-                                        // Apply value args to the abstraction.
-                                        let mut context = Context::new();
-                                        let mut domain = Domain::new();
-                                        for i in 0..value_args.len() {
-                                            context.push(
-                                                value_abstraction.dimensions[i].clone(),
-                                                value_args[i].clone(),
-                                            );
-                                            domain.push(value_abstraction.dimensions[i].clone());
-                                        }
-                                        // Apply the application to the arguments.
-                                        evaluate(
-                                            value_abstraction.body.clone(),
-                                            e,
-                                            k.clone().perturb(context),
-                                            init_d.clone(),
-                                            d.clone().union(domain),
-                                            c,
-                                        )
-                                    }
-
-                                    other_value => Either::Left(other_value),
-                                },
-
-                                Either::Right(r) => Either::Right(r),
-                            }
-                        }
 
-                        Value::ValueAbstraction(value_abstraction) => {
-                            // Apply value args to the abstraction.
-                            let mut context = Context::new();
-                            let mut domain = Domain::new();
-                            //println!("dimensions: {:?}", value_abstraction.dimensions.clone());
-                            //println!("value_args: {:?}", value_args.clone());
-                            for i in 0..value_args.len() {
-                                context.push(
-                                    value_abstraction.dimensions[i].clone(),
-                                    value_args[i].clone(),
-                                );
-                                domain.push(value_abstraction.dimensions[i].clone());
-                            }
-                            //println!("context: {:?}", k.clone().perturb(context.clone()));
-                            // Apply the application to the arguments.
-                            evaluate(
-                                value_abstraction.body.clone(),
-                                e,
-                                k.clone().perturb(context),
-                                init_d.clone(),
-                                d.clone().union(domain),
-                                c,
-                            )
-                        }
-
-                        other => panic!("Expected abstraction but here found {:?}", other),
-                    },
+                Either::Left(Value::ValueAbstraction(value_abstraction)) => {
+                    apply_value_abstraction(*value_abstraction, value_args, at)
+                }
 
-                    Either::Right(r) => Either::Right(r),
+                // Primitive operators are applied to the value arguments.
+                Either::Left(Value::Identifier(op)) => {
+                    Control::Return(apply_primitive(op, value_args))
                 }
+
+                Either::Left(other) => return Err(invalid("an abstraction", &other)),
+
+                Either::Right(r) => Control::Return(Either::Right(missing.union(r))),
             }
         }
 
-        Expression::IntensionBuilder(intens_expr) => {
-            let mut dimensions = Domain::new();
-            let mut missing = Domain::new();
-            for expr in intens_expr.domain.clone() {
-                let vi = evaluate(expr, e, k.clone(), init_d.clone(), d.clone(), c);
-                match vi {
-                    Either::Left(di) => {
-                        dimensions.push(di.expect_dimension());
+        Combine::IntensionBuilder(value) => {
+            let (dimensions, missing) = partition(results);
+            if !missing.0.is_empty() {
+                Control::Return(Either::Right(missing))
+            } else {
+                let mut domain = Domain::new();
+                for v in dimensions {
+                    match v {
+                        // `#` in a domain stands for every dimension of
+                        // the context.
+                        Value::Context(k) => domain = domain.union(k.domain()),
+                        di => {
+                            domain.push(di.expect_dimension());
+                        }
                     }
-
-                    Either::Right(d) => missing = missing.union(d).clone(),
                 }
-            }
-            // println!("evaluated dimensions = {:?}", dimensions.clone());
-            // println!("K = {:?}", k.clone().restrict(dimensions.clone()));
-            if missing.len() > 0 {
-                Either::Right(missing)
-            } else {
                 let intens = Intension {
-                    k: k.clone().restrict(dimensions.clone()),
-                    d: d.clone().union(dimensions.clone()).to_vec(),
-                    x: Box::new(intens_expr.value.clone()),
+                    k: at.k.clone().restrict(domain.clone()),
+                    d: at.d.union(domain).to_vec(),
+                    x: Box::new(value),
                 };
-                Either::Left(Value::Intension(Box::new(intens)))
+                Control::Return(Either::Left(Value::Intension(Box::new(intens))))
             }
         }
 
-        Expression::IntensionApplication(intens_app) => {
-            let intens_app = (*intens_app).clone();
-            let v0 = evaluate(intens_app, e, k.clone(), init_d.clone(), d.clone(), c);
-            match v0 {
-                Either::Left(v) => {
-                    let intens = v.expect_intension();
-                    let ik = intens.k.clone();
-                    let idom = Domain::new();
-                    let idom = idom.from_vec(intens.d.clone());
-                    let x = intens.x.clone();
-                    evaluate(
-                        *x,
-                        e,
-                        k.clone().perturb(ik),
-                        init_d,
-                        d.clone().union(idom),
-                        c,
-                    )
-                }
-
-                Either::Right(_) => v0.clone(),
+        Combine::WhereDim(wd) => {
+            let wd = *wd;
+            let (values, missing) = partition(results);
+            if !missing.0.is_empty() {
+                Control::Return(Either::Right(missing))
+            } else {
+                // The rhs dimensions are evaluated into a context.
+                let (lhs, context, domain) = where_dim(wd, values, &at)?;
+                Control::Eval(lhs, at.perturb(context, domain))
             }
         }
+    })
+}
 
-        Expression::Identifier(id) => {
-            // println!("{} @ {}", id.clone(), k.clone().restrict(d.clone()).print());
-            evaluate_id1(
-                id.clone(),
-                e,
-                k.clone(),
-                init_d.clone(),
-                Domain::new(),
-                d.clone(),
-                c,
-            )
-        }
+// Demand the value of an identifier at the trial domain dd, from the cache if
+// it is there or else by evaluating its definition.
+fn demand(
+    x: Identifier,
+    dd: Domain,
+    at: At,
+    stack: &mut Vec<Frame>,
+    e: &mut Environment,
+    c: &mut Cache,
+    m: &mut Meter,
+) -> Result<Control, EvalError> {
+    let definition = c.definition(&x, e);
+    let mut dd = dd;
+    loop {
+        let key = Key::new(x.clone(), &definition, at.k.clone().restrict(dd.clone()));
+        let v0 = c.find(&key);
+        match v0 {
+            Some(v) => {
+                // The demand is charged as if the entry had been computed.
+                m.hit(c.gas(&key))?;
+                match settle(v, dd, &at) {
+                    Settle::Done(v) => return Ok(Control::Return(v)),
 
-        Expression::WhereDim(wd) => {
-            let rhs = wd.rhs.clone();
-            let lhs = wd.lhs.clone();
-            // evaluate rhs dimensions into a context
-            let mut context = Context::new();
-            let mut domain = Domain::new();
-            let mut missing = Domain::new();
-            for dimension_expr in rhs.0 {
-                let vi = evaluate(
-                    dimension_expr.rhs,
-                    e,
-                    k.clone(),
-                    init_d.clone(),
-                    d.clone(),
-                    c,
-                );
-                match vi {
-                    Either::Left(v) => {
-                        let xi = dimension_expr.lhs;
-                        let depth = k.lookup(wd.dim_q.clone()).unwrap().expect_integer();
-                        let (di, div) = generate_dimension(xi.i, wd.nat_q, depth);
-                        context.push(xi.clone(), div);
-                        context.push(di.clone(), v);
-                        domain.push(xi.clone());
-                    }
+                    Settle::Retry(dd1) => dd = dd1,
+                }
+            }
 
-                    Either::Right(dom) => missing = missing.union(dom),
+            None => {
+                // Mark the demand as in progress so that a definition which
+                // depends on itself is reported instead of looping forever.
+                let gas = m.stats.gas;
+                m.demand(c.pending.len() + 1)?;
+                if let Err(chain) = c.begin(key) {
+                    return Err(EvalError::CyclicDemand(chain));
                 }
+                let body = e.lookup(x.clone()).clone();
+                let trial = At {
+                    k: at.k.clone(),
+                    init_d: at.init_d.clone(),
+                    d: dd.clone(),
+                };
+                stack.push(Frame::Demand {
+                    x,
+                    definition,
+                    dd,
+                    at,
+                    gas,
+                });
+                return Ok(Control::Eval(body, trial));
             }
-            if missing.len() > 0 {
-                println!("missing => {:?}", missing.clone());
-                Either::Right(missing)
+        }
+    }
+}
+
+// Decide what to do with the result of demanding an identifier at the trial
+// domain dd: when the dimensions it is missing are available the identifier
+// is demanded again with them.
+fn settle(v0: Either<Value, Domain>, dd: Domain, at: &At) -> Settle {
+    match v0 {
+        Either::Left(_) => Settle::Done(v0),
+
+        Either::Right(mut d0) => {
+            if d0.clone().is_subset(at.d.clone()) {
+                if d0.is_subset(at.k.clone().domain()) {
+                    Settle::Retry(dd.union(d0))
+                } else {
+                    Settle::Done(Either::Right(d0))
+                }
             } else {
-                // println!("wheredim K pre => {}", k.clone());
-                // println!("wheredim K post => {}", k.clone().perturb(context.clone()));
-                evaluate(
-                    lhs,
-                    e,
-                    k.clone().perturb(context),
-                    init_d,
-                    d.clone().union(domain),
-                    c,
-                )
+                Settle::Done(Either::Right(d0.difference(at.d.clone())))
             }
         }
     }
@@ -1,5 +1,5 @@
 use environment::{Environment, L1Environment};
-use value::Dimension;
+use value::{Dimension, Value};
 
 type Identifier = String;
 
@@ -95,14 +95,27 @@ pub struct NameApplication {
     pub args: Vec<Expression>,
 }
 
+// How the domain of an intension builder came to be.
+#[derive(Hash, Eq, PartialEq, Clone, Debug)]
+pub enum DomainAnnotation {
+    // Written out by hand, e.g. {t} X
+    Explicit,
+    // Inferred from the free dimensions of the body, e.g. {?} X or ↑X
+    Inferred,
+    // Generated by the compiler, e.g. for name arguments
+    Generated,
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct L1IntensionExpression {
+    pub annotation: DomainAnnotation,
     pub domain: Vec<L1Expression>,
     pub value: L1Expression,
 }
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub struct IntensionExpression {
+    pub annotation: DomainAnnotation,
     pub domain: Vec<Expression>,
     pub value: Expression,
 }
@@ -329,6 +342,7 @@ pub enum L1Expression {
     If(Box<L1IfExpression>),
     WhereVar(Box<L1WhereVarExpression>),
     Query(Box<L1Expression>),
+    ContextQuery,
     Perturb(Box<L1PerturbExpression>),
     WhereDim(Box<L1WhereDimExpression>),
 }
@@ -362,8 +376,11 @@ pub enum Expression {
     If(Box<IfExpression>),
     WhereVar(Box<WhereVarExpression>),
     Query(Box<Expression>),
+    ContextQuery,
     Perturb(Box<PerturbExpression>),
     WhereDim(Box<WhereDimExpression>),
+    // A value computed ahead of time, e.g. in a residual program
+    Constant(Box<Value>),
 }
 
 impl Expression {
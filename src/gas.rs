@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use expression::Expression;
+
+// The kinds of expressions, as a cost model prices them.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum ExpressionKind {
+    Literal,
+    Dimension,
+    Identifier,
+    Operator,
+    Sequence,
+    TupleBuilder,
+    BaseAbstraction,
+    BaseApplication,
+    ValueAbstraction,
+    ValueApplication,
+    FunctionApplication,
+    IntensionBuilder,
+    IntensionApplication,
+    Application,
+    If,
+    WhereVar,
+    Query,
+    ContextQuery,
+    Perturb,
+    WhereDim,
+    Constant,
+}
+
+impl ExpressionKind {
+    pub fn of(x: &Expression) -> ExpressionKind {
+        match *x {
+            Expression::Literal(_) => ExpressionKind::Literal,
+            Expression::Dimension(_) => ExpressionKind::Dimension,
+            Expression::Identifier(_) => ExpressionKind::Identifier,
+            Expression::Operator(_) => ExpressionKind::Operator,
+            Expression::Sequence(_) => ExpressionKind::Sequence,
+            Expression::TupleBuilder(_) => ExpressionKind::TupleBuilder,
+            Expression::BaseAbstraction(_) => ExpressionKind::BaseAbstraction,
+            Expression::BaseApplication(_) => ExpressionKind::BaseApplication,
+            Expression::ValueAbstraction(_) => ExpressionKind::ValueAbstraction,
+            Expression::ValueApplication(_) => ExpressionKind::ValueApplication,
+            Expression::FunctionApplication(_) => ExpressionKind::FunctionApplication,
+            Expression::IntensionBuilder(_) => ExpressionKind::IntensionBuilder,
+            Expression::IntensionApplication(_) => ExpressionKind::IntensionApplication,
+            Expression::Application(_) => ExpressionKind::Application,
+            Expression::If(_) => ExpressionKind::If,
+            Expression::WhereVar(_) => ExpressionKind::WhereVar,
+            Expression::Query(_) => ExpressionKind::Query,
+            Expression::ContextQuery => ExpressionKind::ContextQuery,
+            Expression::Perturb(_) => ExpressionKind::Perturb,
+            Expression::WhereDim(_) => ExpressionKind::WhereDim,
+            Expression::Constant(_) => ExpressionKind::Constant,
+        }
+    }
+}
+
+// The gas charged for the work of an evaluation: for every expression
+// evaluated, by its kind, for every primitive applied, by its operator, and
+// for every demand which misses the cache. A demand found in the cache is
+// charged what its entry was charged when it was computed, so what an
+// evaluation is charged only depends on the program and the query, whichever
+// entries are already in the cache. Entries which came from elsewhere are
+// charged one miss.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CostModel {
+    expressions: HashMap<ExpressionKind, u64>,
+    primitives: HashMap<String, u64>,
+    // The costs of the kinds of expressions and the operators which are not
+    // given their own.
+    expression: u64,
+    primitive: u64,
+    cache_miss: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> CostModel {
+        CostModel::uniform(1)
+    }
+}
+
+impl CostModel {
+    // Charge the same for every expression, primitive and cache miss.
+    pub fn uniform(gas: u64) -> CostModel {
+        CostModel {
+            expressions: HashMap::new(),
+            primitives: HashMap::new(),
+            expression: gas,
+            primitive: gas,
+            cache_miss: gas,
+        }
+    }
+
+    pub fn expression(mut self, kind: ExpressionKind, gas: u64) -> CostModel {
+        self.expressions.insert(kind, gas);
+        self
+    }
+
+    pub fn primitive(mut self, op: &str, gas: u64) -> CostModel {
+        self.primitives.insert(op.to_string(), gas);
+        self
+    }
+
+    pub fn cache_miss(mut self, gas: u64) -> CostModel {
+        self.cache_miss = gas;
+        self
+    }
+
+    pub fn expression_cost(&self, x: &Expression) -> u64 {
+        *self.expressions.get(&ExpressionKind::of(x)).unwrap_or(&self.expression)
+    }
+
+    pub fn primitive_cost(&self, op: &str) -> u64 {
+        *self.primitives.get(op).unwrap_or(&self.primitive)
+    }
+
+    pub fn cache_miss_cost(&self) -> u64 {
+        self.cache_miss
+    }
+}
@@ -0,0 +1,610 @@
+use colored::*;
+
+use environment::Environment;
+use expression::*;
+use domain::Domain;
+use encoding::encode;
+use value::*;
+
+type Identifier = String;
+
+// A hand-written intension domain which disagrees with the free dimensions
+// of the intension body.
+#[derive(Clone, Debug)]
+pub struct DomainLint {
+    pub intension: IntensionExpression,
+    // Dimensions which are free in the body but are not frozen.
+    pub missing: Domain,
+    // Dimensions which are frozen but never used by the body.
+    pub unused: Domain,
+}
+
+impl DomainLint {
+    pub fn print(&self) -> String {
+        let mut s = format!(
+            "{} {}",
+            "warning:".bright_yellow(),
+            super::print_expression(Expression::IntensionBuilder(Box::new(self.intension.clone())), 0)
+        );
+        if !self.missing.0.is_empty() {
+            s = format!("{}\n  domain is too small, missing {}", s, self.missing.print());
+        }
+        if !self.unused.0.is_empty() {
+            s = format!("{}\n  domain is too large, unused {}", s, self.unused.print());
+        }
+        s
+    }
+}
+
+fn lookup(e: &Environment, id: &Identifier) -> Option<Expression> {
+    for x in e.0.iter() {
+        if x.id == *id {
+            return Some(x.equation.clone());
+        }
+    }
+    None
+}
+
+// The dimensions which are set by a perturbation or a wheredim and which are
+// therefore no longer free in the expression being evaluated.
+fn tuple_dimensions(x: &Expression) -> Option<Domain> {
+    match x {
+        Expression::TupleBuilder(tuple_expr) => {
+            let mut d = Domain::new();
+            for tuple in tuple_expr {
+                match tuple.lhs {
+                    Expression::Dimension(ref di) => {
+                        d.push(di.clone());
+                    }
+                    _ => return None,
+                }
+            }
+            Some(d)
+        }
+
+        _ => None,
+    }
+}
+
+fn remove(d: Domain, dims: &Vec<Dimension>) -> Domain {
+    let mut bound = Domain::new();
+    for di in dims {
+        bound.push(di.clone());
+    }
+    d.difference(bound)
+}
+
+// Compute the dimensions which an expression may query when it is evaluated.
+// Identifiers are followed through their definitions in the environment, the
+// `visiting` stack prevents looping on recursive definitions.
+pub fn free_dimensions(x: &Expression, e: &Environment, visiting: &mut Vec<Identifier>) -> Domain {
+    match x {
+        &Expression::Literal(_) => Domain::new(),
+
+        &Expression::Operator(_) => Domain::new(),
+
+        // What `#` depends on is left to queries_context.
+        &Expression::ContextQuery => Domain::new(),
+
+        &Expression::Constant(_) => Domain::new(),
+
+        Expression::Dimension(di) => {
+            let mut d = Domain::new();
+            d.push(di.clone());
+            d
+        }
+
+        Expression::Identifier(id) => {
+            if visiting.contains(id) {
+                return Domain::new();
+            }
+            match lookup(e, id) {
+                Some(equation) => {
+                    visiting.push(id.clone());
+                    let d = free_dimensions(&equation, e, visiting);
+                    visiting.pop();
+                    d
+                }
+                None => Domain::new(),
+            }
+        }
+
+        &Expression::Sequence(ref exprs) | &Expression::Application(ref exprs) => {
+            let mut d = Domain::new();
+            for expr in exprs {
+                d = d.union(free_dimensions(expr, e, visiting));
+            }
+            d
+        }
+
+        Expression::TupleBuilder(tuple_expr) => {
+            let mut d = Domain::new();
+            for tuple in tuple_expr {
+                d = d.union(free_dimensions(&tuple.lhs, e, visiting));
+                d = d.union(free_dimensions(&tuple.rhs, e, visiting));
+            }
+            d
+        }
+
+        Expression::If(if_expr) => free_dimensions(&if_expr.condition, e, visiting)
+            .union(free_dimensions(&if_expr.consequent, e, visiting))
+            .union(free_dimensions(&if_expr.alternate, e, visiting)),
+
+        Expression::Query(e0) => free_dimensions(e0, e, visiting),
+
+        Expression::Perturb(perturb_expr) => {
+            let lhs = free_dimensions(&perturb_expr.lhs, e, visiting);
+            let rhs = free_dimensions(&perturb_expr.rhs, e, visiting);
+            match tuple_dimensions(&perturb_expr.rhs) {
+                Some(bound) => lhs.difference(bound).union(rhs),
+                None => lhs.union(rhs),
+            }
+        }
+
+        Expression::WhereVar(wv) => {
+            let mut e = e.clone();
+            e.merge(wv.rhs.clone());
+            free_dimensions(&wv.lhs, &e, visiting)
+        }
+
+        Expression::WhereDim(wd) => {
+            let mut d = Domain::new();
+            let mut bound = vec![];
+            for dimension_expr in wd.rhs.0.iter() {
+                d = d.union(free_dimensions(&dimension_expr.rhs, e, visiting));
+                bound.push(dimension_expr.lhs.clone());
+            }
+            d.union(remove(free_dimensions(&wd.lhs, e, visiting), &bound))
+        }
+
+        Expression::BaseAbstraction(base_abstraction) => remove(
+            free_dimensions(&base_abstraction.body, e, visiting),
+            &base_abstraction.dimensions,
+        ),
+
+        Expression::ValueAbstraction(value_abstraction) => remove(
+            free_dimensions(&value_abstraction.body, e, visiting),
+            &value_abstraction.dimensions,
+        ),
+
+        Expression::BaseApplication(base_application) => {
+            let mut d = free_dimensions(&base_application.lhs, e, visiting);
+            for arg in base_application.args.iter() {
+                d = d.union(free_dimensions(arg, e, visiting));
+            }
+            d
+        }
+
+        Expression::ValueApplication(value_application) => {
+            let mut d = free_dimensions(&value_application.lhs, e, visiting);
+            for arg in value_application.args.iter() {
+                d = d.union(free_dimensions(arg, e, visiting));
+            }
+            d
+        }
+
+        Expression::FunctionApplication(function_application) => {
+            let f = Expression::Identifier(function_application.id.clone());
+            let mut d = free_dimensions(&f, e, visiting);
+            for arg in function_application.base_args.iter() {
+                d = d.union(free_dimensions(arg, e, visiting));
+            }
+            for arg in function_application.value_args.iter() {
+                d = d.union(free_dimensions(arg, e, visiting));
+            }
+            d
+        }
+
+        // Building an intension only evaluates its domain, the body is
+        // evaluated once the intension is applied.
+        Expression::IntensionBuilder(intens_expr) => {
+            let mut d = Domain::new();
+            for expr in intens_expr.domain.iter() {
+                d = d.union(free_dimensions(expr, e, visiting));
+            }
+            d
+        }
+
+        Expression::IntensionApplication(intens_app) => {
+            let mut d = free_dimensions(intens_app, e, visiting);
+            // An unknown body is left to queries_context.
+            let bodies = intension_bodies(intens_app, e, &mut vec![]).unwrap_or_default();
+            for (intens_expr, e) in bodies {
+                let body = free_dimensions(&intens_expr.value, &e, visiting);
+                d = d.union(body.difference(declared_domain(&intens_expr)));
+            }
+            d
+        }
+    }
+}
+
+// Whether an expression may query the whole context with `#`, which no list
+// of free dimensions can stand for. It is followed as free_dimensions follows
+// it.
+pub fn queries_context(x: &Expression, e: &Environment, visiting: &mut Vec<Identifier>) -> bool {
+    match x {
+        &Expression::ContextQuery => true,
+
+        Expression::Identifier(id) => {
+            if visiting.contains(id) {
+                return false;
+            }
+            match lookup(e, id) {
+                Some(equation) => {
+                    visiting.push(id.clone());
+                    let queries = queries_context(&equation, e, visiting);
+                    visiting.pop();
+                    queries
+                }
+                None => false,
+            }
+        }
+
+        Expression::FunctionApplication(function_application) => {
+            let f = Expression::Identifier(function_application.id.clone());
+            queries_context(&f, e, visiting)
+                || function_application
+                    .base_args
+                    .iter()
+                    .chain(function_application.value_args.iter())
+                    .any(|arg| queries_context(arg, e, visiting))
+        }
+
+        Expression::WhereVar(wv) => {
+            let mut e = e.clone();
+            e.merge(wv.rhs.clone());
+            queries_context(&wv.lhs, &e, visiting)
+        }
+
+        Expression::IntensionBuilder(intens_expr) => intens_expr
+            .domain
+            .iter()
+            .any(|expr| queries_context(expr, e, visiting)),
+
+        Expression::IntensionApplication(intens_app) => {
+            if queries_context(intens_app, e, visiting) {
+                return true;
+            }
+            match intension_bodies(intens_app, e, &mut vec![]) {
+                Some(bodies) => bodies
+                    .iter()
+                    .any(|(intens_expr, e)| queries_context(&intens_expr.value, e, visiting)),
+                // A body which is not known may query anything.
+                None => true,
+            }
+        }
+
+        _ => {
+            let mut queries = false;
+            map_children(x.clone(), |expr| {
+                queries = queries || queries_context(&expr, e, visiting);
+                expr
+            });
+            queries
+        }
+    }
+}
+
+// The intension builders which an expression may evaluate to, each with the
+// definitions in scope of its body. They are followed as free_dimensions
+// follows expressions: through identifiers, the lhs of `@`, of where clauses
+// and of wheredims, and both branches of a conditional. None when an intension
+// cannot be found this way, its body is then unknown.
+fn intension_bodies(
+    x: &Expression,
+    e: &Environment,
+    visiting: &mut Vec<Identifier>,
+) -> Option<Vec<(IntensionExpression, Environment)>> {
+    match x {
+        Expression::IntensionBuilder(intens_expr) => Some(vec![(*intens_expr.clone(), e.clone())]),
+
+        Expression::Identifier(id) => {
+            if visiting.contains(id) {
+                return Some(vec![]);
+            }
+            let equation = lookup(e, id)?;
+            visiting.push(id.clone());
+            let bodies = intension_bodies(&equation, e, visiting);
+            visiting.pop();
+            bodies
+        }
+
+        Expression::Perturb(perturb_expr) => intension_bodies(&perturb_expr.lhs, e, visiting),
+
+        Expression::WhereVar(wv) => {
+            let mut e = e.clone();
+            e.merge(wv.rhs.clone());
+            intension_bodies(&wv.lhs, &e, visiting)
+        }
+
+        Expression::WhereDim(wd) => intension_bodies(&wd.lhs, e, visiting),
+
+        Expression::If(if_expr) => {
+            let mut bodies = intension_bodies(&if_expr.consequent, e, visiting)?;
+            bodies.extend(intension_bodies(&if_expr.alternate, e, visiting)?);
+            Some(bodies)
+        }
+
+        _ => None,
+    }
+}
+
+fn declared_domain(intens_expr: &IntensionExpression) -> Domain {
+    let mut d = Domain::new();
+    for expr in intens_expr.domain.iter() {
+        if let Expression::Dimension(di) = expr {
+            d.push(di.clone());
+        }
+    }
+    d
+}
+
+// The dimensions of a domain in a deterministic order.
+fn sorted(d: Domain) -> Vec<Dimension> {
+    let mut dims = d.to_vec();
+    dims.sort_by_cached_key(encode);
+    dims
+}
+
+// Replace the domain of every intension builder written as `{?} X` or `↑X`
+// with the free dimensions of its body.
+pub fn infer_intension_domains(x: Expression) -> Expression {
+    let e = Environment::new();
+    infer(x, &e)
+}
+
+// The same for an expression in the scope of the definitions.
+pub fn infer_intension_domains_in(x: Expression, e: &Environment) -> Expression {
+    infer(x, e)
+}
+
+fn infer(x: Expression, e: &Environment) -> Expression {
+    match x {
+        Expression::IntensionBuilder(intens_expr) => {
+            let intens_expr = *intens_expr;
+            let value = infer(intens_expr.value.clone(), e);
+            let domain = match intens_expr.annotation {
+                // A body which queries `#` depends on the whole context, so
+                // the intension freezes all of it.
+                DomainAnnotation::Inferred => {
+                    if queries_context(&intens_expr.value, e, &mut vec![]) {
+                        vec![Expression::ContextQuery]
+                    } else {
+                        let free = free_dimensions(&intens_expr.value, e, &mut vec![]);
+                        sorted(free)
+                            .into_iter()
+                            .map(Expression::Dimension)
+                            .collect()
+                    }
+                }
+                _ => intens_expr
+                    .domain
+                    .into_iter()
+                    .map(|expr| infer(expr, e))
+                    .collect(),
+            };
+            Expression::IntensionBuilder(Box::new(IntensionExpression {
+                annotation: intens_expr.annotation,
+                domain,
+                value,
+            }))
+        }
+
+        Expression::WhereVar(wv) => {
+            let wv = *wv;
+            let mut scope = e.clone();
+            scope.merge(wv.rhs.clone());
+            let mut rhs = Environment::new();
+            for def in wv.rhs.0 {
+                rhs.define(def.id, infer(def.equation, &scope));
+            }
+            Expression::WhereVar(Box::new(WhereVarExpression {
+                lhs: infer(wv.lhs, &scope),
+                rhs,
+            }))
+        }
+
+        other => map_children(other, |expr| infer(expr, e)),
+    }
+}
+
+// Collect a lint for every hand-written intension domain which does not
+// match the free dimensions of the intension body.
+pub fn lint_intension_domains(x: &Expression) -> Vec<DomainLint> {
+    let mut lints = vec![];
+    let e = Environment::new();
+    lint(x.clone(), &e, &mut lints);
+    lints
+}
+
+fn lint(x: Expression, e: &Environment, lints: &mut Vec<DomainLint>) {
+    match x {
+        Expression::IntensionBuilder(intens_expr) => {
+            if intens_expr.annotation == DomainAnnotation::Explicit {
+                let free = free_dimensions(&intens_expr.value, e, &mut vec![]);
+                let declared = declared_domain(&intens_expr);
+                let mut missing = free.difference(declared.clone());
+                let mut unused = declared.difference(free);
+                // `#` in the domain freezes every dimension, and `#` in the
+                // body uses every dimension which is frozen.
+                if intens_expr.domain.contains(&Expression::ContextQuery) {
+                    missing = Domain::new();
+                }
+                if queries_context(&intens_expr.value, e, &mut vec![]) {
+                    unused = Domain::new();
+                }
+                if !missing.0.is_empty() || !unused.0.is_empty() {
+                    lints.push(DomainLint {
+                        intension: *intens_expr.clone(),
+                        missing,
+                        unused,
+                    });
+                }
+            }
+            lint(intens_expr.value.clone(), e, lints);
+        }
+
+        Expression::WhereVar(wv) => {
+            let mut scope = e.clone();
+            scope.merge(wv.rhs.clone());
+            for def in wv.rhs.0.clone() {
+                lint(def.equation, &scope, lints);
+            }
+            lint(wv.lhs.clone(), &scope, lints);
+        }
+
+        other => {
+            map_children(other, |expr| {
+                lint(expr.clone(), e, lints);
+                expr
+            });
+        }
+    }
+}
+
+// Rebuild an expression by applying `f` to each of its direct children.
+pub fn map_children<F>(x: Expression, mut f: F) -> Expression
+where
+    F: FnMut(Expression) -> Expression,
+{
+    match x {
+        Expression::Literal(_)
+        | Expression::Dimension(_)
+        | Expression::Identifier(_)
+        | Expression::Operator(_)
+        | Expression::ContextQuery
+        | Expression::Constant(_) => x,
+
+        Expression::Sequence(exprs) => Expression::Sequence(exprs.into_iter().map(f).collect()),
+
+        Expression::Application(exprs) => {
+            Expression::Application(exprs.into_iter().map(f).collect())
+        }
+
+        Expression::TupleBuilder(tuple_expr) => Expression::TupleBuilder(
+            tuple_expr
+                .into_iter()
+                .map(|tuple| TupleExpression {
+                    lhs: f(tuple.lhs),
+                    rhs: f(tuple.rhs),
+                })
+                .collect(),
+        ),
+
+        Expression::BaseAbstraction(base_abstraction) => {
+            let base_abstraction = *base_abstraction;
+            Expression::BaseAbstraction(Box::new(BaseAbstraction {
+                dimensions: base_abstraction.dimensions,
+                body: f(base_abstraction.body),
+            }))
+        }
+
+        Expression::BaseApplication(base_application) => {
+            let base_application = *base_application;
+            let lhs = f(base_application.lhs);
+            Expression::BaseApplication(Box::new(BaseApplication {
+                lhs,
+                args: base_application.args.into_iter().map(f).collect(),
+            }))
+        }
+
+        Expression::ValueAbstraction(value_abstraction) => {
+            let value_abstraction = *value_abstraction;
+            Expression::ValueAbstraction(Box::new(ValueAbstraction {
+                dimensions: value_abstraction.dimensions,
+                body: f(value_abstraction.body),
+            }))
+        }
+
+        Expression::ValueApplication(value_application) => {
+            let value_application = *value_application;
+            let lhs = f(value_application.lhs);
+            Expression::ValueApplication(Box::new(ValueApplication {
+                lhs,
+                args: value_application.args.into_iter().map(f).collect(),
+            }))
+        }
+
+        Expression::FunctionApplication(function_application) => {
+            let function_application = *function_application;
+            let base_args = function_application
+                .base_args
+                .into_iter()
+                .map(&mut f)
+                .collect();
+            let value_args = function_application.value_args.into_iter().map(f).collect();
+            Expression::FunctionApplication(Box::new(FunctionApplication {
+                id: function_application.id,
+                base_args,
+                value_args,
+            }))
+        }
+
+        Expression::IntensionBuilder(intens_expr) => {
+            let intens_expr = *intens_expr;
+            let domain = intens_expr.domain.into_iter().map(&mut f).collect();
+            Expression::IntensionBuilder(Box::new(IntensionExpression {
+                annotation: intens_expr.annotation,
+                domain,
+                value: f(intens_expr.value),
+            }))
+        }
+
+        Expression::IntensionApplication(intens_app) => {
+            Expression::IntensionApplication(Box::new(f(*intens_app)))
+        }
+
+        Expression::If(if_expr) => {
+            let if_expr = *if_expr;
+            let condition = f(if_expr.condition);
+            let consequent = f(if_expr.consequent);
+            Expression::If(Box::new(IfExpression {
+                condition,
+                consequent,
+                alternate: f(if_expr.alternate),
+            }))
+        }
+
+        Expression::WhereVar(wv) => {
+            let wv = *wv;
+            let mut rhs = Environment::new();
+            for def in wv.rhs.0 {
+                rhs.define(def.id, f(def.equation));
+            }
+            Expression::WhereVar(Box::new(WhereVarExpression {
+                lhs: f(wv.lhs),
+                rhs,
+            }))
+        }
+
+        Expression::Query(e0) => Expression::Query(Box::new(f(*e0))),
+
+        Expression::Perturb(perturb_expr) => {
+            let perturb_expr = *perturb_expr;
+            let lhs = f(perturb_expr.lhs);
+            Expression::Perturb(Box::new(PerturbExpression {
+                lhs,
+                rhs: f(perturb_expr.rhs),
+            }))
+        }
+
+        Expression::WhereDim(wd) => {
+            let wd = *wd;
+            let lhs = f(wd.lhs);
+            let rhs = wd.rhs
+                .0
+                .into_iter()
+                .map(|dimension_expr| DimensionExpression {
+                    lhs: dimension_expr.lhs,
+                    rhs: f(dimension_expr.rhs),
+                })
+                .collect();
+            Expression::WhereDim(Box::new(WhereDimExpression {
+                nat_q: wd.nat_q,
+                dim_q: wd.dim_q,
+                lhs,
+                rhs: ContextExpression(rhs),
+            }))
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::fmt::Write;
+
+// The JSON values spoken by the worker protocol. Numbers are whole, the
+// protocol has no use for others.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, field: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => {
+                fields.iter().find(|&(f, _)| f == field).map(|(_, v)| v)
+            }
+
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<i64> {
+        match self {
+            &Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(xs) => Some(xs),
+            _ => None,
+        }
+    }
+
+    // The value on one line, as the protocol sends it.
+    pub fn print(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut String) {
+        match self {
+            &Json::Null => out.push_str("null"),
+            &Json::Bool(b) => out.push_str(if b { "true" } else { "false" }),
+            &Json::Number(n) => out.push_str(&n.to_string()),
+            Json::String(s) => write_string(s, out),
+            Json::Array(xs) => {
+                out.push('[');
+                for (i, x) in xs.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    x.write(out);
+                }
+                out.push(']');
+            }
+
+            Json::Object(fields) => {
+                out.push('{');
+                for (i, (field, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_string(field, out);
+                    out.push(':');
+                    v.write(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(out, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// How deeply arrays and objects may nest in what is parsed. Parsing recurses
+// on them and the input comes from other processes.
+pub const MAX_DEPTH: usize = 128;
+
+// Parse a JSON value, which must be all of the input but for whitespace.
+pub fn parse(s: &str) -> Result<Json, String> {
+    let mut p = Parser {
+        chars: s.chars().collect(),
+        at: 0,
+        depth: 0,
+    };
+    let v = p.value()?;
+    p.whitespace();
+    if p.at < p.chars.len() {
+        return Err(format!("unexpected {:?} at {}", p.chars[p.at], p.at));
+    }
+    Ok(v)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    at: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.at).cloned()
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self.peek().ok_or_else(|| "unexpected end of input".to_string())?;
+        self.at += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => Err(format!("expected {:?} but found {:?} at {}", expected, c, self.at - 1)),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, v: Json) -> Result<Json, String> {
+        for expected in word.chars() {
+            self.expect(expected)?;
+        }
+        Ok(v)
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(' ') | Some('\t') | Some('\n') | Some('\r') = self.peek() {
+            self.at += 1;
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.peek() {
+            Some('n') => self.keyword("null", Json::Null),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => self.nested(Parser::array),
+            Some('{') => self.nested(Parser::object),
+
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.at;
+                self.at += 1;
+                while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    self.at += 1;
+                }
+                let digits: String = self.chars[start..self.at].iter().collect();
+                digits
+                    .parse()
+                    .map(Json::Number)
+                    .map_err(|_| format!("invalid number {} at {}", digits, start))
+            }
+
+            Some(c) => Err(format!("unexpected {:?} at {}", c, self.at)),
+
+            None => Err("unexpected end of input".to_string()),
+        }
+    }
+
+    // Parse an array or an object, one level deeper.
+    fn nested<F>(&mut self, f: F) -> Result<Json, String>
+    where
+        F: FnOnce(&mut Parser) -> Result<Json, String>,
+    {
+        if self.depth == MAX_DEPTH {
+            return Err(format!("nested deeper than {} at {}", MAX_DEPTH, self.at));
+        }
+        self.depth += 1;
+        let v = f(self);
+        self.depth -= 1;
+        v
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut xs = vec![];
+        self.whitespace();
+        if self.peek() == Some(']') {
+            self.at += 1;
+            return Ok(Json::Array(xs));
+        }
+        loop {
+            xs.push(self.value()?);
+            self.whitespace();
+            match self.next()? {
+                ',' => {}
+                ']' => return Ok(Json::Array(xs)),
+                c => return Err(format!("unexpected {:?} in array at {}", c, self.at - 1)),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut fields = vec![];
+        self.whitespace();
+        if self.peek() == Some('}') {
+            self.at += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.whitespace();
+            let field = self.string()?;
+            self.whitespace();
+            self.expect(':')?;
+            fields.push((field, self.value()?));
+            self.whitespace();
+            match self.next()? {
+                ',' => {}
+                '}' => return Ok(Json::Object(fields)),
+                c => return Err(format!("unexpected {:?} in object at {}", c, self.at - 1)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(s),
+                '\\' => match self.next()? {
+                    '"' => s.push('"'),
+                    '\\' => s.push('\\'),
+                    '/' => s.push('/'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'n' => s.push('\n'),
+                    'r' => s.push('\r'),
+                    't' => s.push('\t'),
+                    'u' => {
+                        let mut code = 0;
+                        for _ in 0..4 {
+                            let digit = self.next()?
+                                .to_digit(16)
+                                .ok_or_else(|| format!("invalid escape at {}", self.at - 1))?;
+                            code = code * 16 + digit;
+                        }
+                        // Surrogate pairs are not needed by the protocol.
+                        s.push(::std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    c => return Err(format!("invalid escape {:?} at {}", c, self.at - 1)),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+}
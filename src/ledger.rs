@@ -0,0 +1,254 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use cache::Key;
+use digest::{sha256, Digest};
+use domain::Domain;
+use either::Either;
+use encoding::{decode, encode, encode_bytes, encode_u32, encode_u64, Decode, DecodeError, Decoder,
+               Encode};
+use error::LedgerError;
+use signature::Signature;
+use value::Value;
+
+// The header of a ledger, followed by the version of the format.
+const MAGIC: &[u8] = b"gaia-ledger";
+const VERSION: u32 = 1;
+
+// An insertion into a cache. Every record holds the hash of the record before
+// it, so changing, dropping or reordering a record breaks the chain after it.
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub sequence: u64,
+    pub previous: Digest,
+    pub key: Key,
+    pub v: Either<Value, Domain>,
+    pub signature: Option<Signature>,
+}
+
+impl Record {
+    pub fn hash(&self) -> Digest {
+        sha256(&encode(self))
+    }
+}
+
+impl Encode for Record {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_u64(self.sequence, out);
+        self.previous.encode(out);
+        self.key.encode(out);
+        self.v.encode(out);
+        self.signature.encode(out);
+    }
+}
+
+impl Decode for Record {
+    fn decode(r: &mut Decoder) -> Result<Record, DecodeError> {
+        let sequence = r.u64()?;
+        let previous = Digest::decode(r)?;
+        let key = Key::decode(r)?;
+        let v = Decode::decode(r)?;
+        let signature = Decode::decode(r)?;
+        Ok(Record {
+            sequence,
+            previous,
+            key,
+            v,
+            signature,
+        })
+    }
+}
+
+// The end of a chain: how many records it has and the hash of the last one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Head {
+    pub len: u64,
+    pub hash: Digest,
+}
+
+impl Head {
+    // The head of a chain without records.
+    pub fn genesis() -> Head {
+        Head {
+            len: 0,
+            hash: Digest([0; 32]),
+        }
+    }
+}
+
+// Check that the records continue the chain which ends at head, returning the
+// head after them.
+pub fn verify_chain(head: Head, records: &[Record]) -> Result<Head, LedgerError> {
+    let mut head = head;
+    for record in records {
+        if record.sequence != head.len || record.previous != head.hash {
+            return Err(LedgerError::BrokenChain(head.len));
+        }
+        head = Head {
+            len: head.len + 1,
+            hash: record.hash(),
+        };
+    }
+    Ok(head)
+}
+
+// Read the records of a ledger which must end at the expected head, e.g. the
+// head of the ledger which wrote it. The ledger is refused when it was cut
+// short, when a record does not match the hash written after it, when the
+// chain is broken, and when it ends elsewhere: cut between two records, or
+// with records rewritten along with their hashes.
+pub fn replay<P: AsRef<Path>>(path: P, expected: Head) -> Result<Vec<Record>, LedgerError> {
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let (records, head) = read_records(&bytes)?;
+    if head != expected {
+        return Err(LedgerError::UnexpectedHead(expected, head));
+    }
+    Ok(records)
+}
+
+fn read_records(bytes: &[u8]) -> Result<(Vec<Record>, Head), LedgerError> {
+    let mut r = Decoder::new(bytes);
+    if r.take(MAGIC.len()).ok() != Some(MAGIC) {
+        return Err(LedgerError::Format(DecodeError("not a ledger".to_string())));
+    }
+    let version = r.u32()?;
+    if version != VERSION {
+        let unsupported = format!("unsupported version {}", version);
+        return Err(LedgerError::Format(DecodeError(unsupported)));
+    }
+    let mut records = vec![];
+    let mut head = Head::genesis();
+    while !r.is_empty() {
+        let n = head.len;
+        let record_bytes = r.bytes().map_err(|_| LedgerError::Truncated(n))?;
+        let hash = Digest::decode(&mut r).map_err(|_| LedgerError::Truncated(n))?;
+        if sha256(record_bytes) != hash {
+            return Err(LedgerError::Tampered(n));
+        }
+        let record: Record = decode(record_bytes)?;
+        head = verify_chain(head, ::std::slice::from_ref(&record))?;
+        records.push(record);
+    }
+    Ok((records, head))
+}
+
+// A hash-chained log of the insertions into a cache, which is only ever
+// appended to. It rebuilds the cache when it is replayed, and its records can
+// be shipped to peers as they are written, who check that they continue the
+// chain they already have.
+#[derive(Debug)]
+pub struct Ledger {
+    pub path: PathBuf,
+    file: File,
+    head: Head,
+    // The first write which failed. Nothing is appended after it, a record
+    // missing from the file would break the chain of the ones after it.
+    pub error: Option<io::Error>,
+}
+
+impl Ledger {
+    // Start a new ledger, replacing any file at the path.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Ledger> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let mut header = MAGIC.to_vec();
+        encode_u32(VERSION, &mut header);
+        file.write_all(&header)?;
+        Ok(Ledger {
+            path,
+            file,
+            head: Head::genesis(),
+            error: None,
+        })
+    }
+
+    // Continue the ledger at the path, which must be intact.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Ledger, LedgerError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+        let (_, head) = read_records(&bytes)?;
+        Ok(Ledger {
+            path,
+            file,
+            head,
+            error: None,
+        })
+    }
+
+    pub fn head(&self) -> Head {
+        self.head
+    }
+
+    // Append an insertion, returning the head after it.
+    pub fn append(
+        &mut self,
+        key: Key,
+        v: Either<Value, Domain>,
+        signature: Option<Signature>,
+    ) -> io::Result<Head> {
+        let record = Record {
+            sequence: self.head.len,
+            previous: self.head.hash,
+            key,
+            v,
+            signature,
+        };
+        self.write(&record)
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<Head> {
+        if let Some(ref err) = self.error {
+            return Err(io::Error::new(err.kind(), "an earlier write to the ledger failed"));
+        }
+        let bytes = encode(record);
+        let hash = sha256(&bytes);
+        let mut frame = vec![];
+        encode_bytes(&bytes, &mut frame);
+        hash.encode(&mut frame);
+        let written = self.file
+            .seek(SeekFrom::End(0))
+            .and_then(|_| self.file.write_all(&frame));
+        if let Err(err) = written {
+            let kind = err.kind();
+            self.error = Some(err);
+            return Err(io::Error::new(kind, "cannot append to the ledger"));
+        }
+        self.head = Head {
+            len: self.head.len + 1,
+            hash,
+        };
+        Ok(self.head)
+    }
+
+    // Append the records of a peer, which must continue this ledger.
+    pub fn extend(&mut self, records: &[Record]) -> Result<Head, LedgerError> {
+        verify_chain(self.head, records)?;
+        for record in records {
+            self.write(record)?;
+        }
+        Ok(self.head)
+    }
+
+    // The records from the given sequence number on, to ship to a peer which
+    // has the ones before.
+    pub fn records_since(&mut self, sequence: u64) -> Result<Vec<Record>, LedgerError> {
+        let mut bytes = vec![];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut bytes)?;
+        let (records, _) = read_records(&bytes)?;
+        Ok(records.into_iter().skip(sequence as usize).collect())
+    }
+
+    // Wait until the ledger is on disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
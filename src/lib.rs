@@ -1,4 +1,6 @@
 extern crate colored;
+extern crate ed25519_dalek;
+extern crate sha2;
 
 use colored::*;
 
@@ -11,20 +13,138 @@ pub mod domain;
 pub mod value;
 pub mod tuple;
 pub mod either;
+pub mod inference;
+pub mod residual;
+pub mod error;
+pub mod limits;
+pub mod gas;
+pub mod cancel;
+pub mod retirement;
+pub mod provider;
+pub mod backend;
+pub mod encoding;
+pub mod digest;
+pub mod ed25519;
+pub mod runtime;
+pub mod node;
+pub mod rng;
+pub mod simulator;
+pub mod signature;
+pub mod merkle;
+pub mod audit;
+pub mod merge;
+pub mod ledger;
+pub mod shipping;
+pub mod json;
+pub mod worker;
 
 use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
 use expression::*;
 use environment::*;
 use cache::Cache;
 use context::Context;
-use value::{print_dimension, Dimension, Value};
+use value::{print_dimension, print_value, Dimension, Value};
 use domain::Domain;
 use either::Either;
+use inference::DomainLint;
+use residual::Residual;
+use provider::{ContextProvider, NoProvider};
+use error::{EvalError, LoadError};
+use limits::{EvalLimits, EvalStats, Meter};
 
 type Identifier = String;
 
 pub fn evaluate(expr: L1Expression) -> Either<Value, Domain> {
+    match try_evaluate(expr) {
+        Ok(v) => v,
+        Err(err) => panic!("{}", err.print()),
+    }
+}
+
+// Evaluate a program, returning an error instead of panicking when the
+// program cannot be evaluated, e.g. because of a cyclic definition.
+pub fn try_evaluate(expr: L1Expression) -> Result<Either<Value, Domain>, EvalError> {
+    evaluate_with_provider(expr, &mut NoProvider)
+}
+
+// Evaluate a program, asking the provider for the ordinates of dimensions
+// which the program demands but does not define.
+pub fn evaluate_with_provider(
+    expr: L1Expression,
+    p: &mut dyn ContextProvider,
+) -> Result<Either<Value, Domain>, EvalError> {
+    let mut c = Cache::new();
+    evaluate_with_meter(expr, &mut c, p, &mut Meter::unlimited())
+}
+
+// Evaluate a program within the given limits. The statistics are returned
+// with the result, or with the error when a limit is exceeded.
+pub fn evaluate_with_limits(
+    expr: L1Expression,
+    limits: EvalLimits,
+) -> Result<(Either<Value, Domain>, EvalStats), EvalError> {
     let mut c = Cache::new();
+    let mut m = Meter::new(limits);
+    let v = evaluate_with_meter(expr, &mut c, &mut NoProvider, &mut m)?;
+    Ok((v, m.stats()))
+}
+
+// Evaluate a program with the given cache, whose backend may already hold
+// entries from earlier evaluations of the same program.
+pub fn evaluate_with_cache(expr: L1Expression, c: &mut Cache) -> Result<Either<Value, Domain>, EvalError> {
+    evaluate_with_meter(expr, c, &mut NoProvider, &mut Meter::unlimited())
+}
+
+// Save the extension of a program, the entries of its cache, so that a later
+// run of the same program can start from them.
+pub fn save_cache<P: AsRef<Path>>(expr: L1Expression, c: &Cache, path: P) -> io::Result<()> {
+    let (x, _, _) = compile(expr);
+    c.save(path, &x)
+}
+
+// Load the extension saved for a program, refusing it when the program was
+// changed since.
+pub fn load_cache<P: AsRef<Path>>(expr: L1Expression, path: P) -> Result<Cache, LoadError> {
+    let (x, _, _) = compile(expr);
+    let mut c = Cache::new();
+    c.load(path, &x)?;
+    Ok(c)
+}
+
+// Evaluate a program with a cache which outlives the evaluation and a meter
+// which bounds it and may carry a cancellation token. The cache only holds
+// completed demands, so it stays valid when the evaluation is stopped.
+pub fn evaluate_with_meter(
+    expr: L1Expression,
+    c: &mut Cache,
+    p: &mut dyn ContextProvider,
+    m: &mut Meter,
+) -> Result<Either<Value, Domain>, EvalError> {
+    let mut e = primitive_environment();
+    let (x, k, d) = compile(expr);
+    evaluator::evaluate(x, &mut e, k.clone(), d.clone(), d.clone(), c, p, m)
+}
+
+// Evaluate a program as far as possible. When dimensions are missing the
+// result is a residual intension which closes over everything computed so
+// far, the cache retains the extension for when the residual is completed.
+pub fn evaluate_partial(expr: L1Expression, c: &mut Cache) -> Result<Either<Value, Residual>, EvalError> {
+    let e = primitive_environment();
+    let (x, k, d) = compile(expr);
+    residual::evaluate_residual(x, &e, k.clone(), d.clone(), d.clone(), c, &mut NoProvider, &mut Meter::unlimited())
+}
+
+// Complete a residual at a context which supplies (some of) its missing
+// dimensions.
+pub fn complete(r: Residual, k: Context, c: &mut Cache) -> Result<Either<Value, Residual>, EvalError> {
+    let e = primitive_environment();
+    residual::complete(r, &e, k, c, &mut NoProvider, &mut Meter::unlimited())
+}
+
+// The environment of primitive operators which every program starts from.
+pub fn primitive_environment() -> Environment {
     let mut e = Environment::new();
 
     // Define VM opcodes
@@ -47,20 +167,44 @@ pub fn evaluate(expr: L1Expression) -> Either<Value, Domain> {
     e.define("<=".to_string(), Expression::Operator("<=".to_string()));
     e.define(">".to_string(), Expression::Operator(">".to_string()));
     e.define(">=".to_string(), Expression::Operator(">=".to_string()));
+    // Context Union
+    //   K1 ++ K2
+    e.define("++".to_string(), Expression::Operator("++".to_string()));
+    // Context Restriction
+    //   K <| D
+    e.define("<|".to_string(), Expression::Operator("<|".to_string()));
+    // Context Domain
+    //   dom!K
+    e.define("dom".to_string(), Expression::Operator("dom".to_string()));
+
+    e
+}
 
+// Transform a parsed program into an evaluatable expression together with
+// the initial context and domain holding the wheredim dimensions.
+pub fn compile(expr: L1Expression) -> (Expression, Context, Domain) {
     let mut k = Context::new();
     let mut d = Domain::new();
     let mut dimensions = HashMap::new();
     let mut names = HashSet::new();
     let q_dimensions = HashSet::new();
     let (x, q_dims) = transform_l1_dimensions(expr, &mut dimensions, &mut names, 0, q_dimensions);
+    let x = inference::infer_intension_domains(x);
     for q_dim in q_dims.clone() {
         k.push(q_dim.clone(), Value::Literal(Literal::Int32(0)));
         d.push(q_dim);
     }
-    // println!("K :: {}", k.clone().print());
-    // println!("D :: {}\n", d.clone().print());
-    evaluator::evaluate(x, &mut e, k.clone(), d.clone(), d.clone(), &mut c)
+    (x, k, d)
+}
+
+// Report the hand-written intension domains which do not match the free
+// dimensions of their bodies.
+pub fn lint(expr: L1Expression) -> Vec<DomainLint> {
+    let mut dimensions = HashMap::new();
+    let mut names = HashSet::new();
+    let q_dimensions = HashSet::new();
+    let (x, _) = transform_l1_dimensions(expr, &mut dimensions, &mut names, 0, q_dimensions);
+    inference::lint_intension_domains(&x)
 }
 
 pub fn generate_dimensional_map(
@@ -504,7 +648,11 @@ pub fn transform_l1_dimensions(
         L1Expression::WhereVar(wv) => {
             let mut e = Environment::new();
             let mut q_dimensions = q_dimensions;
-            for (id, expr) in wv.rhs.0.clone() {
+            // In the order of the identifiers, so that a program compiles to
+            // the same expression in every process.
+            let mut definitions: Vec<_> = wv.rhs.0.clone().into_iter().collect();
+            definitions.sort_by(|a, b| a.0.cmp(&b.0));
+            for (id, expr) in definitions {
                 let (expr, q_dims) =
                     transform_l1_dimensions(expr, dimensions, names, q, q_dimensions);
                 q_dimensions = q_dims;
@@ -528,6 +676,8 @@ pub fn transform_l1_dimensions(
             (Expression::Query(Box::new(expr)), q_dims)
         }
 
+        L1Expression::ContextQuery => (Expression::ContextQuery, q_dimensions),
+
         L1Expression::Perturb(perturb_expr) => {
             let lhs = perturb_expr.clone().lhs;
             let rhs = perturb_expr.rhs;
@@ -581,6 +731,7 @@ pub fn transform_l1_dimensions(
                     q_dimensions.clone(),
                 );
                 let intension = IntensionExpression {
+                    annotation: DomainAnnotation::Generated,
                     domain: vec![],
                     value: value_arg.clone(),
                 };
@@ -800,6 +951,7 @@ pub fn transform_l1_dimensions(
                             q_dimensions.clone(),
                         );
                         let intension = IntensionExpression {
+                            annotation: DomainAnnotation::Generated,
                             domain: vec![],
                             value: arg.clone(),
                         };
@@ -856,6 +1008,7 @@ pub fn transform_l1_dimensions(
                 q_domain.clone(),
             );
             let intens_expr = IntensionExpression {
+                annotation: intens_expr.annotation.clone(),
                 domain: r.clone(),
                 value: e0,
             };
@@ -1081,6 +1234,10 @@ pub fn print_expression(expr: Expression, indent: u32) -> String {
             format!("#.{}", print_expression(e0, indent))
         }
 
+        Expression::ContextQuery => format!("{}", "#".bright_white()),
+
+        Expression::Constant(v) => print_value(*v),
+
         Expression::Perturb(perturb_expr) => {
             let lhs = print_expression(perturb_expr.clone().lhs, indent);
             let rhs = print_expression(perturb_expr.rhs, indent);
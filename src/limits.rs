@@ -0,0 +1,229 @@
+use std::time::{Duration, Instant};
+
+use cancel::CancellationToken;
+use error::EvalError;
+use expression::Expression;
+use gas::CostModel;
+
+// Bounds on the resources used by an evaluation, a limit of None is
+// unbounded.
+#[derive(Clone, Debug, Default)]
+pub struct EvalLimits {
+    // The number of nested identifier demands.
+    pub max_depth: Option<usize>,
+    // The number of entries in the cache.
+    pub max_cache_entries: Option<usize>,
+    // The number of evaluation steps, one per expression evaluated.
+    pub max_fuel: Option<u64>,
+    // The gas the evaluation may be charged by the cost model of its meter.
+    pub max_gas: Option<u64>,
+    // The wall-clock time by which the evaluation must be done.
+    pub deadline: Option<Instant>,
+}
+
+impl EvalLimits {
+    pub fn unlimited() -> EvalLimits {
+        EvalLimits::default()
+    }
+
+    pub fn max_depth(mut self, max_depth: usize) -> EvalLimits {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn max_cache_entries(mut self, max_cache_entries: usize) -> EvalLimits {
+        self.max_cache_entries = Some(max_cache_entries);
+        self
+    }
+
+    pub fn max_fuel(mut self, max_fuel: u64) -> EvalLimits {
+        self.max_fuel = Some(max_fuel);
+        self
+    }
+
+    pub fn max_gas(mut self, max_gas: u64) -> EvalLimits {
+        self.max_gas = Some(max_gas);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> EvalLimits {
+        self.deadline = Some(Instant::now() + timeout);
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Depth,
+    CacheEntries,
+    Fuel,
+    Gas,
+    Deadline,
+}
+
+impl Limit {
+    pub fn print(&self) -> String {
+        match *self {
+            Limit::Depth => "demand depth".to_string(),
+            Limit::CacheEntries => "cache entries".to_string(),
+            Limit::Fuel => "fuel".to_string(),
+            Limit::Gas => "gas".to_string(),
+            Limit::Deadline => "deadline".to_string(),
+        }
+    }
+}
+
+// What an evaluation has used so far.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EvalStats {
+    pub steps: u64,
+    pub demands: u64,
+    pub max_depth: usize,
+    pub cache_entries: usize,
+    pub gas: u64,
+    pub elapsed: Duration,
+}
+
+impl EvalStats {
+    pub fn print(&self) -> String {
+        format!(
+            "{} steps, {} demands, depth {}, {} cache entries, {} gas, {:?}",
+            self.steps,
+            self.demands,
+            self.max_depth,
+            self.cache_entries,
+            self.gas,
+            self.elapsed
+        )
+    }
+}
+
+// The deadline is checked on every demand but only every so many steps, since
+// reading the clock costs more than a step.
+const DEADLINE_INTERVAL: u64 = 1024;
+
+// Counts the resources used by an evaluation and stops it once it goes over
+// its limits or is cancelled.
+#[derive(Clone, Debug)]
+pub struct Meter {
+    pub limits: EvalLimits,
+    pub stats: EvalStats,
+    pub token: Option<CancellationToken>,
+    pub costs: CostModel,
+    started: Instant,
+}
+
+impl Meter {
+    pub fn new(limits: EvalLimits) -> Meter {
+        Meter {
+            limits,
+            stats: EvalStats::default(),
+            token: None,
+            costs: CostModel::default(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn with_token(mut self, token: CancellationToken) -> Meter {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn with_costs(mut self, costs: CostModel) -> Meter {
+        self.costs = costs;
+        self
+    }
+
+    pub fn unlimited() -> Meter {
+        Meter::new(EvalLimits::unlimited())
+    }
+
+    // The statistics so far.
+    pub fn stats(&self) -> EvalStats {
+        let mut stats = self.stats.clone();
+        stats.elapsed = self.started.elapsed();
+        stats
+    }
+
+    fn exceeded(&self, limit: Limit) -> Result<(), EvalError> {
+        Err(EvalError::LimitExceeded(limit, self.stats()))
+    }
+
+    // Account for the evaluation of one expression.
+    pub fn step(&mut self, x: &Expression) -> Result<(), EvalError> {
+        self.stats.steps += 1;
+        if let Some(ref token) = self.token {
+            if token.is_cancelled() {
+                return Err(EvalError::Cancelled(self.stats()));
+            }
+        }
+        if let Some(max_fuel) = self.limits.max_fuel {
+            if self.stats.steps > max_fuel {
+                return self.exceeded(Limit::Fuel);
+            }
+        }
+        let gas = self.costs.expression_cost(x);
+        self.charge(gas)?;
+        if self.stats.steps.is_multiple_of(DEADLINE_INTERVAL) {
+            return self.check_deadline();
+        }
+        Ok(())
+    }
+
+    // Account for the application of a primitive operator.
+    pub fn primitive(&mut self, op: &str) -> Result<(), EvalError> {
+        let gas = self.costs.primitive_cost(op);
+        self.charge(gas)
+    }
+
+    fn charge(&mut self, gas: u64) -> Result<(), EvalError> {
+        self.stats.gas = self.stats.gas.saturating_add(gas);
+        match self.limits.max_gas {
+            Some(max_gas) if self.stats.gas > max_gas => self.exceeded(Limit::Gas),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_deadline(&self) -> Result<(), EvalError> {
+        match self.limits.deadline {
+            Some(deadline) if Instant::now() >= deadline => self.exceeded(Limit::Deadline),
+            _ => Ok(()),
+        }
+    }
+
+    // Account for a demand for an identifier which missed the cache, at the
+    // given depth of nested demands.
+    pub fn demand(&mut self, depth: usize) -> Result<(), EvalError> {
+        self.stats.demands += 1;
+        let gas = self.costs.cache_miss_cost();
+        self.charge(gas)?;
+        if depth > self.stats.max_depth {
+            self.stats.max_depth = depth;
+        }
+        match self.limits.max_depth {
+            Some(max_depth) if depth > max_depth => self.exceeded(Limit::Depth),
+            _ => self.check_deadline(),
+        }
+    }
+
+    // Account for a demand which was found in the cache, charged the gas its
+    // entry was charged when it was computed, or a miss when that is unknown.
+    pub fn hit(&mut self, gas: Option<u64>) -> Result<(), EvalError> {
+        let gas = gas.unwrap_or_else(|| self.costs.cache_miss_cost());
+        self.charge(gas)
+    }
+
+    // Account for the number of entries the cache would hold after an
+    // insertion, which is refused when it goes over the limit.
+    pub fn cached(&mut self, entries: usize) -> Result<(), EvalError> {
+        match self.limits.max_cache_entries {
+            Some(max_cache_entries) if entries > max_cache_entries => {
+                self.exceeded(Limit::CacheEntries)
+            }
+            _ => {
+                self.stats.cache_entries = entries;
+                Ok(())
+            }
+        }
+    }
+}
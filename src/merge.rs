@@ -0,0 +1,112 @@
+use cache::Key;
+use domain::Domain;
+use either::Either;
+use error::SignatureError;
+use value::Value;
+
+// What to do when two caches hold different values for the same key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    KeepOurs,
+    TakeTheirs,
+    // Remove the entry, it is recomputed when it is demanded again.
+    Recompute,
+}
+
+// Decides how the conflicts of a merge are resolved. Evaluation is
+// deterministic, so a conflict means a bug or a dishonest peer and a policy
+// gets to see every one of them.
+pub trait MergePolicy {
+    fn resolve(
+        &mut self,
+        key: &Key,
+        ours: &Either<Value, Domain>,
+        theirs: &Either<Value, Domain>,
+    ) -> Resolution;
+}
+
+impl<F> MergePolicy for F
+where
+    F: FnMut(&Key, &Either<Value, Domain>, &Either<Value, Domain>) -> Resolution,
+{
+    fn resolve(
+        &mut self,
+        key: &Key,
+        ours: &Either<Value, Domain>,
+        theirs: &Either<Value, Domain>,
+    ) -> Resolution {
+        self(key, ours, theirs)
+    }
+}
+
+// Keep the entries this cache computed, the policy of Cache::merge.
+#[derive(Clone, Debug)]
+pub struct KeepOurs;
+
+impl MergePolicy for KeepOurs {
+    fn resolve(
+        &mut self,
+        _: &Key,
+        _: &Either<Value, Domain>,
+        _: &Either<Value, Domain>,
+    ) -> Resolution {
+        Resolution::KeepOurs
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TakeTheirs;
+
+impl MergePolicy for TakeTheirs {
+    fn resolve(
+        &mut self,
+        _: &Key,
+        _: &Either<Value, Domain>,
+        _: &Either<Value, Domain>,
+    ) -> Resolution {
+        Resolution::TakeTheirs
+    }
+}
+
+// Trust neither side and compute the entry again.
+#[derive(Clone, Debug)]
+pub struct Recompute;
+
+impl MergePolicy for Recompute {
+    fn resolve(
+        &mut self,
+        _: &Key,
+        _: &Either<Value, Domain>,
+        _: &Either<Value, Domain>,
+    ) -> Resolution {
+        Resolution::Recompute
+    }
+}
+
+// Two values for the same key and how the conflict was resolved.
+#[derive(Clone, Debug)]
+pub struct Conflict {
+    pub key: Key,
+    pub ours: Either<Value, Domain>,
+    pub theirs: Either<Value, Domain>,
+    pub resolution: Resolution,
+}
+
+// What a merge found, with the keys in the order of their digests.
+#[derive(Clone, Debug, Default)]
+pub struct MergeReport {
+    // The entries which were only in the other cache.
+    pub added: Vec<Key>,
+    // The entries which both caches hold with the same value.
+    pub identical: Vec<Key>,
+    pub conflicts: Vec<Conflict>,
+    // The entries which failed the checks of the keystore.
+    pub rejected: Vec<(Key, SignatureError)>,
+}
+
+impl MergeReport {
+    // Whether the caches agreed on every key they share.
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty() && self.rejected.is_empty()
+    }
+}
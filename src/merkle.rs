@@ -0,0 +1,151 @@
+use cache::{Cache, Key};
+use digest::{sha256, Digest};
+use domain::Domain;
+use either::Either;
+use encoding::{encode_u32, Decode, DecodeError, Decoder, Encode};
+use value::Value;
+
+// A Merkle tree over the entries of a cache, ordered by the digests of their
+// keys so that nodes which computed the same extension commit to the same
+// root whatever order they computed it in. Leaves and inner nodes are hashed
+// with different prefixes so that one cannot pass for the other, and a node
+// without a sibling moves up a level as it is.
+#[derive(Clone, Debug)]
+pub struct MerkleTree {
+    // The digests of the keys in order, and the levels of the tree from the
+    // leaves up to the root.
+    keys: Vec<Digest>,
+    levels: Vec<Vec<Digest>>,
+}
+
+// A step from a node up to its parent, with the sibling on the left or on the
+// right of the node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    Left(Digest),
+    Right(Digest),
+}
+
+// The siblings on the path from a leaf to the root.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Proof(pub Vec<Step>);
+
+pub fn leaf(key: &Key, v: &Either<Value, Domain>) -> Digest {
+    let mut bytes = vec![0];
+    key.encode(&mut bytes);
+    v.encode(&mut bytes);
+    sha256(&bytes)
+}
+
+fn node(left: &Digest, right: &Digest) -> Digest {
+    let mut bytes = vec![1];
+    bytes.extend_from_slice(&left.0);
+    bytes.extend_from_slice(&right.0);
+    sha256(&bytes)
+}
+
+impl MerkleTree {
+    pub fn new<I: Iterator<Item = (Key, Either<Value, Domain>)>>(entries: I) -> MerkleTree {
+        let mut leaves: Vec<(Digest, Digest)> =
+            entries.map(|(key, v)| (key.digest, leaf(&key, &v))).collect();
+        leaves.sort();
+        leaves.dedup_by_key(|&mut (key, _)| key);
+        let keys = leaves.iter().map(|&(key, _)| key).collect();
+        let mut levels = vec![leaves.into_iter().map(|(_, leaf)| leaf).collect::<Vec<_>>()];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| if pair.len() == 2 { node(&pair[0], &pair[1]) } else { pair[0] })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree {
+            keys,
+            levels,
+        }
+    }
+
+    // The tree over the entries of a cache. A cache which starts empty holds
+    // the extension of the run after it.
+    pub fn of(c: &Cache) -> MerkleTree {
+        MerkleTree::new(c.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    // The root which commits to every entry, the digest of nothing for an
+    // empty tree.
+    pub fn root(&self) -> Digest {
+        match self.levels.last().unwrap().first() {
+            Some(root) => *root,
+            None => sha256(&[]),
+        }
+    }
+
+    // The proof that the entry for the key is in the tree.
+    pub fn prove(&self, key: &Key) -> Option<Proof> {
+        let mut i = self.keys.binary_search(&key.digest).ok()?;
+        let mut steps = vec![];
+        for level in &self.levels[..self.levels.len() - 1] {
+            if i % 2 == 1 {
+                steps.push(Step::Left(level[i - 1]));
+            } else if i + 1 < level.len() {
+                steps.push(Step::Right(level[i + 1]));
+            }
+            i /= 2;
+        }
+        Some(Proof(steps))
+    }
+}
+
+// Whether the proof shows that the entry is in the tree with the root.
+pub fn verify(root: &Digest, key: &Key, v: &Either<Value, Domain>, proof: &Proof) -> bool {
+    let top = proof.0.iter().fold(leaf(key, v), |digest, step| match *step {
+        Step::Left(ref sibling) => node(sibling, &digest),
+        Step::Right(ref sibling) => node(&digest, sibling),
+    });
+    top == *root
+}
+
+impl Encode for Proof {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_u32(self.0.len() as u32, out);
+        for step in &self.0 {
+            match *step {
+                Step::Left(ref sibling) => {
+                    out.push(0);
+                    sibling.encode(out);
+                }
+
+                Step::Right(ref sibling) => {
+                    out.push(1);
+                    sibling.encode(out);
+                }
+            }
+        }
+    }
+}
+
+impl Decode for Proof {
+    fn decode(r: &mut Decoder) -> Result<Proof, DecodeError> {
+        let n = r.u32()?;
+        let mut steps = vec![];
+        for _ in 0..n {
+            let step = match r.u8()? {
+                0 => Step::Left(Digest::decode(r)?),
+                1 => Step::Right(Digest::decode(r)?),
+                tag => return Err(DecodeError(format!("invalid step tag {}", tag))),
+            };
+            steps.push(step);
+        }
+        Ok(Proof(steps))
+    }
+}
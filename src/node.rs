@@ -0,0 +1,451 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use backend::{CacheBackend, ShardedBackend};
+use cache::Key;
+use digest::Digest;
+use domain::Domain;
+use either::Either;
+use encoding::{decode_untrusted, encode, encode_u32, Decode, DecodeError, Decoder, Encode};
+use error::SignatureError;
+use signature::{self, Keystore, Signature};
+use value::Value;
+
+// The protocol spoken between gaia nodes. Every message is framed by its
+// length and written in the binary encoding, a request is answered by exactly
+// one response on the same connection. Entries travel with the signature of
+// the node which computed them, if it signs its entries. A node only takes
+// the entries signed by a key it trusts, and refuses the others.
+#[derive(Clone, Debug)]
+pub enum Request {
+    Find(Key),
+    Add(Key, Either<Value, Domain>, Option<Signature>),
+}
+
+#[derive(Clone, Debug)]
+pub enum Response {
+    Found(Option<(Either<Value, Domain>, Option<Signature>)>),
+    Added,
+    Refused,
+}
+
+impl Encode for Request {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Request::Find(ref key) => {
+                out.push(0);
+                key.encode(out);
+            }
+
+            Request::Add(ref key, ref v, ref signature) => {
+                out.push(1);
+                key.encode(out);
+                v.encode(out);
+                signature.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for Request {
+    fn decode(r: &mut Decoder) -> Result<Request, DecodeError> {
+        match r.u8()? {
+            0 => Ok(Request::Find(Key::decode(r)?)),
+            1 => {
+                let key = Key::decode(r)?;
+                let v = Decode::decode(r)?;
+                let signature = Decode::decode(r)?;
+                Ok(Request::Add(key, v, signature))
+            }
+            tag => Err(DecodeError(format!("invalid request tag {}", tag))),
+        }
+    }
+}
+
+impl Encode for Response {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Response::Found(None) => out.push(0),
+
+            Response::Found(Some((ref v, ref signature))) => {
+                out.push(1);
+                v.encode(out);
+                signature.encode(out);
+            }
+
+            Response::Added => out.push(2),
+
+            Response::Refused => out.push(3),
+        }
+    }
+}
+
+impl Decode for Response {
+    fn decode(r: &mut Decoder) -> Result<Response, DecodeError> {
+        match r.u8()? {
+            0 => Ok(Response::Found(None)),
+            1 => {
+                let v = Decode::decode(r)?;
+                let signature = Decode::decode(r)?;
+                Ok(Response::Found(Some((v, signature))))
+            }
+            2 => Ok(Response::Added),
+            3 => Ok(Response::Refused),
+            tag => Err(DecodeError(format!("invalid response tag {}", tag))),
+        }
+    }
+}
+
+// The longest message a node reads, which keeps a peer from making it
+// allocate what the length of a frame claims.
+pub const MAX_MESSAGE: u32 = 16 << 20;
+
+// How many connections a node serves at once, a connection beyond them is
+// closed as soon as it is accepted. A connection on which the peer neither
+// sends nor takes anything for the timeout is closed as well, the peer opens
+// another one when it needs it.
+pub const MAX_CONNECTIONS: usize = 64;
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn invalid(err: DecodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.0)
+}
+
+fn too_long(len: usize) -> io::Error {
+    let message = format!("message of {} bytes is longer than {}", len, MAX_MESSAGE);
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn write_message<T: Encode, W: Write>(w: &mut W, message: &T) -> io::Result<()> {
+    let bytes = encode(message);
+    if bytes.len() > MAX_MESSAGE as usize {
+        return Err(too_long(bytes.len()));
+    }
+    let mut frame = Vec::with_capacity(bytes.len() + 4);
+    encode_u32(bytes.len() as u32, &mut frame);
+    frame.extend(bytes);
+    w.write_all(&frame)?;
+    w.flush()
+}
+
+pub fn read_message<T: Decode, R: Read>(r: &mut R) -> io::Result<T> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = Decoder::new(&len).u32().map_err(invalid)?;
+    if len > MAX_MESSAGE {
+        return Err(too_long(len as usize));
+    }
+    let mut bytes = vec![0; len as usize];
+    r.read_exact(&mut bytes)?;
+    decode_untrusted(&bytes).map_err(invalid)
+}
+
+type Signatures = Arc<Mutex<HashMap<Digest, Signature>>>;
+
+type SharedKeystore = Arc<dyn Keystore + Send + Sync>;
+
+// A node serving the entries of its backend to its peers, which find entries
+// there and add the entries they compute. The node only takes the entries
+// signed by the keys its keystore trusts. It passes the signatures on, it is
+// up to the peers to check them again against whom they trust.
+#[derive(Debug)]
+pub struct Node {
+    pub addr: SocketAddr,
+    pub backend: ShardedBackend,
+    signatures: Signatures,
+    stopped: Arc<AtomicBool>,
+    accept: Option<thread::JoinHandle<()>>,
+}
+
+impl Node {
+    // Serve the backend at the address, port 0 picks a free port. The
+    // keystore decides whose entries are taken.
+    pub fn bind<A, K>(addr: A, backend: ShardedBackend, keystore: K) -> io::Result<Node>
+    where
+        A: ToSocketAddrs,
+        K: Keystore + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let signatures = Arc::new(Mutex::new(HashMap::new()));
+        let keystore: SharedKeystore = Arc::new(keystore);
+        let open = Arc::new(AtomicUsize::new(0));
+        let accept = {
+            let backend = backend.clone();
+            let signatures = signatures.clone();
+            let keystore = keystore.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                            open.fetch_sub(1, Ordering::SeqCst);
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                        let backend = backend.clone();
+                        let signatures = signatures.clone();
+                        let keystore = keystore.clone();
+                        let open = open.clone();
+                        thread::spawn(move || {
+                            serve(stream, backend, signatures, keystore);
+                            open.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    }
+                }
+            })
+        };
+        Ok(Node {
+            addr,
+            backend,
+            signatures,
+            stopped,
+            accept: Some(accept),
+        })
+    }
+
+    // The signature of an entry which the node serves, if it was signed.
+    pub fn signature(&self, key: &Key) -> Option<Signature> {
+        self.signatures.lock().unwrap().get(&key.digest).cloned()
+    }
+
+    // Stop accepting connections, the ones already open are served until
+    // their peers close them.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the accepting thread.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(accept) = self.accept.take() {
+            let _ = accept.join();
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if self.accept.is_some() {
+            self.stop();
+        }
+    }
+}
+
+// Whether a node takes an entry: it must be signed, by a key it trusts.
+fn authorize(
+    keystore: &dyn Keystore,
+    key: &Key,
+    v: &Either<Value, Domain>,
+    signature: Option<&Signature>,
+) -> Result<(), SignatureError> {
+    match signature {
+        Some(_) => signature::check(Some(keystore), key, v, signature),
+        None => Err(SignatureError::Missing),
+    }
+}
+
+fn serve(
+    mut stream: TcpStream,
+    mut backend: ShardedBackend,
+    signatures: Signatures,
+    keystore: SharedKeystore,
+) {
+    let _ = stream.set_nodelay(true);
+    let _ = stream.set_read_timeout(Some(CONNECTION_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(CONNECTION_TIMEOUT));
+    loop {
+        let response = match read_message(&mut stream) {
+            Ok(Request::Find(key)) => {
+                let signature = signatures.lock().unwrap().get(&key.digest).cloned();
+                Response::Found(backend.find(&key).map(|v| (v, signature)))
+            }
+
+            Ok(Request::Add(ref key, ref v, signature))
+                if authorize(&*keystore, key, v, signature.as_ref()).is_err() =>
+            {
+                Response::Refused
+            }
+
+            Ok(Request::Add(key, v, signature)) => {
+                {
+                    let mut signatures = signatures.lock().unwrap();
+                    match signature {
+                        Some(signature) => signatures.insert(key.digest, signature),
+                        None => signatures.remove(&key.digest),
+                    };
+                }
+                backend.add(key, v);
+                Response::Added
+            }
+
+            // The peer closed the connection or does not speak gaia.
+            Err(_) => break,
+        };
+        if write_message(&mut stream, &response).is_err() {
+            break;
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+// A connection to a peer which is opened when it is first needed and again
+// after it failed. A peer which failed is left alone for a timeout.
+#[derive(Debug)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub timeout: Duration,
+    stream: Option<TcpStream>,
+    down_until: Option<Instant>,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr) -> Peer {
+        Peer {
+            addr,
+            timeout: Duration::from_secs(1),
+            stream: None,
+            down_until: None,
+        }
+    }
+
+    pub fn request(&mut self, request: &Request) -> io::Result<Response> {
+        if let Some(until) = self.down_until {
+            if Instant::now() < until {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "peer is down"));
+            }
+        }
+        let response = self.exchange(request);
+        if response.is_err() {
+            self.stream = None;
+            self.down_until = Some(Instant::now() + self.timeout);
+        } else {
+            self.down_until = None;
+        }
+        response
+    }
+
+    // A connection kept open from an earlier request may have been closed by
+    // the node since, the request is then sent again on a new one.
+    fn exchange(&mut self, request: &Request) -> io::Result<Response> {
+        if self.stream.is_some() {
+            if let Ok(response) = self.send(request) {
+                return Ok(response);
+            }
+            self.stream = None;
+        }
+        self.send(request)
+    }
+
+    fn send(&mut self, request: &Request) -> io::Result<Response> {
+        if self.stream.is_none() {
+            let stream = TcpStream::connect_timeout(&self.addr, self.timeout)?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            stream.set_nodelay(true)?;
+            self.stream = Some(stream);
+        }
+        let stream = self.stream.as_mut().unwrap();
+        write_message(stream, request)?;
+        read_message(stream)
+    }
+}
+
+// A backend which asks its peers for the entries it is missing before they
+// are computed, and publishes the entries which are added to it. A peer which
+// cannot be reached is skipped, the entry is then computed locally. With a
+// keystore the entries published are signed and the entries found at a peer
+// are checked, an entry which fails is treated as missing. The entries found
+// at a peer keep the signature they came with.
+#[derive(Debug)]
+pub struct PeerBackend<B: CacheBackend> {
+    pub local: B,
+    pub peers: Vec<Peer>,
+    pub keystore: Option<Box<dyn Keystore>>,
+    signatures: HashMap<Key, Signature>,
+    // The entries found at a peer, and the ones refused.
+    pub remote_hits: u64,
+    pub rejected: u64,
+}
+
+impl<B: CacheBackend> PeerBackend<B> {
+    pub fn new(local: B, peers: Vec<SocketAddr>) -> PeerBackend<B> {
+        PeerBackend {
+            local,
+            peers: peers.into_iter().map(Peer::new).collect(),
+            keystore: None,
+            signatures: HashMap::new(),
+            remote_hits: 0,
+            rejected: 0,
+        }
+    }
+
+    pub fn with_keystore<K: Keystore + 'static>(mut self, keystore: K) -> PeerBackend<B> {
+        self.keystore = Some(Box::new(keystore));
+        self
+    }
+}
+
+impl<B: CacheBackend> CacheBackend for PeerBackend<B> {
+    fn find(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        self.find_signed(key).map(|(v, _)| v)
+    }
+
+    fn find_signed(&mut self, key: &Key) -> Option<(Either<Value, Domain>, Option<Signature>)> {
+        if let Some(v) = self.local.find(key) {
+            return Some((v, self.signatures.get(key).cloned()));
+        }
+        let keystore = self.keystore.as_deref();
+        for peer in self.peers.iter_mut() {
+            let response = peer.request(&Request::Find(key.clone()));
+            if let Ok(Response::Found(Some((v, signature)))) = response {
+                if signature::check(keystore, key, &v, signature.as_ref()).is_err() {
+                    self.rejected += 1;
+                    continue;
+                }
+                self.remote_hits += 1;
+                if let Some(signature) = signature {
+                    self.signatures.insert(key.clone(), signature);
+                }
+                self.local.add(key.clone(), v.clone());
+                return Some((v, signature));
+            }
+        }
+        None
+    }
+
+    fn add(&mut self, key: Key, v: Either<Value, Domain>) {
+        let signature = match self.keystore {
+            Some(ref keystore) => signature::sign(&**keystore, &key, &v),
+            None => None,
+        };
+        let request = Request::Add(key.clone(), v.clone(), signature);
+        for peer in self.peers.iter_mut() {
+            let _ = peer.request(&request);
+        }
+        self.signatures.remove(&key);
+        self.local.add(key, v);
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        self.signatures.remove(key);
+        self.local.remove(key)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Key, Either<Value, Domain>)> + 'a> {
+        self.local.iter()
+    }
+
+    fn len(&self) -> usize {
+        self.local.len()
+    }
+}
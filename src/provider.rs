@@ -0,0 +1,40 @@
+use context::Context;
+use value::{Dimension, Value};
+
+// Supplies ordinates for dimensions which are demanded by a program but are
+// not in the domain of the evaluation, e.g. from configuration, command line
+// flags or a prompt. Declining leaves the dimension missing as before.
+//
+// Ordinates supplied by a provider are not part of the cache keys, so a
+// provider must give the same answer for a dimension for as long as the cache
+// it is used with is kept.
+pub trait ContextProvider {
+    fn provide(&mut self, di: &Dimension, k: &Context) -> Option<Value>;
+}
+
+// Declines every dimension.
+pub struct NoProvider;
+
+impl ContextProvider for NoProvider {
+    fn provide(&mut self, _di: &Dimension, _k: &Context) -> Option<Value> {
+        None
+    }
+}
+
+// Supplies the ordinates of a fixed context.
+pub struct StaticProvider(pub Context);
+
+impl ContextProvider for StaticProvider {
+    fn provide(&mut self, di: &Dimension, _k: &Context) -> Option<Value> {
+        self.0.lookup(di.clone())
+    }
+}
+
+impl<F> ContextProvider for F
+where
+    F: FnMut(&Dimension) -> Option<Value>,
+{
+    fn provide(&mut self, di: &Dimension, _k: &Context) -> Option<Value> {
+        self(di)
+    }
+}
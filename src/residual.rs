@@ -0,0 +1,89 @@
+use colored::*;
+
+use environment::Environment;
+use expression::*;
+use context::Context;
+use domain::Domain;
+use cache::Cache;
+use either::Either;
+use error::EvalError;
+use evaluator::residualize;
+use provider::ContextProvider;
+use limits::Meter;
+use value::*;
+
+// The remainder of a program which could not be evaluated for lack of some
+// dimensions. The intension closes over the context in which evaluation got
+// stuck and its body has every value computed so far substituted in place.
+#[derive(Clone, Debug)]
+pub struct Residual {
+    pub intension: Intension,
+    pub missing: Domain,
+}
+
+impl Residual {
+    pub fn print(&self) -> String {
+        format!(
+            "{} {}",
+            print_value(Value::Intension(Box::new(self.intension.clone()))),
+            format!("missing {}", self.missing.print()).bright_white()
+        )
+    }
+}
+
+// Close a residual expression over the context and domain at which it must be
+// evaluated, so that it can be spliced into an expression evaluated elsewhere.
+fn close(x: Expression, k: Context, d: Domain) -> Intension {
+    Intension {
+        k: k.clone().restrict(d.clone()),
+        d: d.to_vec(),
+        x: Box::new(x),
+    }
+}
+
+pub fn closed(x: Expression, k: Context, d: Domain) -> Expression {
+    let intens = close(x, k, d);
+    let constant = Expression::Constant(Box::new(Value::Intension(Box::new(intens))));
+    Expression::IntensionApplication(Box::new(constant))
+}
+
+// Evaluate an expression, returning a residual in place of the set of missing
+// dimensions when the expression cannot be evaluated at (k, d). The where
+// clauses are kept in the residual, the environment of the caller is left as
+// it was.
+#[allow(clippy::too_many_arguments)]
+pub fn evaluate_residual(
+    x: Expression,
+    e: &Environment,
+    k: Context,
+    init_d: Domain,
+    d: Domain,
+    c: &mut Cache,
+    p: &mut dyn ContextProvider,
+    m: &mut Meter,
+) -> Result<Either<Value, Residual>, EvalError> {
+    let mut e = e.clone();
+    Ok(match residualize(x, &mut e, k.clone(), init_d, d.clone(), c, p, m)? {
+        Either::Left(l) => Either::Left(l),
+        Either::Right((r, missing)) => Either::Right(Residual {
+            intension: close(r, k, d),
+            missing,
+        }),
+    })
+}
+
+// Apply a residual at a context which supplies (some of) its missing
+// dimensions, the result is either a value or a smaller residual.
+pub fn complete(
+    r: Residual,
+    e: &Environment,
+    k: Context,
+    c: &mut Cache,
+    p: &mut dyn ContextProvider,
+    m: &mut Meter,
+) -> Result<Either<Value, Residual>, EvalError> {
+    let constant = Expression::Constant(Box::new(Value::Intension(Box::new(r.intension))));
+    let x = Expression::IntensionApplication(Box::new(constant));
+    let d = k.domain();
+    evaluate_residual(x, e, k, d.clone(), d, c, p, m)
+}
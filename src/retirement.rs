@@ -0,0 +1,128 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use cache::{Cache, Key};
+
+type Identifier = String;
+
+// Decides which entries leave the cache. A policy is asked after every
+// insertion, retired entries are recomputed when they are demanded again so a
+// policy only trades memory for time.
+pub trait RetirementPolicy: Debug {
+    fn name(&self) -> String;
+
+    // The entries to retire now that the entry for key was added.
+    fn retire(&mut self, c: &Cache, key: &Key) -> Vec<Key>;
+}
+
+// The retirement age of GLU: an entry which has not been used for the given
+// number of ticks retires. A stream which only looks back a few points keeps
+// just those points in the cache.
+#[derive(Clone, Debug)]
+pub struct RetirementAge(pub u64);
+
+impl RetirementPolicy for RetirementAge {
+    fn name(&self) -> String {
+        "retirement age".to_string()
+    }
+
+    fn retire(&mut self, c: &Cache, _key: &Key) -> Vec<Key> {
+        let clock = c.clock;
+        let age = self.0;
+        c.least_recently_used()
+            .take_while(|&(used, _)| used + age < clock)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+}
+
+// Keep at most the given number of entries, retiring the least recently used.
+#[derive(Clone, Debug)]
+pub struct Lru(pub usize);
+
+impl RetirementPolicy for Lru {
+    fn name(&self) -> String {
+        "lru".to_string()
+    }
+
+    fn retire(&mut self, c: &Cache, _key: &Key) -> Vec<Key> {
+        let excess = c.len().saturating_sub(self.0);
+        c.least_recently_used()
+            .take(excess)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+}
+
+// An entry retires the given number of ticks after it was added, whether it
+// is used or not.
+#[derive(Clone, Debug)]
+pub struct MaxAge {
+    pub age: u64,
+    added: VecDeque<(u64, Key)>,
+}
+
+impl MaxAge {
+    pub fn new(age: u64) -> MaxAge {
+        MaxAge {
+            age,
+            added: VecDeque::new(),
+        }
+    }
+}
+
+impl RetirementPolicy for MaxAge {
+    fn name(&self) -> String {
+        "max age".to_string()
+    }
+
+    fn retire(&mut self, c: &Cache, key: &Key) -> Vec<Key> {
+        self.added.push_back((c.clock, key.clone()));
+        let mut victims = vec![];
+        while self.added.front().is_some_and(|&(added, _)| added + self.age < c.clock) {
+            let (added, key) = self.added.pop_front().unwrap();
+            // The entry may have been retired and added again since.
+            if c.age(&key).map(|age| age.added) == Some(added) {
+                victims.push(key);
+            }
+        }
+        victims
+    }
+}
+
+// Keep at most so many entries of the given identifiers, retiring their least
+// recently used ones.
+#[derive(Clone, Debug, Default)]
+pub struct IdentifierCap {
+    pub caps: HashMap<Identifier, usize>,
+}
+
+impl IdentifierCap {
+    pub fn new() -> IdentifierCap {
+        IdentifierCap::default()
+    }
+
+    pub fn cap(mut self, x: &str, max: usize) -> IdentifierCap {
+        self.caps.insert(x.to_string(), max);
+        self
+    }
+}
+
+impl RetirementPolicy for IdentifierCap {
+    fn name(&self) -> String {
+        "identifier cap".to_string()
+    }
+
+    fn retire(&mut self, c: &Cache, key: &Key) -> Vec<Key> {
+        match self.caps.get(&key.x) {
+            Some(&max) => {
+                let excess = c.count_of(&key.x).saturating_sub(max);
+                c.least_recently_used_of(&key.x)
+                    .take(excess)
+                    .cloned()
+                    .collect()
+            }
+
+            None => vec![],
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::{HashMap, HashSet};
+use cache::Cache;
+use context::Context;
+use digest::Digest;
+use domain::Domain;
+use either::Either;
+use encoding::digest;
+use environment::{Environment, L1Environment};
+use error::{EvalError, ShipError};
+use evaluator;
+use expression::*;
+use inference;
+use limits::Meter;
+use provider::{ContextProvider, NoProvider};
+use shipping::{named_dimensions, Shipment};
+use value::{Dimension, Intension, Value};
+use {compile, primitive_environment, transform_l1_dimensions};
+
+type Identifier = String;
+
+// Stands in for the query in the compiled program. It cannot be written in a
+// program, so it cannot clash with an identifier.
+const QUERY: &str = "?query";
+
+// A where clause around the body of a program.
+#[derive(Clone, Debug)]
+enum Scope {
+    Dimensions(L1ContextExpression),
+    Variables(L1Environment),
+}
+
+// A program loaded once to answer many queries. The queries are evaluated in
+// the scope of the where clauses around the body of the program and share one
+// cache, so what one query computes is found by the next.
+#[derive(Debug)]
+pub struct Runtime {
+    scopes: Vec<Scope>,
+    body: L1Expression,
+    // The program compiled with QUERY in place of its body, and the context
+    // and domain it is evaluated at.
+    program: Expression,
+    k: Context,
+    d: Domain,
+    // What a query needs to be compiled as if it were the body.
+    dimensions: HashMap<Identifier, Dimension>,
+    q: u32,
+    definitions: Environment,
+    primitives: Environment,
+    // The definitions shipped with imported intensions, in scope inside the
+    // where clauses of the program.
+    imported: Environment,
+    pub cache: Cache,
+}
+
+impl Runtime {
+    pub fn new(program: L1Expression) -> Runtime {
+        Runtime::with_cache(program, Cache::new())
+    }
+
+    pub fn with_cache(program: L1Expression, c: Cache) -> Runtime {
+        let mut scopes = vec![];
+        let mut x = program;
+        let body = loop {
+            x = match x {
+                L1Expression::WhereDim(wd) => {
+                    let wd = *wd;
+                    scopes.push(Scope::Dimensions(wd.rhs));
+                    wd.lhs
+                }
+
+                L1Expression::WhereVar(wv) => {
+                    let wv = *wv;
+                    scopes.push(Scope::Variables(wv.rhs));
+                    wv.lhs
+                }
+
+                other => break other,
+            }
+        };
+        let mut runtime = Runtime {
+            scopes,
+            body,
+            program: Expression::Identifier(QUERY.to_string()),
+            k: Context::new(),
+            d: Domain::new(),
+            dimensions: HashMap::new(),
+            q: 0,
+            definitions: Environment::new(),
+            primitives: primitive_environment(),
+            imported: Environment::new(),
+            cache: c,
+        };
+        runtime.load();
+        runtime
+    }
+
+    // Compile the where clauses around the query.
+    fn load(&mut self) {
+        let mut program = L1Expression::Identifier(QUERY.to_string());
+        for scope in self.scopes.iter().rev() {
+            program = match scope.clone() {
+                Scope::Dimensions(rhs) => L1Expression::WhereDim(Box::new(L1WhereDimExpression {
+                    lhs: program,
+                    rhs,
+                })),
+
+                Scope::Variables(rhs) => L1Expression::WhereVar(Box::new(L1WhereVarExpression {
+                    lhs: program,
+                    rhs,
+                })),
+            };
+        }
+        let (x, k, d) = compile(program);
+
+        // The dimensions in scope at the query as transform_l1_dimensions
+        // declares them, one level of hidden dimensions per dimension clause.
+        self.dimensions = HashMap::new();
+        self.q = 0;
+        for scope in &self.scopes {
+            if let Scope::Dimensions(rhs) = scope {
+                for (i, dimension_expr) in rhs.0.iter().enumerate() {
+                    let di = Dimension {
+                        i: i as u32,
+                        v: Value::Identifier(dimension_expr.lhs.clone()),
+                    };
+                    self.dimensions.insert(dimension_expr.lhs.clone(), di);
+                }
+                self.q += 1;
+            }
+        }
+
+        self.definitions = Environment::new();
+        let mut scope = &x;
+        loop {
+            scope = match scope {
+                Expression::WhereDim(wd) => &wd.lhs,
+                Expression::WhereVar(wv) => {
+                    self.definitions.merge(wv.rhs.clone());
+                    &wv.lhs
+                }
+                _ => break,
+            }
+        }
+        self.program = x;
+        self.k = k;
+        self.d = d;
+    }
+
+    // The hash of the program, which names it to the workers evaluating it.
+    pub fn hash(&self) -> Digest {
+        digest(&self.program)
+    }
+
+    // Evaluate the body of the program.
+    pub fn run(&mut self) -> Result<Either<Value, Domain>, EvalError> {
+        let body = self.body.clone();
+        self.query(body)
+    }
+
+    // Evaluate an identifier of the program with some of its dimensions set,
+    // e.g. runtime.eval("fib", &[("n", 30)]) for fib @ [n <- 30].
+    pub fn eval(
+        &mut self,
+        x: &str,
+        args: &[(&str, u32)],
+    ) -> Result<Either<Value, Domain>, EvalError> {
+        let id = L1Expression::Identifier(x.to_string());
+        let query = if args.is_empty() {
+            id
+        } else {
+            let tuples = args
+                .iter()
+                .map(|&(di, ord)| L1TupleExpression {
+                    lhs: L1Expression::Identifier(di.to_string()),
+                    rhs: L1Expression::Literal(Literal::Int32(ord)),
+                })
+                .collect();
+            L1Expression::Perturb(Box::new(L1PerturbExpression {
+                lhs: id,
+                rhs: L1Expression::TupleBuilder(tuples),
+            }))
+        };
+        self.query(query)
+    }
+
+    // Evaluate an expression as if it were the body of the program.
+    pub fn query(&mut self, x: L1Expression) -> Result<Either<Value, Domain>, EvalError> {
+        self.query_with_meter(x, &mut NoProvider, &mut Meter::unlimited())
+    }
+
+    pub fn query_with_meter(
+        &mut self,
+        x: L1Expression,
+        p: &mut dyn ContextProvider,
+        m: &mut Meter,
+    ) -> Result<Either<Value, Domain>, EvalError> {
+        let mut dimensions = self.dimensions.clone();
+        let mut names = HashSet::new();
+        let (x, q_dims) =
+            transform_l1_dimensions(x, &mut dimensions, &mut names, self.q, HashSet::new());
+        let x = inference::infer_intension_domains_in(x, &self.definitions);
+        let mut k = self.k.clone();
+        let mut d = self.d.clone();
+        for q_dim in q_dims {
+            k.push(q_dim.clone(), Value::Literal(Literal::Int32(0)));
+            d.push(q_dim);
+        }
+        let x = self.splice(x);
+        // Where clauses extend the environment as they are evaluated, each
+        // query starts from the primitives.
+        let mut e = self.primitives.clone();
+        evaluator::evaluate(x, &mut e, k, d.clone(), d, &mut self.cache, p, m)
+    }
+
+    // Demand an identifier at a context as the program would, e.g. at the
+    // context of an entry, against the given cache.
+    pub fn demand_at(
+        &mut self,
+        x: &str,
+        k: Context,
+        c: &mut Cache,
+        m: &mut Meter,
+    ) -> Result<Either<Value, Domain>, EvalError> {
+        let query = Expression::Perturb(Box::new(PerturbExpression {
+            lhs: Expression::Identifier(x.to_string()),
+            rhs: Expression::Constant(Box::new(Value::Context(k))),
+        }));
+        let x = self.splice(query);
+        let mut e = self.primitives.clone();
+        let (k, d) = (self.k.clone(), self.d.clone());
+        evaluator::evaluate(x, &mut e, k, d.clone(), d, c, &mut NoProvider, m)
+    }
+
+    // Apply an intension in the scope of the where clauses of the program.
+    pub fn apply(&mut self, intension: Intension) -> Result<Either<Value, Domain>, EvalError> {
+        let application = Expression::IntensionApplication(Box::new(Expression::Constant(
+            Box::new(Value::Intension(Box::new(intension))),
+        )));
+        self.evaluate(application)
+    }
+
+    // Pack an intension built by this runtime, e.g. the value of an
+    // identifier, to be applied by another one. Its body may only refer to
+    // identifiers and dimensions of the where clauses around the body of the
+    // program.
+    pub fn export(&mut self, intension: &Intension) -> Result<Shipment, ShipError> {
+        let mut scope = self.definitions.clone();
+        scope.merge(self.imported.clone());
+        let (definitions, undefined) = scope.closure(&intension.x);
+        let undefined = undefined.into_iter().find(|x| self.primitives.get(x).is_none());
+        if let Some(x) = undefined {
+            return Err(ShipError::Undefined(x));
+        }
+
+        // The dimensions of the where clauses are bound to hidden dimensions
+        // as the clauses are evaluated, the query sees the bindings.
+        let mut named = vec![];
+        named_dimensions(&intension.x, &mut named);
+        for definition in &definitions.0 {
+            named_dimensions(&definition.equation, &mut named);
+        }
+        let k = match self.evaluate(Expression::ContextQuery) {
+            Ok(Either::Left(Value::Context(k))) => k,
+            _ => Context::new(),
+        };
+        let hidden = Domain::new().from_vec(intension.d.clone());
+        let mut dimensions = Context::new();
+        for di in named {
+            if let Some(Value::Dimension(hi)) = k.lookup(di.clone()) {
+                if hidden.contains(*hi.clone()) && dimensions.lookup(di.clone()).is_none() {
+                    dimensions.push(di, Value::Dimension(hi));
+                }
+            }
+        }
+        Ok(Shipment {
+            intension: intension.clone(),
+            definitions,
+            dimensions,
+        })
+    }
+
+    // Take in the definitions of a shipped intension and return the intension
+    // to apply. A definition must agree with the one this runtime already
+    // has for its identifier, if any.
+    pub fn import(&mut self, shipment: &Shipment) -> Result<Intension, ShipError> {
+        for definition in &shipment.definitions.0 {
+            let x = &definition.id;
+            let defined = self.definitions
+                .get(x)
+                .or_else(|| self.imported.get(x))
+                .or_else(|| self.primitives.get(x));
+            match defined {
+                Some(equation) if *equation != definition.equation => {
+                    return Err(ShipError::Conflict(x.clone()))
+                }
+                _ => {}
+            }
+        }
+        for definition in &shipment.definitions.0 {
+            if self.imported.get(&definition.id).is_none() {
+                self.imported.define(definition.id.clone(), definition.equation.clone());
+            }
+        }
+        Ok(shipment.unpack())
+    }
+
+    // Evaluate a compiled expression in place of the query.
+    fn evaluate(&mut self, x: Expression) -> Result<Either<Value, Domain>, EvalError> {
+        let x = self.splice(x);
+        let mut e = self.primitives.clone();
+        let (k, d) = (self.k.clone(), self.d.clone());
+        let (c, p, m) = (&mut self.cache, &mut NoProvider, &mut Meter::unlimited());
+        evaluator::evaluate(x, &mut e, k, d.clone(), d, c, p, m)
+    }
+
+    // Put the query in place of QUERY in the compiled program, with the
+    // imported definitions around it.
+    fn splice(&self, query: Expression) -> Expression {
+        let query = if self.imported.0.is_empty() {
+            query
+        } else {
+            Expression::WhereVar(Box::new(WhereVarExpression {
+                lhs: query,
+                rhs: self.imported.clone(),
+            }))
+        };
+        splice(self.program.clone(), query)
+    }
+
+    // Add a definition to the innermost where clause of the program, or
+    // replace the definition of the identifier where it is defined. The
+    // entries computed from a replaced definition no longer hold, they are
+    // invalidated with the entries which read them.
+    pub fn define(&mut self, id: &str, x: L1Expression) {
+        let definition = self.definition(id);
+        let defined = self.scopes.iter_mut().rev().find(|scope| match *scope {
+            &mut Scope::Variables(ref rhs) => rhs.0.contains_key(id),
+            _ => false,
+        });
+        match defined {
+            Some(&mut Scope::Variables(ref mut rhs)) => {
+                rhs.define(id.to_string(), x);
+                self.cache.invalidate(&id.to_string(), &definition);
+            }
+
+            _ => {
+                if let Some(&mut Scope::Variables(ref mut rhs)) = self.scopes.last_mut() {
+                    rhs.define(id.to_string(), x);
+                } else {
+                    let mut rhs = L1Environment::new();
+                    rhs.define(id.to_string(), x);
+                    self.scopes.push(Scope::Variables(rhs));
+                }
+            }
+        }
+        self.load();
+    }
+
+    // The digest of the definition of an identifier as the queries see it,
+    // which addresses its entries in the cache.
+    pub fn definition(&self, id: &str) -> Digest {
+        let mut e = self.primitives.clone();
+        e.merge(self.definitions.clone());
+        e.merge(self.imported.clone());
+        e.digest(&id.to_string()).0
+    }
+}
+
+// Put the query in place of QUERY in the compiled program.
+fn splice(x: Expression, query: Expression) -> Expression {
+    match x {
+        Expression::WhereDim(wd) => {
+            let wd = *wd;
+            Expression::WhereDim(Box::new(WhereDimExpression {
+                lhs: splice(wd.lhs, query),
+                ..wd
+            }))
+        }
+
+        Expression::WhereVar(wv) => {
+            let wv = *wv;
+            Expression::WhereVar(Box::new(WhereVarExpression {
+                lhs: splice(wv.lhs, query),
+                rhs: wv.rhs,
+            }))
+        }
+
+        _ => query,
+    }
+}
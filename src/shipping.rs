@@ -0,0 +1,75 @@
+use context::Context;
+use domain::Domain;
+use encoding::{Decode, DecodeError, Decoder, Encode};
+use environment::Environment;
+use expression::Expression;
+use inference::map_children;
+use value::{Dimension, Intension, Value};
+
+// An intension packed to be applied by another runtime, with the definitions
+// its body refers to, transitively, and the hidden dimensions which the
+// dimensions it names stood for where it was built. Hidden dimensions are
+// numbered by the where clauses of a program, so the receiving runtime may use
+// the same ones for other dimensions of its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shipment {
+    pub intension: Intension,
+    pub definitions: Environment,
+    // Binds the dimensions named in the body to the hidden dimensions which
+    // the context of the intension sets.
+    pub dimensions: Context,
+}
+
+impl Shipment {
+    // The intension which binds the named dimensions itself, so that applying
+    // it anywhere reads the ordinates it was built with.
+    pub fn unpack(&self) -> Intension {
+        let mut intension = self.intension.clone();
+        let mut d = Domain::new().from_vec(intension.d);
+        for tuple in &self.dimensions.0 {
+            d.push(tuple.dim.clone());
+        }
+        intension.k = intension.k.perturb(self.dimensions.clone());
+        intension.d = d.to_vec();
+        intension
+    }
+}
+
+// The dimensions an expression names, the ones a where clause declares.
+pub fn named_dimensions(x: &Expression, out: &mut Vec<Dimension>) {
+    match x {
+        Expression::Dimension(di) => {
+            if let Value::Identifier(_) = di.v {
+                out.push(di.clone());
+            }
+        }
+
+        other => {
+            map_children(other.clone(), |child| {
+                named_dimensions(&child, out);
+                child
+            });
+        }
+    }
+}
+
+impl Encode for Shipment {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.intension.encode(out);
+        self.definitions.encode(out);
+        self.dimensions.encode(out);
+    }
+}
+
+impl Decode for Shipment {
+    fn decode(r: &mut Decoder) -> Result<Shipment, DecodeError> {
+        let intension = Intension::decode(r)?;
+        let definitions = Environment::decode(r)?;
+        let dimensions = Context::decode(r)?;
+        Ok(Shipment {
+            intension,
+            definitions,
+            dimensions,
+        })
+    }
+}
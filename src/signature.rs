@@ -0,0 +1,140 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use cache::Key;
+use domain::Domain;
+use ed25519::{self, Keypair, PublicKey};
+use either::Either;
+use encoding::{encode, Decode, DecodeError, Decoder, Encode};
+use error::SignatureError;
+use value::Value;
+
+// The signature of a cache entry by the node which computed it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Signature {
+    pub signer: PublicKey,
+    pub bytes: [u8; 64],
+}
+
+impl Encode for Signature {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.signer.0);
+        out.extend_from_slice(&self.bytes);
+    }
+}
+
+impl Decode for Signature {
+    fn decode(r: &mut Decoder) -> Result<Signature, DecodeError> {
+        let mut signer = [0; 32];
+        signer.copy_from_slice(r.take(32)?);
+        let mut bytes = [0; 64];
+        bytes.copy_from_slice(r.take(64)?);
+        Ok(Signature {
+            signer: PublicKey(signer),
+            bytes,
+        })
+    }
+}
+
+// What is signed for an entry: its key, whose digest stands for the
+// definitions and the context it was computed from, with the identifier and
+// the context in their canonical encoding so that neither can be swapped for
+// another, and its value.
+pub fn message(key: &Key, v: &Either<Value, Domain>) -> Vec<u8> {
+    let mut message = b"gaia-entry".to_vec();
+    key.encode(&mut message);
+    message.extend(encode(v));
+    message
+}
+
+// Holds the key pair of a node and decides whose entries the node accepts.
+// Nodes which keep their keys elsewhere implement their own.
+pub trait Keystore: Debug {
+    // Sign with the key pair of the node, if it has one.
+    fn sign(&self, message: &[u8]) -> Option<Signature>;
+
+    // Whether entries signed by the signer are accepted.
+    fn trusts(&self, signer: &PublicKey) -> bool;
+
+    // Whether entries without a signature are refused.
+    fn requires_signatures(&self) -> bool;
+}
+
+// A keystore in memory, which trusts its own key and the keys it is given.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryKeystore {
+    pub keypair: Option<Keypair>,
+    pub trusted: HashSet<PublicKey>,
+    pub required: bool,
+}
+
+impl MemoryKeystore {
+    pub fn new() -> MemoryKeystore {
+        MemoryKeystore::default()
+    }
+
+    pub fn with_keypair(mut self, keypair: Keypair) -> MemoryKeystore {
+        self.trusted.insert(keypair.public);
+        self.keypair = Some(keypair);
+        self
+    }
+
+    pub fn trust(mut self, signer: PublicKey) -> MemoryKeystore {
+        self.trusted.insert(signer);
+        self
+    }
+
+    pub fn require_signatures(mut self) -> MemoryKeystore {
+        self.required = true;
+        self
+    }
+}
+
+impl Keystore for MemoryKeystore {
+    fn sign(&self, message: &[u8]) -> Option<Signature> {
+        self.keypair.as_ref().map(|keypair| Signature {
+            signer: keypair.public,
+            bytes: keypair.sign(message),
+        })
+    }
+
+    fn trusts(&self, signer: &PublicKey) -> bool {
+        self.trusted.contains(signer)
+    }
+
+    fn requires_signatures(&self) -> bool {
+        self.required
+    }
+}
+
+pub fn sign(keystore: &dyn Keystore, key: &Key, v: &Either<Value, Domain>) -> Option<Signature> {
+    keystore.sign(&message(key, v))
+}
+
+// Check an entry received from elsewhere. A signature must hold whoever
+// checks it, the keystore decides whose signatures are accepted and whether
+// entries without one are.
+pub fn check(
+    keystore: Option<&dyn Keystore>,
+    key: &Key,
+    v: &Either<Value, Domain>,
+    signature: Option<&Signature>,
+) -> Result<(), SignatureError> {
+    match signature {
+        Some(signature) => {
+            if !ed25519::verify(&signature.signer, &message(key, v), &signature.bytes) {
+                return Err(SignatureError::Invalid(signature.signer));
+            }
+            match keystore {
+                Some(keystore) if !keystore.trusts(&signature.signer) => {
+                    Err(SignatureError::Untrusted(signature.signer))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        None => match keystore {
+            Some(keystore) if keystore.requires_signatures() => Err(SignatureError::Missing),
+            _ => Ok(()),
+        },
+    }
+}
@@ -0,0 +1,345 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use backend::CacheBackend;
+use cache::{Cache, Key};
+use digest::Digest;
+use domain::Domain;
+use either::Either;
+use encoding::encode;
+use error::EvalError;
+use expression::L1Expression;
+use rng::Rng;
+use value::Value;
+use evaluate_with_cache;
+
+// How the virtual network behaves, times are in ticks of the simulated clock.
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    // Every message takes from min to max ticks to arrive.
+    pub latency: (u64, u64),
+    // The probability that a message is lost.
+    pub loss: f64,
+    // How long a node waits for the answer to a request which never comes.
+    pub timeout: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            latency: (1, 1),
+            loss: 0.0,
+            timeout: 100,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn latency(mut self, min: u64, max: u64) -> NetworkConfig {
+        self.latency = (min, max);
+        self
+    }
+
+    pub fn loss(mut self, p: f64) -> NetworkConfig {
+        self.loss = p;
+        self
+    }
+
+    pub fn timeout(mut self, ticks: u64) -> NetworkConfig {
+        self.timeout = ticks;
+        self
+    }
+}
+
+// What happened to the messages of a simulation.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NetworkStats {
+    pub sent: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    // Messages which could not cross a partition.
+    pub blocked: u64,
+    pub remote_hits: u64,
+}
+
+// An entry on its way from one node to another.
+#[derive(Clone, Debug)]
+struct Message {
+    from: usize,
+    to: usize,
+    key: Key,
+    v: Either<Value, Domain>,
+}
+
+// The entries a node keeps, by digest.
+type Store = BTreeMap<Digest, (Key, Either<Value, Domain>)>;
+
+// Everything the nodes share: their stores, the network between them and the
+// clock. The stores are ordered by digest so that nothing depends on the
+// order of a hash map.
+#[derive(Debug)]
+struct World {
+    config: NetworkConfig,
+    rng: Rng,
+    clock: u64,
+    stores: Vec<Store>,
+    // The side of the partition each node is on.
+    sides: Vec<usize>,
+    // Messages in flight by time of arrival and order of sending.
+    queue: BTreeMap<(u64, u64), Message>,
+    sequence: u64,
+    stats: NetworkStats,
+}
+
+impl World {
+    fn reachable(&self, from: usize, to: usize) -> bool {
+        self.sides[from] == self.sides[to]
+    }
+
+    // Send a message, which arrives after some latency unless it is lost.
+    fn send(&mut self, message: Message) {
+        self.stats.sent += 1;
+        if self.rng.chance(self.config.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        let (min, max) = self.config.latency;
+        let arrival = self.clock + self.rng.between(min, max);
+        self.sequence += 1;
+        self.queue.insert((arrival, self.sequence), message);
+    }
+
+    // Ask a peer for an entry and wait for the answer. A request or answer
+    // which is lost or cannot cross a partition costs a timeout.
+    fn request(&mut self, from: usize, to: usize, key: &Key) -> Option<Either<Value, Domain>> {
+        self.stats.sent += 2;
+        if !self.reachable(from, to) {
+            self.stats.blocked += 1;
+            self.clock += self.config.timeout;
+            return None;
+        }
+        if self.rng.chance(self.config.loss) || self.rng.chance(self.config.loss) {
+            self.stats.lost += 1;
+            self.clock += self.config.timeout;
+            return None;
+        }
+        let (min, max) = self.config.latency;
+        self.clock += self.rng.between(min, max) + self.rng.between(min, max);
+        self.stats.delivered += 2;
+        self.stores[to].get(&key.digest).map(|(_, v)| v.clone())
+    }
+
+    // Deliver the messages which arrive by the given time, unless a
+    // partition separates their nodes by then.
+    fn deliver_until(&mut self, time: u64) {
+        while let Some(&(arrival, sequence)) = self.queue.keys().next() {
+            if arrival > time {
+                break;
+            }
+            let message = self.queue.remove(&(arrival, sequence)).unwrap();
+            self.clock = self.clock.max(arrival);
+            if !self.reachable(message.from, message.to) {
+                self.stats.blocked += 1;
+                continue;
+            }
+            self.stats.delivered += 1;
+            self.stores[message.to]
+                .entry(message.key.digest)
+                .or_insert((message.key, message.v));
+        }
+    }
+}
+
+// The backend of the cache of a simulated node. A miss asks the peers in turn
+// and every entry added is published to them.
+#[derive(Debug)]
+pub struct SimulatedBackend {
+    node: usize,
+    world: Rc<RefCell<World>>,
+}
+
+impl CacheBackend for SimulatedBackend {
+    fn find(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        let mut world = self.world.borrow_mut();
+        let now = world.clock;
+        world.deliver_until(now);
+        if let Some((_, v)) = world.stores[self.node].get(&key.digest) {
+            return Some(v.clone());
+        }
+        for peer in 0..world.stores.len() {
+            if peer == self.node {
+                continue;
+            }
+            if let Some(v) = world.request(self.node, peer, key) {
+                world.stats.remote_hits += 1;
+                world.stores[self.node].insert(key.digest, (key.clone(), v.clone()));
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    fn add(&mut self, key: Key, v: Either<Value, Domain>) {
+        let mut world = self.world.borrow_mut();
+        for peer in 0..world.stores.len() {
+            if peer != self.node {
+                let message = Message {
+                    from: self.node,
+                    to: peer,
+                    key: key.clone(),
+                    v: v.clone(),
+                };
+                world.send(message);
+            }
+        }
+        world.stores[self.node].insert(key.digest, (key, v));
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Either<Value, Domain>> {
+        let mut world = self.world.borrow_mut();
+        world.stores[self.node].remove(&key.digest).map(|(_, v)| v)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Key, Either<Value, Domain>)> + 'a> {
+        let world = self.world.borrow();
+        let entries: Vec<_> = world.stores[self.node].values().cloned().collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn len(&self) -> usize {
+        self.world.borrow().stores[self.node].len()
+    }
+}
+
+// Nodes in one process with one cache each, connected by a virtual network
+// whose latency, losses and partitions are decided by a seeded generator. The
+// same seed gives the same simulation.
+#[derive(Debug)]
+pub struct Simulation {
+    pub seed: u64,
+    pub caches: Vec<Cache>,
+    world: Rc<RefCell<World>>,
+}
+
+impl Simulation {
+    pub fn new(nodes: usize, seed: u64, config: NetworkConfig) -> Simulation {
+        let world = Rc::new(RefCell::new(World {
+            config,
+            rng: Rng::new(seed),
+            clock: 0,
+            stores: (0..nodes).map(|_| BTreeMap::new()).collect(),
+            sides: vec![0; nodes],
+            queue: BTreeMap::new(),
+            sequence: 0,
+            stats: NetworkStats::default(),
+        }));
+        let caches = (0..nodes)
+            .map(|node| {
+                Cache::with_backend(SimulatedBackend {
+                    node,
+                    world: world.clone(),
+                })
+            })
+            .collect();
+        Simulation {
+            seed,
+            caches,
+            world,
+        }
+    }
+
+    pub fn clock(&self) -> u64 {
+        self.world.borrow().clock
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.world.borrow().stats.clone()
+    }
+
+    // Split the nodes, nodes on different sides cannot reach each other.
+    // Nodes which are not listed are on a side of their own.
+    pub fn partition(&mut self, sides: &[&[usize]]) {
+        let mut world = self.world.borrow_mut();
+        let nodes = world.sides.len();
+        world.sides = (sides.len()..sides.len() + nodes).collect();
+        for (side, members) in sides.iter().enumerate() {
+            for &node in members.iter() {
+                world.sides[node] = side;
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        let mut world = self.world.borrow_mut();
+        world.sides = vec![0; world.sides.len()];
+    }
+
+    // Evaluate a program at a node.
+    pub fn evaluate(
+        &mut self,
+        node: usize,
+        expr: L1Expression,
+    ) -> Result<Either<Value, Domain>, EvalError> {
+        evaluate_with_cache(expr, &mut self.caches[node])
+    }
+
+    // Let the clock run until every message in flight has arrived or been
+    // dropped at a partition.
+    pub fn run(&mut self) {
+        let mut world = self.world.borrow_mut();
+        let end = world.queue.keys().last().map_or(world.clock, |&(arrival, _)| arrival);
+        world.deliver_until(end);
+    }
+
+    // Every node sends every entry it has to every peer, so that entries
+    // lost on the way get another chance.
+    pub fn gossip(&mut self) {
+        let mut world = self.world.borrow_mut();
+        for node in 0..world.stores.len() {
+            let entries: Vec<_> = world.stores[node].values().cloned().collect();
+            for peer in 0..world.stores.len() {
+                if peer == node {
+                    continue;
+                }
+                for (key, v) in &entries {
+                    let message = Message {
+                        from: node,
+                        to: peer,
+                        key: key.clone(),
+                        v: v.clone(),
+                    };
+                    world.send(message);
+                }
+            }
+        }
+    }
+
+    // The entries of a node, in the canonical encoding and ordered by digest
+    // so that extensions can be compared.
+    pub fn extension(&self, node: usize) -> Vec<(Digest, Vec<u8>)> {
+        self.world.borrow().stores[node]
+            .iter()
+            .map(|(digest, (_, v))| (*digest, encode(v)))
+            .collect()
+    }
+
+    pub fn converged(&self) -> bool {
+        let first = self.extension(0);
+        (1..self.caches.len()).all(|node| self.extension(node) == first)
+    }
+
+    // Deliver and gossip until the nodes agree, returning how many rounds of
+    // gossip it took, or None when they still disagree after max rounds.
+    pub fn run_until_converged(&mut self, max: usize) -> Option<usize> {
+        for round in 0..max + 1 {
+            self.run();
+            if self.converged() {
+                return Some(round);
+            }
+            if round < max {
+                self.gossip();
+            }
+        }
+        None
+    }
+}
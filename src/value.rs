@@ -1,6 +1,7 @@
 use colored::*;
 use expression::*;
 use context::*;
+use domain::*;
 
 type Identifier = String;
 
@@ -33,6 +34,7 @@ pub enum Value {
     NameAbstraction(Box<NameAbstraction>),
     Identifier(String),
     Context(Context),
+    Domain(Domain),
     PrimOp(Identifier),
 }
 
@@ -60,6 +62,22 @@ impl Value {
         }
     }
 
+    pub fn expect_context(&self) -> Context {
+        match self {
+            Value::Context(k) => k.clone(),
+
+            other => panic!("Expected context but here found {:?}", other),
+        }
+    }
+
+    pub fn expect_domain(&self) -> Domain {
+        match self {
+            Value::Domain(d) => d.clone(),
+
+            other => panic!("Expected domain but here found {:?}", other),
+        }
+    }
+
     pub fn expect_intension(&self) -> Intension {
         match self {
             &Value::Intension(ref intens) => *intens.clone(),
@@ -125,6 +143,8 @@ pub fn print_value(v: Value) -> String {
 
         Value::Context(k) => format!("{:?}", k),
 
+        Value::Domain(d) => d.print(),
+
         Value::PrimOp(op) => format!("{}", op.bright_white()),
     }
 }
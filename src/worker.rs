@@ -0,0 +1,400 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use cache::{Cache, Key};
+use context::Context;
+use digest::Digest;
+use domain::Domain;
+use either::Either;
+use encoding::{decode_untrusted, encode, Decode, Encode};
+use error::{SignatureError, WorkerError};
+use expression::L1Expression;
+use json::{self, Json};
+use limits::Meter;
+use runtime::Runtime;
+use signature::Signature;
+use value::Value;
+
+// The protocol spoken between a coordinator and its workers over the standard
+// input and output of the workers, one JSON object per line. A request names
+// the program by its hash and gives the context in the binary encoding, in
+// hex, and so do the responses for their values:
+//
+//     {"id":0,"program":"9f2c..","x":"fib","context":"0200.."}
+//     {"id":0,"value":"00..","entries":[["<key>","<value>","<signature>"],..]}
+//     {"id":0,"missing":"01..","entries":[..]}
+//     {"id":0,"error":"unknown program 9f2c.."}
+//
+// The entries are the ones the worker computed for the demand, with the
+// signatures of the worker, or null when it signs nothing.
+
+// A demand for an identifier of a program at a context, e.g. the context of
+// an entry.
+#[derive(Clone, Debug)]
+pub struct Demand {
+    pub program: Digest,
+    pub x: String,
+    pub k: Context,
+}
+
+// What a worker answers to a demand.
+#[derive(Clone, Debug)]
+pub struct Answer {
+    pub v: Either<Value, Domain>,
+    pub entries: Vec<(Key, Either<Value, Domain>, Option<Signature>)>,
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn unhex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("invalid hex {}", s));
+    }
+    (0..s.len() / 2)
+        .map(|i| {
+            u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| format!("invalid hex {}", s))
+        })
+        .collect()
+}
+
+fn field<'a>(message: &'a Json, name: &str) -> Result<&'a Json, String> {
+    message.get(name).ok_or_else(|| format!("missing field {}", name))
+}
+
+fn encoded<T: Encode>(x: &T) -> Json {
+    Json::String(hex(&encode(x)))
+}
+
+fn decoded<T: Decode>(x: &Json) -> Result<T, String> {
+    let bytes = unhex(x.as_str().ok_or("expected a string")?)?;
+    decode_untrusted(&bytes).map_err(|err| err.0)
+}
+
+fn id(message: &Json) -> Result<i64, String> {
+    field(message, "id")?.as_number().ok_or_else(|| "expected a number for id".to_string())
+}
+
+pub fn request(id: i64, demand: &Demand) -> Json {
+    Json::Object(vec![
+        ("id".to_string(), Json::Number(id)),
+        ("program".to_string(), Json::String(demand.program.hex())),
+        ("x".to_string(), Json::String(demand.x.clone())),
+        ("context".to_string(), encoded(&demand.k)),
+    ])
+}
+
+pub fn parse_request(message: &Json) -> Result<(i64, Demand), String> {
+    let program = field(message, "program")?.as_str().ok_or("expected a string for program")?;
+    let program = unhex(program)?;
+    if program.len() != 32 {
+        return Err("expected a program hash".to_string());
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&program);
+    let x = field(message, "x")?.as_str().ok_or("expected a string for x")?;
+    let demand = Demand {
+        program: Digest(hash),
+        x: x.to_string(),
+        k: decoded(field(message, "context")?)?,
+    };
+    Ok((id(message)?, demand))
+}
+
+pub fn response(id: Option<i64>, answer: &Result<Answer, String>) -> Json {
+    let id = id.map_or(Json::Null, Json::Number);
+    match answer {
+        Ok(answer) => {
+            let result = match answer.v {
+                Either::Left(ref v) => ("value".to_string(), encoded(v)),
+                Either::Right(ref missing) => ("missing".to_string(), encoded(missing)),
+            };
+            let entries = answer
+                .entries
+                .iter()
+                .map(|(key, v, signature)| {
+                    let signature = signature.as_ref().map_or(Json::Null, encoded);
+                    Json::Array(vec![encoded(key), encoded(v), signature])
+                })
+                .collect();
+            Json::Object(vec![
+                ("id".to_string(), id),
+                result,
+                ("entries".to_string(), Json::Array(entries)),
+            ])
+        }
+
+        Err(err) => Json::Object(vec![
+            ("id".to_string(), id),
+            ("error".to_string(), Json::String(err.clone())),
+        ]),
+    }
+}
+
+pub fn parse_response(message: &Json) -> Result<(i64, Result<Answer, String>), String> {
+    let id = id(message)?;
+    if let Some(err) = message.get("error") {
+        let err = err.as_str().ok_or("expected a string for error")?;
+        return Ok((id, Err(err.to_string())));
+    }
+    let v = match (message.get("value"), message.get("missing")) {
+        (Some(v), _) => Either::Left(decoded(v)?),
+        (None, Some(missing)) => Either::Right(decoded(missing)?),
+        (None, None) => return Err("missing field value".to_string()),
+    };
+    let mut entries = vec![];
+    for entry in field(message, "entries")?.as_array().ok_or("expected an array for entries")? {
+        match entry.as_array() {
+            Some(&[ref key, ref v, Json::Null]) => entries.push((decoded(key)?, decoded(v)?, None)),
+            Some([key, v, signature]) => {
+                entries.push((decoded(key)?, decoded(v)?, Some(decoded(signature)?)))
+            }
+            _ => return Err("expected a key, a value and a signature".to_string()),
+        }
+    }
+    Ok((id, Ok(Answer { v, entries })))
+}
+
+// The programs a worker evaluates, by hash, each with the cache it keeps from
+// one demand to the next.
+#[derive(Debug, Default)]
+pub struct Worker {
+    programs: HashMap<Digest, (Runtime, Cache)>,
+}
+
+impl Worker {
+    pub fn new() -> Worker {
+        Worker {
+            programs: HashMap::new(),
+        }
+    }
+
+    // Load a program, returning its hash.
+    pub fn load(&mut self, program: L1Expression) -> Digest {
+        self.load_with_cache(program, Cache::new())
+    }
+
+    // Load a program which is evaluated in the cache, e.g. one with a
+    // keystore which signs the entries sent back.
+    pub fn load_with_cache(&mut self, program: L1Expression, c: Cache) -> Digest {
+        let runtime = Runtime::new(program);
+        let hash = runtime.hash();
+        self.programs.insert(hash, (runtime, c));
+        hash
+    }
+
+    pub fn answer(&mut self, demand: &Demand) -> Result<Answer, String> {
+        let (runtime, c) = match self.programs.get_mut(&demand.program) {
+            Some(&mut (ref mut runtime, ref mut c)) => (runtime, c),
+            None => return Err(format!("unknown program {}", demand.program.hex())),
+        };
+        c.mark();
+        let v = runtime
+            .demand_at(&demand.x, demand.k.clone(), c, &mut Meter::unlimited())
+            .map_err(|err| err.print())?;
+        let entries = c.export_since_mark();
+        Ok(Answer {
+            v,
+            entries,
+        })
+    }
+
+    // Answer the requests read from r on w until r ends.
+    pub fn serve<R: BufRead, W: Write>(&mut self, r: R, mut w: W) -> io::Result<()> {
+        for line in r.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let message = json::parse(&line);
+            let id = message
+                .as_ref()
+                .ok()
+                .and_then(|message| message.get("id"))
+                .and_then(Json::as_number);
+            let answer = message
+                .and_then(|message| parse_request(&message))
+                .and_then(|(_, demand)| self.answer(&demand));
+            writeln!(w, "{}", response(id, &answer).print())?;
+            w.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// A pool of worker processes which evaluate demands for a coordinator. The
+// responses of every worker are read by a thread of their own, so a worker is
+// never held up by a full pipe. A worker which does not answer a demand within
+// the timeout is killed and the demand goes to another one.
+#[derive(Debug)]
+pub struct WorkerPool {
+    children: Vec<Child>,
+    stdins: Vec<ChildStdin>,
+    responses: Receiver<(usize, io::Result<String>)>,
+    // The workers which exited, broke the protocol or timed out, which get no
+    // more demands.
+    failed: Vec<bool>,
+    pub timeout: Duration,
+    // The entries sent back which failed the checks of the keystore of the
+    // cache.
+    pub rejected: Vec<(Key, SignatureError)>,
+}
+
+impl WorkerPool {
+    // Start n workers with the command, e.g. `gaia worker fib.i`.
+    pub fn spawn<F: FnMut() -> Command>(n: usize, mut command: F) -> io::Result<WorkerPool> {
+        let (sender, responses) = channel();
+        let mut children = vec![];
+        let mut stdins = vec![];
+        for i in 0..n {
+            let mut child = command().stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+            stdins.push(child.stdin.take().unwrap());
+            let stdout = BufReader::new(child.stdout.take().unwrap());
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for line in stdout.lines() {
+                    let failed = line.is_err();
+                    if sender.send((i, line)).is_err() || failed {
+                        return;
+                    }
+                }
+                let exited = io::Error::new(io::ErrorKind::UnexpectedEof, "the worker exited");
+                let _ = sender.send((i, Err(exited)));
+            });
+            children.push(child);
+        }
+        Ok(WorkerPool {
+            children,
+            stdins,
+            responses,
+            failed: vec![false; n],
+            timeout: Duration::from_secs(60),
+            rejected: vec![],
+        })
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> WorkerPool {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    // Evaluate independent demands on the workers, one at a time at each, and
+    // import the entries they computed into the cache, with the signatures of
+    // the workers. The results are in the order of the demands.
+    pub fn demand_all(
+        &mut self,
+        demands: &[Demand],
+        c: &mut Cache,
+    ) -> Vec<Result<Either<Value, Domain>, WorkerError>> {
+        let mut results: Vec<Option<Result<Either<Value, Domain>, WorkerError>>> =
+            demands.iter().map(|_| None).collect();
+        // The demand each worker is evaluating and when it is due.
+        let mut busy: Vec<Option<(usize, Instant)>> = vec![None; self.len()];
+        let mut waiting: VecDeque<usize> = (0..demands.len()).collect();
+        loop {
+            for (i, slot) in busy.iter_mut().enumerate() {
+                let next = match waiting.front() {
+                    Some(&next) if slot.is_none() && !self.failed[i] => next,
+                    _ => continue,
+                };
+                let line = request(next as i64, &demands[next]).print();
+                match writeln!(self.stdins[i], "{}", line).and_then(|_| self.stdins[i].flush()) {
+                    Ok(()) => {
+                        *slot = Some((next, Instant::now() + self.timeout));
+                        waiting.pop_front();
+                    }
+
+                    // The demand goes to the next worker.
+                    Err(_) => self.failed[i] = true,
+                }
+            }
+            let due = match busy.iter().filter_map(|slot| slot.map(|(_, due)| due)).min() {
+                Some(due) => due,
+                None => break,
+            };
+            let wait = due.saturating_duration_since(Instant::now());
+            let (i, line) = match self.responses.recv_timeout(wait) {
+                Ok(response) => response,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.expire(&mut busy, &mut waiting);
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let demand = match busy[i].take() {
+                Some((demand, _)) => demand,
+                None => continue,
+            };
+            let response = line.map_err(WorkerError::Io).and_then(|line| {
+                json::parse(&line)
+                    .and_then(|message| parse_response(&message))
+                    .map_err(WorkerError::Protocol)
+            });
+            results[demand] = Some(match response {
+                Ok((id, _)) if id != demand as i64 => {
+                    self.failed[i] = true;
+                    let unexpected = format!("expected a response to {} but got {}", demand, id);
+                    Err(WorkerError::Protocol(unexpected))
+                }
+
+                Ok((_, Ok(answer))) => {
+                    for (key, v, signature) in answer.entries {
+                        if let Err(err) = c.import(key.clone(), v, signature) {
+                            self.rejected.push((key, err));
+                        }
+                    }
+                    Ok(answer.v)
+                }
+
+                Ok((_, Err(err))) => Err(WorkerError::Remote(err)),
+
+                Err(err) => {
+                    self.failed[i] = true;
+                    Err(err)
+                }
+            });
+        }
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or(Err(WorkerError::Unavailable)))
+            .collect()
+    }
+
+    // Give up on the workers whose demands are overdue, their demands are
+    // handed to the others first.
+    fn expire(&mut self, busy: &mut [Option<(usize, Instant)>], waiting: &mut VecDeque<usize>) {
+        let now = Instant::now();
+        for (i, slot) in busy.iter_mut().enumerate() {
+            if let Some((demand, due)) = *slot {
+                if due <= now {
+                    self.failed[i] = true;
+                    let _ = self.children[i].kill();
+                    *slot = None;
+                    waiting.push_front(demand);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    // Close the input of the workers, which ends them, and wait for them.
+    fn drop(&mut self) {
+        self.stdins.clear();
+        for child in &mut self.children {
+            let _ = child.wait();
+        }
+    }
+}
@@ -0,0 +1,79 @@
+extern crate gaia;
+
+mod common;
+
+use common::*;
+use gaia::evaluate_with_cache;
+use gaia::audit::{self, Claims, Verdict};
+use gaia::cache::Cache;
+use gaia::digest::sha256;
+use gaia::either::Either;
+use gaia::expression::Literal;
+use gaia::runtime::Runtime;
+use gaia::value::Value;
+
+#[test]
+fn test_spot_checks() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let mut runtime = Runtime::new(body[0].clone());
+    let mut peer = Cache::new();
+    evaluate_with_cache(body[0].clone(), &mut peer).unwrap();
+
+    // Every claim of an honest peer holds, each check evaluates one equation.
+    let claims = Claims::of(&peer);
+    let checks = audit::audit(&mut runtime, &claims, claims.len(), 1);
+    assert_eq!(checks.len(), claims.len());
+    for check in &checks {
+        assert!(check.is_consistent(), "{:?}", check);
+        assert!(check.recomputed.is_empty());
+        assert_eq!(check.stats.demands, 1);
+    }
+
+    // A peer which lies about fib [n <- 3] is caught there, and where the lie
+    // is used, while the rest of what it claims holds.
+    let three = fib_key(&peer, 3);
+    let four = fib_key(&peer, 4);
+    let mut liar = Cache::new();
+    for (key, v) in peer.iter() {
+        liar.add(key, v);
+    }
+    liar.add(three.clone(), Either::Left(Value::Literal(Literal::Int32(3))));
+    let claims = Claims::of(&liar);
+    match audit::spot_check(&mut runtime, &claims, &three).verdict {
+        Verdict::Inconsistent(v) => {
+            assert_eq!(v.expect_value(), Value::Literal(Literal::Int32(2)))
+        }
+        other => panic!("Expected an inconsistent claim, got {:?}", other),
+    }
+    assert!(!audit::spot_check(&mut runtime, &claims, &four).is_consistent());
+    assert!(audit::spot_check(&mut runtime, &claims, &fib_key(&peer, 2)).is_consistent());
+
+    // Dependencies which were not claimed are computed and reported.
+    let two = fib_key(&peer, 2);
+    let claims = Claims::new(peer.iter().filter(|(key, _)| *key != two));
+    let check = audit::spot_check(&mut runtime, &claims, &three);
+    assert!(check.is_consistent());
+    assert_eq!(check.recomputed, vec![two]);
+
+    // A claim for a key the program does not demand is not about it.
+    let mut other = three.clone();
+    other.digest = sha256(b"another program");
+    let claim = (other.clone(), Either::Left(Value::Literal(Literal::Int32(2))));
+    let claims = Claims::new(peer.iter().chain(vec![claim]));
+    match audit::spot_check(&mut runtime, &claims, &other).verdict {
+        Verdict::NotDemanded => (),
+        other => panic!("Expected a claim which is not demanded, got {:?}", other),
+    }
+
+    // A seed repeats an audit.
+    let claims = Claims::of(&peer);
+    let sample = |seed| -> Vec<_> {
+        audit::audit(&mut Runtime::new(body[0].clone()), &claims, 5, seed)
+            .into_iter()
+            .map(|check| check.key.digest)
+            .collect()
+    };
+    assert_eq!(sample(9), sample(9));
+    assert_eq!(sample(9).len(), 5);
+}
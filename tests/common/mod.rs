@@ -0,0 +1,42 @@
+// What the tests of the distributed cache share: the parser, the programs in
+// isrc and the entries of fib_stream.i. Not every test uses all of it.
+#![allow(dead_code)]
+
+use gaia::cache::{Cache, Key};
+use gaia::expression::Literal;
+use gaia::value::{Dimension, Value};
+use std::fs::File;
+use std::io::prelude::*;
+
+mod grammar {
+    include!(concat!(env!("OUT_DIR"), "/grammar.rs"));
+}
+
+pub use self::grammar::*;
+
+pub fn read_source(filename: &str) -> String {
+    let mut f = File::open(filename).expect("File not found");
+    let mut source = String::new();
+    f.read_to_string(&mut source)
+        .expect("Something went wrong reading source");
+    source
+}
+
+// The key of the entry for fib [n <- ord] of isrc/fib_stream.i. The context
+// binds n to a hidden dimension, which holds the ordinate.
+pub fn fib_key(c: &Cache, ord: u32) -> Key {
+    let n = Dimension {
+        i: 0,
+        v: Value::Identifier("n".to_string()),
+    };
+    c.iter()
+        .map(|(key, _)| key)
+        .find(|key| {
+            let hidden = match key.k.lookup(n.clone()) {
+                Some(Value::Dimension(hidden)) => key.k.lookup(*hidden),
+                _ => None,
+            };
+            key.x == "fib" && hidden == Some(Value::Literal(Literal::Int32(ord)))
+        })
+        .unwrap()
+}
@@ -1,12 +1,30 @@
 extern crate colored;
 extern crate gaia;
 
-use gaia::evaluate;
-use gaia::value::Value;
-use gaia::expression::Literal;
+use gaia::{compile, complete, evaluate, evaluate_partial, evaluate_with_cache, evaluate_with_limits,
+           evaluate_with_meter, evaluate_with_provider, lint, load_cache, primitive_environment,
+           save_cache, try_evaluate};
+use gaia::cancel::CancellationToken;
+use gaia::error::{EvalError, LoadError};
+use gaia::limits::{EvalLimits, Limit, Meter};
+use gaia::gas::{CostModel, ExpressionKind};
+use gaia::provider::NoProvider;
+use gaia::retirement::{IdentifierCap, Lru, MaxAge, RetirementAge};
+use gaia::cache::Cache;
+use gaia::digest::sha256;
+use gaia::encoding::{decode, digest, encode};
+use gaia::runtime::Runtime;
+use gaia::backend::{CacheBackend, DiskBackend, HashMapBackend, LruBackend, ShardedBackend};
+use gaia::context::Context;
+use gaia::either::Either;
+use gaia::value::{Dimension, Value};
+use gaia::expression::{Expression, Literal};
+use gaia::residual::evaluate_residual;
 use std::fs::File;
 use std::io::prelude::*;
 use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use std::{env, fs, thread};
 use gaia::transform_l1_dimensions;
 
 mod grammar {
@@ -90,6 +108,30 @@ fn test_query() {
     assert!(expression("#.t").is_ok());
 }
 
+#[test]
+fn test_context_query() {
+    assert!(expression("#").is_ok());
+    assert!(expression("X @ (# ++ [t <- 0])").is_ok());
+    assert!(expression("X @ (K <| dom!K)").is_ok());
+}
+
+#[test]
+fn test_context() {
+    let context_source_1 = read_source("./isrc/context_1.i");
+    let context_source_2 = read_source("./isrc/context_2.i");
+
+    let context_test_1 = scope(context_source_1.as_ref()).unwrap();
+    let context_test_2 = scope(context_source_2.as_ref()).unwrap();
+
+    let context_test_1_result = evaluate(context_test_1[0].clone()).expect_value();
+
+    assert_eq!(Value::Literal(Literal::Int32(5)), context_test_1_result);
+
+    let context_test_2_result = evaluate(context_test_2[0].clone()).expect_value();
+
+    assert_eq!(Value::Literal(Literal::Int32(19)), context_test_2_result);
+}
+
 #[test]
 fn test_variable_declaration() {
     assert!(function_or_variable_declaration("x = 0").is_ok());
@@ -137,6 +179,290 @@ fn test_intension() {
     assert_eq!(Value::Literal(Literal::Int32(3)), intension_test_2_result);
 }
 
+#[test]
+fn test_inferred_intension() {
+    assert!(expression("{?} #.t").is_ok());
+    assert!(expression("↑#.t").is_ok());
+    assert!(expression("{#} #.t").is_ok());
+
+    let intension_source_3 = read_source("./isrc/intension_3.i");
+    let intension_source_4 = read_source("./isrc/intension_4.i");
+
+    let intension_test_3 = scope(intension_source_3.as_ref()).unwrap();
+    let intension_test_4 = scope(intension_source_4.as_ref()).unwrap();
+
+    let intension_test_3_result = evaluate(intension_test_3[0].clone()).expect_value();
+
+    assert_eq!(Value::Literal(Literal::Int32(0)), intension_test_3_result);
+
+    let intension_test_4_result = evaluate(intension_test_4[0].clone()).expect_value();
+
+    assert_eq!(Value::Literal(Literal::Int32(2)), intension_test_4_result);
+
+    // A body which queries the whole context freezes all of it.
+    let intension_source_5 = read_source("./isrc/intension_5.i");
+    let intension_test_5 = scope(intension_source_5.as_ref()).unwrap();
+    let intension_test_5_result = evaluate(intension_test_5[0].clone()).expect_value();
+    assert_eq!(Value::Literal(Literal::Int32(0)), intension_test_5_result);
+
+    // The intensions a body applies are found through conditionals, and
+    // what their bodies use which they do not freeze is frozen.
+    let intension_source_6 = read_source("./isrc/intension_6.i");
+    let intension_test_6 = scope(intension_source_6.as_ref()).unwrap();
+    let intension_test_6_result = evaluate(intension_test_6[0].clone()).expect_value();
+    assert_eq!(Value::Literal(Literal::Int32(2)), intension_test_6_result);
+}
+
+#[test]
+fn test_intension_lint() {
+    let intension_source_1 = read_source("./isrc/intension_1.i");
+    let intension_source_2 = read_source("./isrc/intension_2.i");
+
+    let intension_test_1 = scope(intension_source_1.as_ref()).unwrap();
+    let intension_test_2 = scope(intension_source_2.as_ref()).unwrap();
+
+    assert_eq!(lint(intension_test_1[0].clone()).len(), 0);
+
+    let lints = lint(intension_test_2[0].clone());
+    assert_eq!(lints.len(), 1);
+    assert_eq!(lints[0].missing.0.len(), 1);
+    assert_eq!(lints[0].unused.0.len(), 0);
+}
+
+#[test]
+fn test_residual() {
+    let residual_source_1 = read_source("./isrc/residual_1.i");
+    let residual_test_1 = scope(residual_source_1.as_ref()).unwrap();
+
+    let mut c = Cache::new();
+    let residual = match evaluate_partial(residual_test_1[0].clone(), &mut c).unwrap() {
+        Either::Right(r) => r,
+        Either::Left(l) => panic!("Expected residual but here found {:?}", l),
+    };
+    assert_eq!(residual.missing.0.len(), 1);
+
+    let mut k = Context::new();
+    for di in residual.missing.to_vec() {
+        k.push(di, Value::Literal(Literal::Int32(7)));
+    }
+    let residual_test_1_result = match complete(residual, k, &mut c).unwrap() {
+        Either::Left(l) => l,
+        Either::Right(r) => panic!("Expected value but here found {}", r.print()),
+    };
+
+    assert_eq!(Value::Literal(Literal::Int32(12)), residual_test_1_result);
+
+    // The residual keeps its where clauses, the environment it was
+    // evaluated in is left as it was.
+    let (x, k, d) = compile(residual_test_1[0].clone());
+    let e = primitive_environment();
+    let defined = e.0.len();
+    let mut m = Meter::unlimited();
+    let v = evaluate_residual(x, &e, k, d.clone(), d, &mut Cache::new(), &mut NoProvider, &mut m);
+    assert!(v.unwrap().right().is_some());
+    assert_eq!(e.0.len(), defined);
+
+    // Residuals are rebuilt on the stack of the evaluator, however deeply
+    // the intensions nest.
+    let residual_source_2 = read_source("./isrc/residual_2.i");
+    let residual_test_2 = scope(residual_source_2.as_ref()).unwrap();
+    let residual = evaluate_partial(residual_test_2[0].clone(), &mut c).unwrap().right().unwrap();
+    assert_eq!(residual.missing.0.len(), 1);
+    let mut k = Context::new();
+    for di in residual.missing.to_vec() {
+        k.push(di, Value::Literal(Literal::Int32(7)));
+    }
+    let v = complete(residual, k, &mut c).unwrap();
+    assert_eq!(Value::Literal(Literal::Int32(307)), v.left().unwrap());
+
+    // Expressions which cannot be evaluated are reported, not panicked on.
+    let invalid = scope("if 1 then 2 else #.t where dim t <- 0 end").unwrap();
+    match evaluate_partial(invalid[0].clone(), &mut Cache::new()) {
+        Err(EvalError::Invalid(message)) => assert!(message.starts_with("expected a boolean")),
+        other => panic!("Expected an invalid expression but here found {:?}", other),
+    }
+}
+
+#[test]
+fn test_context_provider() {
+    let provider_source_1 = read_source("./isrc/residual_1.i");
+    let provider_test_1 = scope(provider_source_1.as_ref()).unwrap();
+
+    let mut asked = vec![];
+    let provider_test_1_result = evaluate_with_provider(provider_test_1[0].clone(), &mut |di: &Dimension| {
+        asked.push(di.clone());
+        Some(Value::Literal(Literal::Int32(7)))
+    }).unwrap();
+    assert_eq!(asked.len(), 1);
+    match provider_test_1_result {
+        Either::Left(l) => assert_eq!(Value::Literal(Literal::Int32(12)), l),
+        Either::Right(m) => panic!("Expected value but here found missing {}", m.print()),
+    }
+
+    let provider_test_2_result = evaluate_with_provider(provider_test_1[0].clone(), &mut |_: &Dimension| None).unwrap();
+    match provider_test_2_result {
+        Either::Right(m) => assert_eq!(m.0.len(), 1),
+        Either::Left(l) => panic!("Expected missing dimensions but here found {:?}", l),
+    }
+}
+
+#[test]
+fn test_cycle() {
+    let cycle_source_1 = read_source("./isrc/cycle_1.i");
+    let cycle_test_1 = scope(cycle_source_1.as_ref()).unwrap();
+    match try_evaluate(cycle_test_1[0].clone()) {
+        Err(EvalError::CyclicDemand(chain)) => {
+            assert_eq!(chain.len(), 2);
+            assert_eq!(chain[0], chain[1]);
+        }
+        other => panic!("Expected cyclic demand but here found {:?}", other),
+    }
+
+    let cycle_source_2 = read_source("./isrc/cycle_2.i");
+    let cycle_test_2 = scope(cycle_source_2.as_ref()).unwrap();
+    match try_evaluate(cycle_test_2[0].clone()) {
+        Err(EvalError::CyclicDemand(chain)) => {
+            let ids: Vec<String> = chain.iter().map(|key| key.x.clone()).collect();
+            assert_eq!(ids, vec!["A", "B", "A"]);
+        }
+        other => panic!("Expected cyclic demand but here found {:?}", other),
+    }
+}
+
+#[test]
+fn test_limits() {
+    let limits_source = read_source("./isrc/fib.i");
+    let limits_test = scope(limits_source.as_ref()).unwrap();
+
+    let (v, stats) = evaluate_with_limits(limits_test[0].clone(), EvalLimits::unlimited()).unwrap();
+    match v {
+        Either::Left(l) => assert_eq!(Value::Literal(Literal::Int32(2)), l),
+        Either::Right(m) => panic!("Expected value but here found missing {}", m.print()),
+    }
+    assert!(stats.steps > 0);
+    assert!(stats.max_depth > 1);
+    assert!(stats.cache_entries > 0);
+
+    let limits = vec![
+        (EvalLimits::unlimited().max_depth(2), Limit::Depth),
+        (EvalLimits::unlimited().max_cache_entries(3), Limit::CacheEntries),
+        (EvalLimits::unlimited().max_fuel(20), Limit::Fuel),
+        (EvalLimits::unlimited().timeout(Duration::from_secs(0)), Limit::Deadline),
+    ];
+    for (limits, expected) in limits {
+        let limits_test_source = read_source("./isrc/naturals.i");
+        let limits_test = scope(limits_test_source.as_ref()).unwrap();
+        match evaluate_with_limits(limits_test[0].clone(), limits.clone()) {
+            Err(EvalError::LimitExceeded(limit, stats)) => {
+                assert_eq!(expected, limit);
+                assert!(stats.steps > 0);
+                if let Some(max_depth) = limits.max_depth {
+                    assert!(stats.max_depth <= max_depth + 1);
+                }
+                if let Some(max_cache_entries) = limits.max_cache_entries {
+                    assert!(stats.cache_entries <= max_cache_entries);
+                }
+                if let Some(max_fuel) = limits.max_fuel {
+                    assert_eq!(stats.steps, max_fuel + 1);
+                }
+            }
+            other => panic!("Expected {} limit exceeded but here found {:?}", expected.print(), other),
+        }
+    }
+}
+
+#[test]
+fn test_gas() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let run_with_cache = |limits: EvalLimits, costs: CostModel, c: &mut Cache| {
+        let mut m = Meter::new(limits).with_costs(costs);
+        let v = evaluate_with_meter(body[0].clone(), c, &mut NoProvider, &mut m);
+        (v, m.stats())
+    };
+    let run = |limits: EvalLimits, costs: CostModel| run_with_cache(limits, costs, &mut Cache::new());
+
+    // Every run of the program from an empty cache is charged the same.
+    let (v, stats) = run(EvalLimits::unlimited(), CostModel::default());
+    assert_eq!(v.unwrap().expect_value(), Value::Literal(Literal::Int32(55)));
+    let gas = stats.gas;
+    assert!(gas > stats.steps + stats.demands);
+    assert_eq!(run(EvalLimits::unlimited(), CostModel::default()).1.gas, gas);
+
+    // The same run from a cache which already holds some or all of the
+    // entries is charged the same, a demand found in the cache is charged
+    // what its entry was charged when it was computed.
+    let mut c = Cache::new();
+    assert_eq!(run_with_cache(EvalLimits::unlimited(), CostModel::default(), &mut c).1.gas, gas);
+    let (v, warm) = run_with_cache(EvalLimits::unlimited(), CostModel::default(), &mut c);
+    assert_eq!(v.unwrap().expect_value(), Value::Literal(Literal::Int32(55)));
+    assert_eq!(warm.demands, 0);
+    assert_eq!(warm.gas, gas);
+    let fib_10 = Either::Left(Value::Literal(Literal::Int32(55)));
+    let key = c.iter().find(|entry| encode(&entry.1) == encode(&fib_10)).unwrap().0;
+    c.remove(&key);
+    let (_, stats) = run_with_cache(EvalLimits::unlimited(), CostModel::default(), &mut c);
+    assert_eq!(stats.demands, 1);
+    assert_eq!(stats.gas, gas);
+
+    // Each part of the cost model is charged for what it prices. Every demand,
+    // found in the cache or not, is charged one miss and the conditional of
+    // the equation of fib.
+    let (_, misses) = run(EvalLimits::unlimited(), CostModel::uniform(0).cache_miss(3));
+    let (_, ifs) = run(EvalLimits::unlimited(), CostModel::uniform(0).expression(ExpressionKind::If, 1));
+    assert_eq!(misses.gas, 3 * ifs.gas);
+    assert!(ifs.gas > ifs.demands);
+    // fib of 10 computed without a cache adds 88 times and subtracts twice
+    // for each addition.
+    let (_, plus) = run(EvalLimits::unlimited(), CostModel::uniform(0).primitive("+", 1));
+    let (_, minus) = run(EvalLimits::unlimited(), CostModel::uniform(0).primitive("-", 1));
+    assert_eq!(plus.gas, 88);
+    assert_eq!(minus.gas, 176);
+
+    // The evaluation stops once it is charged more than its budget.
+    let (v, stats) = run(EvalLimits::unlimited().max_gas(gas), CostModel::default());
+    assert!(v.is_ok());
+    assert_eq!(stats.gas, gas);
+    match run(EvalLimits::unlimited().max_gas(gas - 1), CostModel::default()).0 {
+        Err(EvalError::LimitExceeded(Limit::Gas, stats)) => assert_eq!(stats.gas, gas),
+        other => panic!("Expected gas limit exceeded but here found {:?}", other),
+    }
+}
+
+#[test]
+fn test_cancellation() {
+    let cancel_source = read_source("./isrc/cancel_1.i");
+    let cancel_test = scope(cancel_source.as_ref()).unwrap();
+
+    // The token is cancelled in the middle of the evaluation, when 'Y' asks
+    // for its dimension.
+    let token = CancellationToken::new();
+    let mut c = Cache::new();
+    let mut m = Meter::new(EvalLimits::unlimited()).with_token(token.clone());
+    let mut provider = |_: &Dimension| {
+        token.cancel();
+        Some(Value::Literal(Literal::Int32(7)))
+    };
+    let cancel_test_1_result = evaluate_with_meter(cancel_test[0].clone(), &mut c, &mut provider, &mut m);
+    match cancel_test_1_result {
+        Err(EvalError::Cancelled(stats)) => assert!(stats.steps > 0),
+        other => panic!("Expected cancellation but here found {:?}", other),
+    }
+    assert!(c.pending.is_empty());
+    let entries = c.len();
+    assert!(entries > 0);
+
+    // The cache is reused by the next evaluation.
+    let mut m = Meter::unlimited();
+    let mut provider = |_: &Dimension| Some(Value::Literal(Literal::Int32(7)));
+    let cancel_test_2_result = evaluate_with_meter(cancel_test[0].clone(), &mut c, &mut provider, &mut m).unwrap();
+    match cancel_test_2_result {
+        Either::Left(l) => assert_eq!(Value::Literal(Literal::Int32(12)), l),
+        Either::Right(m) => panic!("Expected value but here found missing {}", m.print()),
+    }
+    assert!(c.len() >= entries);
+}
+
 #[test]
 fn test_fib_stream() {
     let fib_source = read_source("./isrc/fib_stream.i");
@@ -187,6 +513,289 @@ fn test_naturals() {
     // println!("result == {:?}", result.clone());
 }
 
+#[test]
+fn test_naturals_deep() {
+    let naturals_source = read_source("./isrc/naturals_deep.i");
+    let body = scope(naturals_source.as_ref()).unwrap();
+    let (result, stats) = evaluate_with_limits(body[0].clone(), EvalLimits::unlimited()).unwrap();
+    match result {
+        Either::Left(l) => assert_eq!(Value::Literal(Literal::Int32(5000)), l),
+        Either::Right(m) => panic!("Expected value but here found missing {}", m.print()),
+    }
+    assert!(stats.max_depth > 5000);
+}
+
+#[test]
+fn test_retirement() {
+    let sum_source = read_source("./isrc/running_sum.i");
+    let body = scope(sum_source.as_ref()).unwrap();
+    let sum = Value::Literal(Literal::Int32(499500));
+
+    let mut c = Cache::new();
+    let v = evaluate_with_meter(body[0].clone(), &mut c, &mut NoProvider, &mut Meter::unlimited());
+    assert_eq!(sum, v.unwrap().expect_value());
+    let entries = c.len();
+    assert_eq!(c.evictions.total, 0);
+
+    let mut c = Cache::new().with_policy(RetirementAge(16));
+    let v = evaluate_with_meter(body[0].clone(), &mut c, &mut NoProvider, &mut Meter::unlimited());
+    assert_eq!(sum, v.unwrap().expect_value());
+    assert!(c.len() < 20);
+    assert_eq!(c.evictions.total as usize, entries - c.len());
+
+    let mut c = Cache::new().with_policy(Lru(8));
+    let v = evaluate_with_meter(body[0].clone(), &mut c, &mut NoProvider, &mut Meter::unlimited());
+    assert_eq!(sum, v.unwrap().expect_value());
+    assert!(c.len() <= 8);
+    assert_eq!(c.evictions.by_policy["lru"], c.evictions.total);
+
+    let mut c = Cache::new().with_policy(MaxAge::new(32));
+    let v = evaluate_with_meter(body[0].clone(), &mut c, &mut NoProvider, &mut Meter::unlimited());
+    assert_eq!(sum, v.unwrap().expect_value());
+    assert!(c.len() <= 33);
+
+    // Retired points of 'fib' are recomputed when they are demanded again.
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let mut c = Cache::new().with_policy(IdentifierCap::new().cap("fib", 2));
+    let v = evaluate_with_meter(body[0].clone(), &mut c, &mut NoProvider, &mut Meter::unlimited());
+    assert_eq!(Value::Literal(Literal::Int32(55)), v.unwrap().expect_value());
+    assert!(c.count_of(&"fib".to_string()) <= 2);
+    assert!(c.evictions.by_identifier["fib"] > 0);
+    let v = evaluate_with_meter(body[0].clone(), &mut c, &mut NoProvider, &mut Meter::unlimited());
+    assert_eq!(Value::Literal(Literal::Int32(55)), v.unwrap().expect_value());
+}
+
+#[test]
+fn test_backends() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let fib = Value::Literal(Literal::Int32(55));
+
+    let mut c = Cache::with_backend(HashMapBackend::new());
+    assert_eq!(fib, evaluate_with_cache(body[0].clone(), &mut c).unwrap().expect_value());
+    let entries = c.len();
+    assert_eq!(entries, c.iter().count());
+
+    let mut c = Cache::with_backend(LruBackend::new(4));
+    assert_eq!(fib, evaluate_with_cache(body[0].clone(), &mut c).unwrap().expect_value());
+    assert!(c.len() <= 4);
+
+    // Clones of a sharded backend share their entries across threads.
+    let shared = ShardedBackend::new(8);
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let backend = shared.clone();
+            let x = body[0].clone();
+            thread::spawn(move || {
+                let mut c = Cache::with_backend(backend);
+                evaluate_with_cache(x, &mut c).unwrap().expect_value()
+            })
+        })
+        .collect();
+    for t in threads {
+        assert_eq!(fib, t.join().unwrap());
+    }
+    assert_eq!(entries, shared.len());
+
+    // The entries on disk are found again after reopening the store.
+    let path = env::temp_dir().join(format!("gaia-cache-{}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut c = Cache::with_backend(DiskBackend::open(&path).unwrap());
+    assert_eq!(fib, evaluate_with_cache(body[0].clone(), &mut c).unwrap().expect_value());
+    assert_eq!(entries, c.len());
+    drop(c);
+    let mut c = Cache::with_backend(DiskBackend::open(&path).unwrap());
+    assert_eq!(entries, c.len());
+    let mut m = Meter::unlimited();
+    let v = evaluate_with_meter(body[0].clone(), &mut c, &mut NoProvider, &mut m);
+    assert_eq!(fib, v.unwrap().expect_value());
+    assert_eq!(m.stats().demands, 0);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_save_cache() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let fib = Value::Literal(Literal::Int32(55));
+    let path = env::temp_dir().join(format!("gaia-extension-{}", std::process::id()));
+
+    let mut c = Cache::new();
+    assert_eq!(fib, evaluate_with_cache(body[0].clone(), &mut c).unwrap().expect_value());
+    save_cache(body[0].clone(), &c, &path).unwrap();
+
+    // The next run starts warm.
+    let mut warm = load_cache(body[0].clone(), &path).unwrap();
+    assert_eq!(c.len(), warm.len());
+    let mut m = Meter::unlimited();
+    let v = evaluate_with_meter(body[0].clone(), &mut warm, &mut NoProvider, &mut m);
+    assert_eq!(fib, v.unwrap().expect_value());
+    assert_eq!(m.stats().demands, 0);
+
+    // A changed definition of 'fib' refuses the saved entries.
+    let changed = fib_source.replace("#.n <= 1", "#.n <= 2");
+    let body = scope(changed.as_ref()).unwrap();
+    match load_cache(body[0].clone(), &path) {
+        Err(LoadError::ProgramChanged) => (),
+        other => panic!("Expected a changed program, got {:?}", other),
+    }
+
+    File::create(&path).unwrap().write_all(b"not a cache").unwrap();
+    match load_cache(body[0].clone(), &path) {
+        Err(LoadError::Format(_)) => (),
+        other => panic!("Expected a format error, got {:?}", other),
+    }
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_runtime() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let mut runtime = Runtime::new(body[0].clone());
+    assert_eq!(Value::Literal(Literal::Int32(55)), runtime.run().unwrap().expect_value());
+
+    // Later queries find what earlier ones computed.
+    let v = runtime.eval("fib", &[("n", 20)]).unwrap();
+    assert_eq!(Value::Literal(Literal::Int32(6765)), v.expect_value());
+    let entries = runtime.cache.len();
+    let v = runtime.eval("fib", &[("n", 15)]).unwrap();
+    assert_eq!(Value::Literal(Literal::Int32(610)), v.expect_value());
+    assert_eq!(entries, runtime.cache.len());
+
+    let v = runtime.query(expression("fib @ [n <- 3] + fib @ [n <- 4]").unwrap());
+    assert_eq!(Value::Literal(Literal::Int32(5)), v.unwrap().expect_value());
+
+    // A new definition keeps the cache, a replaced one starts afresh.
+    runtime.define("double", expression("fib * 2").unwrap());
+    let v = runtime.eval("double", &[("n", 10)]).unwrap();
+    assert_eq!(Value::Literal(Literal::Int32(110)), v.expect_value());
+    assert!(runtime.cache.len() > entries);
+
+    let lucas = "if #.n <= 1 then 2 - #.n else fib @ [n <- #.n - 1] + fib @ [n <- #.n - 2]";
+    runtime.define("fib", expression(lucas).unwrap());
+    let v = runtime.eval("double", &[("n", 10)]).unwrap();
+    assert_eq!(Value::Literal(Literal::Int32(246)), v.expect_value());
+}
+
+#[test]
+fn test_invalidation() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let mut runtime = Runtime::new(body[0].clone());
+    runtime.define("double", expression("fib * 2").unwrap());
+    runtime.define("square", expression("#.n * #.n").unwrap());
+    runtime.eval("double", &[("n", 10)]).unwrap();
+    runtime.eval("square", &[("n", 10)]).unwrap();
+    let entries = runtime.cache.len();
+
+    // Only 'fib' and what read it go, 'square' stays.
+    let fib = runtime.definition("fib");
+    let removed = runtime.cache.invalidate(&"fib".to_string(), &fib);
+    assert!(removed.iter().any(|key| key.x == "double"));
+    assert!(removed.iter().all(|key| key.x == "fib" || key.x == "double"));
+    assert_eq!(entries - removed.len(), runtime.cache.len());
+    assert!(runtime.cache.iter().all(|(key, _)| key.x == "square"));
+
+    let lucas = "if #.n <= 1 then 2 - #.n else fib @ [n <- #.n - 1] + fib @ [n <- #.n - 2]";
+    runtime.define("fib", expression(lucas).unwrap());
+    let v = runtime.eval("double", &[("n", 10)]).unwrap();
+    assert_eq!(Value::Literal(Literal::Int32(246)), v.expect_value());
+    runtime.define("square", expression("#.n + #.n").unwrap());
+    assert!(runtime.cache.iter().all(|(key, _)| key.x != "square"));
+    assert!(runtime.cache.iter().any(|(key, _)| key.x == "double"));
+}
+
+#[test]
+fn test_digest() {
+    assert_eq!(
+        sha256(b"").hex(),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        sha256(b"abc").hex(),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    let long = [b'a'; 1000];
+    assert_eq!(
+        sha256(&long).hex(),
+        "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+    );
+}
+
+#[test]
+fn test_content_addressed_keys() {
+    let plus = scope("X @ [t <- 3] where dim t <- 0 X = #.t + 1 end").unwrap();
+    let times = scope("X @ [t <- 3] where dim t <- 0 X = #.t * 2 end").unwrap();
+    let again = scope("X @ [t <- 3] + 1 where dim t <- 0 X = #.t + 1 end").unwrap();
+
+    // The same name for different equations does not share entries.
+    let mut c = Cache::new();
+    let v = evaluate_with_cache(plus[0].clone(), &mut c).unwrap();
+    assert_eq!(Value::Literal(Literal::Int32(4)), v.expect_value());
+    let v = evaluate_with_cache(times[0].clone(), &mut c).unwrap();
+    assert_eq!(Value::Literal(Literal::Int32(6)), v.expect_value());
+
+    // The same equation in another program does.
+    let mut m = Meter::unlimited();
+    let v = evaluate_with_meter(again[0].clone(), &mut c, &mut NoProvider, &mut m);
+    assert_eq!(Value::Literal(Literal::Int32(5)), v.unwrap().expect_value());
+    assert_eq!(m.stats().demands, 0);
+
+    // Invalidating X of one program leaves the X of the other.
+    let x = Runtime::new(plus[0].clone()).definition("X");
+    let removed = c.invalidate(&"X".to_string(), &x);
+    assert!(!removed.is_empty());
+    assert_eq!(c.len(), removed.len());
+    let mut m = Meter::unlimited();
+    let v = evaluate_with_meter(times[0].clone(), &mut c, &mut NoProvider, &mut m);
+    assert_eq!(Value::Literal(Literal::Int32(6)), v.unwrap().expect_value());
+    assert_eq!(m.stats().demands, 0);
+}
+
+#[test]
+fn test_canonical_encoding() {
+    let t = Dimension {
+        i: 0,
+        v: Value::Identifier("t".to_string()),
+    };
+    let s = Dimension {
+        i: 1,
+        v: Value::Identifier("s".to_string()),
+    };
+    let one = Value::Literal(Literal::Int32(1));
+    let two = Value::Literal(Literal::Int32(2));
+
+    // Equal contexts and domains encode identically whatever their order.
+    let mut k1 = Context::new();
+    k1.push(t.clone(), one.clone());
+    k1.push(s.clone(), two.clone());
+    let mut k2 = Context::new();
+    k2.push(s.clone(), two.clone());
+    k2.push(t.clone(), one.clone());
+    assert_eq!(encode(&k1), encode(&k2));
+    assert_eq!(digest(&k1), digest(&k2));
+    assert_eq!(encode(&k1.domain()), encode(&k2.domain()));
+    let mut k3 = Context::new();
+    k3.push(t.clone(), two.clone());
+    k3.push(s.clone(), two.clone());
+    assert!(digest(&k1) != digest(&k3));
+
+    // Digests are stable from run to run.
+    assert_eq!(
+        digest(&Value::Context(k1.clone())).hex(),
+        "0f80b57e48b295270a8aaab584207d48d845bb30b5ec5ed626a792930c0c2b33"
+    );
+
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let (x, k, _) = compile(body[0].clone());
+    assert_eq!(x, decode::<Expression>(&encode(&x)).unwrap());
+    assert_eq!(encode(&k), encode(&decode::<Context>(&encode(&k)).unwrap()));
+    assert!(decode::<Expression>(&encode(&x)[1..]).is_err());
+}
+
 #[test]
 fn test_wvr() {
     let wvr_source = read_source("./isrc/wvr.i");
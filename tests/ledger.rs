@@ -0,0 +1,110 @@
+extern crate gaia;
+
+mod common;
+
+use common::*;
+use gaia::{compile, evaluate_with_cache, evaluate_with_meter};
+use gaia::cache::Cache;
+use gaia::either::Either;
+use gaia::error::LedgerError;
+use gaia::expression::Literal;
+use gaia::ledger::{self, Ledger};
+use gaia::limits::Meter;
+use gaia::provider::NoProvider;
+use gaia::value::Value;
+use std::{env, fs};
+
+#[test]
+fn test_ledger() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let dir = env::temp_dir();
+    let path = dir.join(format!("gaia-ledger-{}", std::process::id()));
+    let copy = dir.join(format!("gaia-ledger-copy-{}", std::process::id()));
+    let other = dir.join(format!("gaia-ledger-other-{}", std::process::id()));
+
+    // Every insertion is recorded.
+    let mut c = Cache::new().with_ledger(Ledger::create(&path).unwrap());
+    evaluate_with_cache(body[0].clone(), &mut c).unwrap();
+    let head = c.ledger.as_ref().unwrap().head();
+    assert!(head.len >= c.len() as u64);
+    assert_eq!(Ledger::open(&path).unwrap().head(), head);
+
+    // Replaying the ledger rebuilds the cache.
+    let mut rebuilt = Cache::new();
+    assert_eq!(rebuilt.replay(&path, head).unwrap() as u64, head.len);
+    let mut m = Meter::unlimited();
+    let v = evaluate_with_meter(body[0].clone(), &mut rebuilt, &mut NoProvider, &mut m);
+    assert_eq!(v.unwrap().expect_value(), Value::Literal(Literal::Int32(55)));
+    assert_eq!(m.stats().demands, 0);
+
+    // What is replayed or loaded into a cache was recorded before, it is not
+    // recorded again.
+    let mut rebuilt = Cache::new().with_ledger(Ledger::create(&other).unwrap());
+    rebuilt.replay(&path, head).unwrap();
+    let (program, _, _) = compile(body[0].clone());
+    c.save(&copy, &program).unwrap();
+    rebuilt.load(&copy, &program).unwrap();
+    assert_eq!(rebuilt.ledger.as_ref().unwrap().head().len, 0);
+
+    // A peer receives the records as they are written and checks that they
+    // continue its chain.
+    let records = ledger::replay(&path, head).unwrap();
+    let half = records.len() / 2;
+    let mut peer = Ledger::create(&copy).unwrap();
+    peer.extend(&records[..half]).unwrap();
+    let rest = c.ledger.as_mut().unwrap().records_since(half as u64).unwrap();
+    assert_eq!(peer.extend(&rest).unwrap(), head);
+    match peer.extend(&records[..1]) {
+        Err(LedgerError::BrokenChain(n)) => assert_eq!(n, head.len),
+        other => panic!("Expected a broken chain, got {:?}", other),
+    }
+
+    // Damage is found where it is.
+    let bytes = fs::read(&path).unwrap();
+    let last = head.len - 1;
+    let mut tampered = bytes.clone();
+    let at = tampered.len() - 40;
+    tampered[at] ^= 1;
+    fs::write(&copy, &tampered).unwrap();
+    match ledger::replay(&copy, head) {
+        Err(LedgerError::Tampered(n)) => assert_eq!(n, last),
+        other => panic!("Expected a tampered record, got {:?}", other),
+    }
+    fs::write(&copy, &bytes[..bytes.len() - 10]).unwrap();
+    match Cache::new().replay(&copy, head) {
+        Err(LedgerError::Truncated(n)) => assert_eq!(n, last),
+        other => panic!("Expected a truncated ledger, got {:?}", other),
+    }
+    // Without its first record the second one no longer follows.
+    let header = b"gaia-ledger".len() + 4;
+    let len = bytes[header..header + 4].iter().fold(0, |n, &b| n << 8 | b as usize);
+    let first = 4 + len + 32;
+    let mut dropped = bytes[..header].to_vec();
+    dropped.extend_from_slice(&bytes[header + first..]);
+    fs::write(&copy, &dropped).unwrap();
+    match ledger::replay(&copy, head) {
+        Err(LedgerError::BrokenChain(n)) => assert_eq!(n, 0),
+        other => panic!("Expected a broken chain, got {:?}", other),
+    }
+    // A ledger cut between two records, or whose last record was rewritten
+    // along with its hash, does not end at the head.
+    let mut cut = Ledger::create(&copy).unwrap();
+    let shorter = cut.extend(&records[..records.len() - 1]).unwrap();
+    match ledger::replay(&copy, head) {
+        Err(LedgerError::UnexpectedHead(expected, found)) => {
+            assert_eq!((expected, found), (head, shorter))
+        }
+        other => panic!("Expected another head, got {:?}", other),
+    }
+    let forged = Either::Left(Value::Literal(Literal::Int32(1000)));
+    let rewritten = cut.append(records[last as usize].key.clone(), forged, None).unwrap();
+    assert_eq!(rewritten.len, head.len);
+    match ledger::replay(&copy, head) {
+        Err(LedgerError::UnexpectedHead(_, found)) => assert_eq!(found, rewritten),
+        other => panic!("Expected another head, got {:?}", other),
+    }
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(&copy);
+    let _ = fs::remove_file(&other);
+}
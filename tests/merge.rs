@@ -0,0 +1,82 @@
+extern crate gaia;
+
+mod common;
+
+use common::*;
+use gaia::{evaluate_with_cache, evaluate_with_meter};
+use gaia::cache::Cache;
+use gaia::either::Either;
+use gaia::expression::{L1Expression, Literal};
+use gaia::limits::Meter;
+use gaia::merge::{Recompute, Resolution, TakeTheirs};
+use gaia::provider::NoProvider;
+use gaia::signature::MemoryKeystore;
+use gaia::value::Value;
+
+#[test]
+fn test_merge() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let ten = scope(fib_source.as_ref()).unwrap();
+    let twelve = scope(fib_source.replace("[n <- 10]", "[n <- 12]").as_ref()).unwrap();
+    let compute = |program: &L1Expression| {
+        let mut c = Cache::new();
+        evaluate_with_cache(program.clone(), &mut c).unwrap();
+        c
+    };
+    let ours = compute(&ten[0]);
+    let theirs = compute(&twelve[0]);
+
+    // Nodes which computed parts of the same extension agree on what they
+    // share, the rest is added.
+    let mut c = compute(&ten[0]);
+    let report = c.merge(&theirs);
+    assert!(report.is_clean());
+    assert_eq!(report.identical.len(), ours.len());
+    assert_eq!(report.added.len(), theirs.len() - ours.len());
+    let mut m = Meter::unlimited();
+    let v = evaluate_with_meter(twelve[0].clone(), &mut c, &mut NoProvider, &mut m);
+    assert_eq!(v.unwrap().expect_value(), Value::Literal(Literal::Int32(144)));
+    assert_eq!(m.stats().demands, 0);
+
+    // A peer which disagrees about fib [n <- 3] is reported, we keep ours by
+    // default.
+    let three = fib_key(&ours, 3);
+    let mut liar = compute(&twelve[0]);
+    liar.add(three.clone(), Either::Left(Value::Literal(Literal::Int32(3))));
+    let value_of = |c: &mut Cache| c.backend.find(&three).map(|v| v.expect_value());
+    let mut c = compute(&ten[0]);
+    let report = c.merge(&liar);
+    assert!(!report.is_clean());
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(report.conflicts[0].key, three);
+    assert_eq!(report.conflicts[0].resolution, Resolution::KeepOurs);
+    assert_eq!(value_of(&mut c), Some(Value::Literal(Literal::Int32(2))));
+
+    let mut c = compute(&ten[0]);
+    c.merge_with(&liar, &mut TakeTheirs);
+    assert_eq!(value_of(&mut c), Some(Value::Literal(Literal::Int32(3))));
+
+    // Recomputing removes our entry and the entries which read it.
+    let mut c = compute(&ten[0]);
+    c.merge_with(&liar, &mut Recompute);
+    assert_eq!(value_of(&mut c), None);
+    assert!(c.backend.find(&fib_key(&ours, 4)).is_none());
+    let v = evaluate_with_cache(ten[0].clone(), &mut c).unwrap();
+    assert_eq!(v.expect_value(), Value::Literal(Literal::Int32(55)));
+
+    // The policy is a hook which sees every conflict.
+    let mut seen = vec![];
+    let mut c = compute(&ten[0]);
+    c.merge_with(&liar, &mut |key: &gaia::cache::Key, _: &_, _: &_| {
+        seen.push(key.clone());
+        Resolution::KeepOurs
+    });
+    assert_eq!(seen, vec![three]);
+
+    // Entries which fail the checks of the keystore are not merged.
+    let keystore = MemoryKeystore::new().require_signatures();
+    let mut c = Cache::new().with_keystore(keystore);
+    let report = c.merge(&theirs);
+    assert_eq!(report.rejected.len(), theirs.len());
+    assert_eq!(c.len(), 0);
+}
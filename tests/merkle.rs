@@ -0,0 +1,47 @@
+extern crate gaia;
+
+mod common;
+
+use common::*;
+use gaia::evaluate_with_cache;
+use gaia::cache::Cache;
+use gaia::digest::sha256;
+use gaia::either::Either;
+use gaia::encoding::{decode, encode};
+use gaia::expression::Literal;
+use gaia::merkle::{self, MerkleTree, Proof};
+use gaia::value::Value;
+
+#[test]
+fn test_merkle() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let mut c = Cache::new();
+    evaluate_with_cache(body[0].clone(), &mut c).unwrap();
+    let tree = MerkleTree::of(&c);
+    assert_eq!(tree.len(), c.len());
+    let root = tree.root();
+
+    // Every entry has a proof, which a peer checks against the root alone.
+    for (key, v) in c.iter() {
+        assert!(merkle::verify(&root, &key, &v, &tree.prove(&key).unwrap()));
+    }
+
+    // fib [n <- 3] = 2 belongs to the extension, fib [n <- 3] = 3 does not.
+    let three = Value::Literal(Literal::Int32(3));
+    let key = fib_key(&c, 3);
+    let v = c.backend.find(&key).unwrap();
+    assert_eq!(v.clone().expect_value(), Value::Literal(Literal::Int32(2)));
+    let proof: Proof = decode(&encode(&tree.prove(&key).unwrap())).unwrap();
+    assert!(merkle::verify(&root, &key, &v, &proof));
+    assert!(!merkle::verify(&root, &key, &Either::Left(three.clone()), &proof));
+
+    // Another node which computed the same extension commits to the same root,
+    // one with a different entry does not.
+    let mut other = Cache::new();
+    evaluate_with_cache(body[0].clone(), &mut other).unwrap();
+    assert_eq!(root, MerkleTree::of(&other).root());
+    other.add(key, Either::Left(three));
+    assert!(root != MerkleTree::of(&other).root());
+    assert_eq!(MerkleTree::of(&Cache::new()).root(), sha256(b""));
+}
@@ -0,0 +1,140 @@
+extern crate gaia;
+
+mod common;
+
+use common::*;
+use gaia::{evaluate_with_cache, evaluate_with_meter};
+use gaia::backend::{CacheBackend, HashMapBackend, ShardedBackend};
+use gaia::cache::Cache;
+use gaia::ed25519::Keypair;
+use gaia::encoding::{decode, decode_untrusted, encode, MAX_DEPTH};
+use gaia::expression::{Expression, IfExpression, Literal};
+use gaia::limits::Meter;
+use gaia::node::{self, Node, PeerBackend, Request, Response, MAX_CONNECTIONS};
+use gaia::provider::NoProvider;
+use gaia::signature::MemoryKeystore;
+use gaia::value::Value;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn test_nodes() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let fib = Value::Literal(Literal::Int32(55));
+    let alice = Keypair::from_seed(&[1; 32]);
+    let trusts_alice = || MemoryKeystore::new().trust(alice.public).require_signatures();
+    let first = Node::bind("127.0.0.1:0", ShardedBackend::new(4), trusts_alice()).unwrap();
+    let second = Node::bind("127.0.0.1:0", ShardedBackend::new(4), trusts_alice()).unwrap();
+    // A peer which cannot be reached is skipped.
+    let gone = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    // Entries which are not signed, or not by a trusted key, are refused.
+    let peers = vec![first.addr];
+    let mut c = Cache::with_backend(PeerBackend::new(HashMapBackend::new(), peers.clone()));
+    evaluate_with_cache(body[0].clone(), &mut c).unwrap();
+    let mallory = MemoryKeystore::new().with_keypair(Keypair::from_seed(&[2; 32]));
+    let publisher = PeerBackend::new(HashMapBackend::new(), peers).with_keystore(mallory);
+    evaluate_with_cache(body[0].clone(), &mut Cache::with_backend(publisher)).unwrap();
+    assert_eq!(first.backend.len(), 0);
+
+    // The entries computed by one evaluation are published to the nodes.
+    let peers = vec![first.addr, second.addr];
+    let signs_as_alice = MemoryKeystore::new().with_keypair(alice.clone());
+    let publisher = PeerBackend::new(HashMapBackend::new(), peers).with_keystore(signs_as_alice);
+    let mut c = Cache::with_backend(publisher);
+    assert_eq!(fib, evaluate_with_cache(body[0].clone(), &mut c).unwrap().expect_value());
+    assert_eq!(c.len(), first.backend.len());
+    assert_eq!(c.len(), second.backend.len());
+
+    // Another evaluation finds them there instead of computing them.
+    let peers = vec![gone, second.addr];
+    let mut c = Cache::with_backend(PeerBackend::new(HashMapBackend::new(), peers));
+    let mut m = Meter::unlimited();
+    let v = evaluate_with_meter(body[0].clone(), &mut c, &mut NoProvider, &mut m);
+    assert_eq!(fib, v.unwrap().expect_value());
+    assert_eq!(m.stats().demands, 0);
+    // They keep the signatures they were published with.
+    let entries = c.export();
+    assert!(!entries.is_empty());
+    for (_, _, signature) in entries {
+        assert_eq!(signature.unwrap().signer, alice.public);
+    }
+
+    // A frame longer than a message may be is not read.
+    let mut stream = TcpStream::connect(first.addr).unwrap();
+    stream.write_all(&[0xff; 4]).unwrap();
+    stream.write_all(&[0; 64]).unwrap();
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    assert!(response.is_empty());
+
+    first.shutdown();
+    second.shutdown();
+}
+
+#[test]
+fn test_node_connections() {
+    let node = Node::bind("127.0.0.1:0", ShardedBackend::new(4), MemoryKeystore::new()).unwrap();
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let mut c = Cache::new();
+    evaluate_with_cache(body[0].clone(), &mut c).unwrap();
+    let find = Request::Find(c.iter().next().unwrap().0);
+
+    // The connections beyond the ones a node serves at once are closed
+    // without an answer.
+    let open: Vec<TcpStream> = (0..MAX_CONNECTIONS)
+        .map(|_| TcpStream::connect(node.addr).unwrap())
+        .collect();
+    let mut extra = TcpStream::connect(node.addr).unwrap();
+    extra.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let _ = node::write_message(&mut extra, &find);
+    let mut response = vec![];
+    let _ = extra.read_to_end(&mut response);
+    assert!(response.is_empty());
+
+    // Once they are closed others are served.
+    drop(open);
+    let answered = (0..100).any(|_| {
+        thread::sleep(Duration::from_millis(10));
+        let mut stream = TcpStream::connect(node.addr).unwrap();
+        node::write_message(&mut stream, &find).is_ok()
+            && node::read_message::<Response, _>(&mut stream).is_ok()
+    });
+    assert!(answered);
+    node.shutdown();
+}
+
+#[test]
+fn test_decode_depth() {
+    let leaf = Expression::Literal(Literal::Int32(0));
+    let nest = |depth: usize| {
+        let mut x = leaf.clone();
+        for _ in 0..depth - 1 {
+            x = Expression::If(Box::new(IfExpression {
+                condition: leaf.clone(),
+                consequent: x,
+                alternate: leaf.clone(),
+            }));
+        }
+        x
+    };
+
+    // Expressions from elsewhere nest as deeply as decoding them allows.
+    let x = nest(MAX_DEPTH);
+    assert_eq!(decode_untrusted::<Expression>(&encode(&x)).unwrap(), x);
+
+    // Deeper input is refused before it exhausts the stack.
+    let mut deeper = vec![14; MAX_DEPTH];
+    deeper.extend(encode(&x));
+    let err = decode_untrusted::<Expression>(&deeper).unwrap_err();
+    assert!(err.0.starts_with("nested too deeply"));
+    assert!(decode_untrusted::<Expression>(&vec![14; 1 << 20]).is_err());
+
+    // What the process wrote itself is decoded however deeply it nests.
+    let x = nest(MAX_DEPTH + 32);
+    assert_eq!(decode::<Expression>(&encode(&x)).unwrap(), x);
+}
@@ -0,0 +1,44 @@
+extern crate gaia;
+
+mod common;
+
+use common::*;
+use gaia::either::Either;
+use gaia::encoding::{decode, encode};
+use gaia::error::ShipError;
+use gaia::expression::{L1Expression, Literal};
+use gaia::runtime::Runtime;
+use gaia::shipping::Shipment;
+use gaia::value::Value;
+
+#[test]
+fn test_shipping() {
+    let source = read_source("./isrc/intension_4.i");
+    let body = scope(source.as_ref()).unwrap();
+    let mut runtime = Runtime::new(body[0].clone());
+    let intension = match runtime.eval("intension", &[("t", 1), ("s", 2)]) {
+        Ok(Either::Left(Value::Intension(intension))) => *intension,
+        other => panic!("Expected an intension but here found {:?}", other),
+    };
+    assert_eq!(runtime.apply(intension.clone()).unwrap().expect_value(), Value::Literal(Literal::Int32(3)));
+
+    // The body refers to X, which is shipped with it, and to the dimensions t
+    // and s, whose hidden dimensions fib_stream.i uses for n.
+    let shipment = runtime.export(&intension).unwrap();
+    assert_eq!(shipment.definitions.0.len(), 1);
+    assert_eq!(shipment.definitions.0[0].id, "X");
+    assert_eq!(shipment.dimensions.0.len(), 2);
+    let shipment: Shipment = decode(&encode(&shipment)).unwrap();
+
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let fib_body = scope(fib_source.as_ref()).unwrap();
+    let mut remote = Runtime::new(fib_body[0].clone());
+    let imported = remote.import(&shipment).unwrap();
+    assert_eq!(remote.apply(imported).unwrap().expect_value(), Value::Literal(Literal::Int32(3)));
+    assert_eq!(remote.run().unwrap().expect_value(), Value::Literal(Literal::Int32(55)));
+
+    // A runtime which defines X otherwise refuses the shipment.
+    let mut other = Runtime::new(body[0].clone());
+    other.define("X", L1Expression::Literal(Literal::Int32(7)));
+    assert_eq!(other.import(&shipment).unwrap_err(), ShipError::Conflict("X".to_string()));
+}
@@ -0,0 +1,148 @@
+extern crate gaia;
+
+mod common;
+
+use common::*;
+use gaia::{compile, evaluate_with_cache, evaluate_with_meter};
+use gaia::backend::{HashMapBackend, ShardedBackend};
+use gaia::cache::{Cache, Key};
+use gaia::ed25519::{verify, Keypair};
+use gaia::either::Either;
+use gaia::error::{LoadError, SignatureError};
+use gaia::expression::Literal;
+use gaia::limits::Meter;
+use gaia::node::{Node, PeerBackend};
+use gaia::provider::NoProvider;
+use gaia::signature::MemoryKeystore;
+use gaia::value::Value;
+use std::{env, fs};
+
+#[test]
+fn test_ed25519() {
+    let hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+    let bytes = |s: &str| -> Vec<u8> {
+        if s == "-" {
+            return vec![];
+        }
+        (0..s.len() / 2)
+            .map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap())
+            .collect()
+    };
+
+    // The test vectors of RFC 8032, the empty message written as -.
+    let vectors = read_source("./tests/rfc8032.txt");
+    let mut checked = 0;
+    for line in vectors.lines().filter(|line| !line.starts_with('#')) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let mut seed = [0; 32];
+        seed.copy_from_slice(&bytes(fields[0]));
+        let message = bytes(fields[2]);
+        let keypair = Keypair::from_seed(&seed);
+        assert_eq!(keypair.public.hex(), fields[1]);
+        let signature = keypair.sign(&message);
+        assert_eq!(hex(&signature), fields[3]);
+        assert!(verify(&keypair.public, &message, &signature));
+
+        // Neither another message nor a flipped bit of the signature holds.
+        let mut other = message.clone();
+        other.push(0);
+        assert!(!verify(&keypair.public, &other, &signature));
+        let mut flipped = signature;
+        flipped[0] ^= 1;
+        assert!(!verify(&keypair.public, &message, &flipped));
+        checked += 1;
+    }
+    assert_eq!(checked, 5);
+}
+
+#[test]
+fn test_signatures() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let fib = Value::Literal(Literal::Int32(55));
+    let alice = Keypair::from_seed(&[1; 32]);
+    let mallory = Keypair::from_seed(&[2; 32]);
+
+    // The entries computed with a key pair are signed by it.
+    let mut c = Cache::new().with_keystore(MemoryKeystore::new().with_keypair(alice.clone()));
+    assert_eq!(fib, evaluate_with_cache(body[0].clone(), &mut c).unwrap().expect_value());
+    let entries = c.export();
+    for &(_, _, signature) in &entries {
+        assert_eq!(signature.unwrap().signer, alice.public);
+    }
+
+    // A node which trusts alice imports them and computes nothing.
+    let trusts_alice = || MemoryKeystore::new().trust(alice.public).require_signatures();
+    let mut bob = Cache::new().with_keystore(trusts_alice());
+    for &(ref key, ref v, signature) in &entries {
+        bob.import(key.clone(), v.clone(), signature).unwrap();
+    }
+    let mut m = Meter::unlimited();
+    let v = evaluate_with_meter(body[0].clone(), &mut bob, &mut NoProvider, &mut m);
+    assert_eq!(fib, v.unwrap().expect_value());
+    assert_eq!(m.stats().demands, 0);
+
+    // Entries which were tampered with, signed by someone else or not signed
+    // at all are refused.
+    let mut bob = Cache::new().with_keystore(trusts_alice());
+    let (key, _, signature) = entries[0].clone();
+    let forged = Either::Left(Value::Literal(Literal::Int32(1000)));
+    let err = bob.import(key.clone(), forged.clone(), signature);
+    assert_eq!(err, Err(SignatureError::Invalid(alice.public)));
+    let signs_as_mallory = MemoryKeystore::new().with_keypair(mallory.clone());
+    let mut by_mallory = Cache::new().with_keystore(signs_as_mallory);
+    by_mallory.add(key.clone(), forged.clone());
+    let signature = by_mallory.signature(&key).cloned();
+    let err = bob.import(key.clone(), forged.clone(), signature);
+    assert_eq!(err, Err(SignatureError::Untrusted(mallory.public)));
+    let err = bob.import(key.clone(), forged.clone(), None);
+    assert_eq!(err, Err(SignatureError::Missing));
+    // The identifier and the context are signed along with the digest.
+    let (_, v, signature) = entries[0].clone();
+    let renamed = Key {
+        x: "other".to_string(),
+        ..key.clone()
+    };
+    let err = bob.import(renamed, v.clone(), signature);
+    assert_eq!(err, Err(SignatureError::Invalid(alice.public)));
+    let moved = Key {
+        k: entries[1].0.k.clone(),
+        ..key.clone()
+    };
+    let err = bob.import(moved, v, signature);
+    assert_eq!(err, Err(SignatureError::Invalid(alice.public)));
+    assert_eq!(bob.len(), 0);
+
+    // Saved caches keep their signatures, which are checked when they load.
+    let path = env::temp_dir().join(format!("gaia-signed-{}", std::process::id()));
+    let (program, _, _) = compile(body[0].clone());
+    c.save(&path, &program).unwrap();
+    let mut bob = Cache::new().with_keystore(trusts_alice());
+    assert_eq!(bob.load(&path, &program).unwrap(), c.len());
+    let mut carol = Cache::new().with_keystore(MemoryKeystore::new().trust(mallory.public));
+    match carol.load(&path, &program) {
+        Err(LoadError::Signature(_, SignatureError::Untrusted(signer))) => {
+            assert_eq!(signer, alice.public)
+        }
+        other => panic!("Expected an untrusted signer, got {:?}", other),
+    }
+    assert_eq!(carol.len(), 0);
+    let _ = fs::remove_file(&path);
+
+    // Nodes pass the signatures on, and peers check them.
+    let node = Node::bind("127.0.0.1:0", ShardedBackend::new(4), trusts_alice()).unwrap();
+    let publisher = PeerBackend::new(HashMapBackend::new(), vec![node.addr])
+        .with_keystore(MemoryKeystore::new().with_keypair(alice.clone()));
+    let mut c = Cache::with_backend(publisher);
+    evaluate_with_cache(body[0].clone(), &mut c).unwrap();
+    let (key, _) = c.iter().next().unwrap();
+    assert_eq!(node.signature(&key).unwrap().signer, alice.public);
+    let mut m = Meter::unlimited();
+    let peer = PeerBackend::new(HashMapBackend::new(), vec![node.addr])
+        .with_keystore(MemoryKeystore::new().trust(mallory.public));
+    let mut c = Cache::with_backend(peer);
+    let v = evaluate_with_meter(body[0].clone(), &mut c, &mut NoProvider, &mut m);
+    assert_eq!(fib, v.unwrap().expect_value());
+    assert!(m.stats().demands > 0);
+    node.shutdown();
+}
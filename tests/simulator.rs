@@ -0,0 +1,57 @@
+extern crate gaia;
+
+mod common;
+
+use common::*;
+use gaia::evaluate_with_meter;
+use gaia::expression::Literal;
+use gaia::limits::Meter;
+use gaia::provider::NoProvider;
+use gaia::simulator::{NetworkConfig, Simulation};
+use gaia::value::Value;
+
+#[test]
+fn test_simulator() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let fib = Value::Literal(Literal::Int32(55));
+    let simulate = |seed: u64| {
+        let config = NetworkConfig::default().latency(1, 10).loss(0.2);
+        let mut sim = Simulation::new(3, seed, config);
+        for node in 0..3 {
+            let v = sim.evaluate(node, body[0].clone()).unwrap();
+            assert_eq!(fib, v.expect_value(), "seed {}", sim.seed);
+        }
+        let rounds = sim.run_until_converged(50);
+        assert!(rounds.is_some(), "seed {} did not converge", sim.seed);
+        sim
+    };
+
+    // Every node ends up with the same extension, whatever the network did.
+    for seed in 0..8 {
+        simulate(seed);
+    }
+
+    // A seed reproduces a simulation.
+    let first = simulate(7);
+    let second = simulate(7);
+    assert_eq!(first.stats(), second.stats());
+    assert_eq!(first.clock(), second.clock());
+    assert_eq!(first.extension(2), second.extension(2));
+    assert!(first.stats().lost > 0);
+
+    // Nodes on either side of a partition diverge until it heals.
+    let mut sim = Simulation::new(3, 11, NetworkConfig::default().latency(1, 5));
+    sim.partition(&[&[0, 1], &[2]]);
+    sim.evaluate(0, body[0].clone()).unwrap();
+    sim.run();
+    assert!(!sim.converged());
+    assert_eq!(0, sim.extension(2).len());
+    assert!(sim.stats().blocked > 0);
+    sim.heal();
+    let mut m = Meter::unlimited();
+    let v = evaluate_with_meter(body[0].clone(), &mut sim.caches[2], &mut NoProvider, &mut m);
+    assert_eq!(fib, v.unwrap().expect_value());
+    assert_eq!(m.stats().demands, 0);
+    assert!(sim.run_until_converged(10).is_some());
+}
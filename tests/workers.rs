@@ -0,0 +1,163 @@
+extern crate gaia;
+
+mod common;
+
+use common::*;
+use gaia::cache::Cache;
+use gaia::context::Context;
+use gaia::digest::sha256;
+use gaia::ed25519::Keypair;
+use gaia::either::Either;
+use gaia::encoding::encode;
+use gaia::error::{SignatureError, WorkerError};
+use gaia::expression::Literal;
+use gaia::json::{self, Json};
+use gaia::runtime::Runtime;
+use gaia::signature::MemoryKeystore;
+use gaia::value::Value;
+use gaia::worker::{self, request, Demand, Worker, WorkerPool};
+use std::process::Command;
+use std::time::Duration;
+
+#[test]
+fn test_workers() {
+    let fib_source = read_source("./isrc/fib_stream.i");
+    let body = scope(fib_source.as_ref()).unwrap();
+    let mut runtime = Runtime::new(body[0].clone());
+    runtime.run().unwrap();
+    let program = runtime.hash();
+    let demand = |ord| Demand {
+        program,
+        x: "fib".to_string(),
+        k: fib_key(&runtime.cache, ord).k,
+    };
+    let fibs = [0, 1, 1, 2, 3, 5, 8, 13, 21, 34, 55];
+    let fib = |ord: usize| Either::Left(Value::Literal(Literal::Int32(fibs[ord])));
+
+    // A worker reads one request per line and answers each on a line.
+    let mut worker = Worker::new();
+    assert_eq!(worker.load(body[0].clone()), program);
+    let requests = format!(
+        "{}\n{}\nnot json\n",
+        request(0, &demand(10)).print(),
+        request(1, &demand(9)).print()
+    );
+    let mut out = vec![];
+    worker.serve(requests.as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 3);
+    let (id, answer) = worker::parse_response(&json::parse(lines[0]).unwrap()).unwrap();
+    let answer = answer.unwrap();
+    assert_eq!(id, 0);
+    assert_eq!(encode(&answer.v), encode(&fib(10)));
+    assert!(answer.entries.len() > 10);
+    // The second demand was computed by the first.
+    let (_, answer) = worker::parse_response(&json::parse(lines[1]).unwrap()).unwrap();
+    assert_eq!(answer.unwrap().entries.len(), 0);
+    assert_eq!(json::parse(lines[2]).unwrap().get("id"), Some(&Json::Null));
+    assert!(json::parse(lines[2]).unwrap().get("error").is_some());
+
+    // Out of the context of an entry fib is missing the dimension of n, which
+    // the worker answers on its line like any other value.
+    let nowhere = Demand {
+        k: Context::new(),
+        ..demand(10)
+    };
+    let mut out = vec![];
+    worker.serve(format!("{}\n", request(3, &nowhere).print()).as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().count(), 1);
+    let (id, answer) = worker::parse_response(&json::parse(&out).unwrap()).unwrap();
+    assert_eq!(id, 3);
+    match answer.unwrap().v {
+        Either::Right(missing) => assert_eq!(missing.0.len(), 1),
+        other => panic!("Expected missing dimensions but here found {:?}", other),
+    }
+
+    // A worker with a key pair signs the entries it sends back.
+    let alice = Keypair::from_seed(&[1; 32]);
+    let mut signing = Worker::new();
+    let c = Cache::new().with_keystore(MemoryKeystore::new().with_keypair(alice.clone()));
+    signing.load_with_cache(body[0].clone(), c);
+    let answer = signing.answer(&demand(4)).unwrap();
+    for &(_, _, signature) in &answer.entries {
+        assert_eq!(signature.unwrap().signer, alice.public);
+    }
+    let line = worker::response(Some(0), &Ok(answer)).print();
+    let (_, answer) = worker::parse_response(&json::parse(&line).unwrap()).unwrap();
+    assert!(answer.unwrap().entries.iter().all(|entry| entry.2.is_some()));
+
+    // A coordinator fans demands out to worker processes and takes in the
+    // entries they computed.
+    let command = || {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_gaia"));
+        command.arg("worker").arg("./isrc/fib_stream.i");
+        command
+    };
+    let mut pool = WorkerPool::spawn(3, command).unwrap();
+    let mut demands: Vec<Demand> = (5..11).map(&demand).collect();
+    demands.push(Demand {
+        program: sha256(b"another program"),
+        ..demand(1)
+    });
+    demands.push(nowhere.clone());
+    let mut c = Cache::new();
+    let results = pool.demand_all(&demands, &mut c);
+    for (ord, result) in (5..11).zip(&results) {
+        assert_eq!(encode(result.as_ref().unwrap()), encode(&fib(ord)));
+    }
+    match results[6] {
+        Err(WorkerError::Remote(ref err)) => assert!(err.starts_with("unknown program")),
+        ref other => panic!("Expected an unknown program but here found {:?}", other),
+    }
+    match results[7] {
+        Ok(Either::Right(ref missing)) => assert_eq!(missing.0.len(), 1),
+        ref other => panic!("Expected missing dimensions but here found {:?}", other),
+    }
+    for ord in 0..11 {
+        let key = fib_key(&c, ord as u32);
+        let v = c.iter().find(|entry| entry.0 == key).unwrap().1;
+        assert_eq!(encode(&v), encode(&fib(ord)));
+    }
+    assert!(pool.rejected.is_empty());
+
+    // The entries keep where they came from: a cache which only takes signed
+    // entries refuses the ones of unsigned workers.
+    let mut pool = WorkerPool::spawn(1, command).unwrap();
+    let mut c = Cache::new().with_keystore(MemoryKeystore::new().require_signatures());
+    let results = pool.demand_all(&demands[..1], &mut c);
+    assert_eq!(encode(results[0].as_ref().unwrap()), encode(&fib(5)));
+    assert_eq!(c.len(), 0);
+    assert!(!pool.rejected.is_empty());
+    assert!(pool.rejected.iter().all(|(_, err)| *err == SignatureError::Missing));
+
+    // A worker which does not answer in time is given up on and its demand
+    // goes to another one.
+    let mut spawned = 0;
+    let command = || {
+        spawned += 1;
+        if spawned == 1 {
+            let mut command = Command::new("sleep");
+            command.arg("30");
+            return command;
+        }
+        command()
+    };
+    let mut pool = WorkerPool::spawn(2, command).unwrap().with_timeout(Duration::from_secs(1));
+    let mut c = Cache::new();
+    let results = pool.demand_all(&demands[..4], &mut c);
+    for (ord, result) in (5..9).zip(&results) {
+        assert_eq!(encode(result.as_ref().unwrap()), encode(&fib(ord)));
+    }
+}
+
+#[test]
+fn test_json_depth() {
+    // Nesting is bounded rather than overflowing the stack.
+    assert!(json::parse(&"[".repeat(100000)).is_err());
+    let nested = format!("{}{}", "[".repeat(json::MAX_DEPTH), "]".repeat(json::MAX_DEPTH));
+    assert!(json::parse(&nested).is_ok());
+    let nested = format!("{}1{}", "[".repeat(json::MAX_DEPTH + 1), "]".repeat(json::MAX_DEPTH + 1));
+    assert!(json::parse(&nested).is_err());
+}